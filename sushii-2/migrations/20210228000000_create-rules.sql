@@ -0,0 +1,66 @@
+-- Rule tables used by sushii-rules. These may already exist on databases
+-- where the app_public schema was created by the web API, so nothing is
+-- created twice.
+CREATE SCHEMA IF NOT EXISTS app_public;
+
+DO $$
+BEGIN
+    CREATE TYPE app_public.rule_scope AS ENUM (
+        'GUILD',
+        'CHANNEL',
+        'USER'
+    );
+EXCEPTION
+    WHEN duplicate_object THEN NULL;
+END
+$$;
+
+CREATE TABLE IF NOT EXISTS app_public.guild_rule_sets (
+    id          BIGINT  GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+    -- Global rule sets don't have a guild
+    guild_id    BIGINT,
+    name        TEXT    NOT NULL,
+    description TEXT,
+    enabled     BOOLEAN NOT NULL DEFAULT TRUE,
+    editable    BOOLEAN NOT NULL DEFAULT TRUE,
+    author      BIGINT,
+    category    TEXT
+);
+
+CREATE TABLE IF NOT EXISTS app_public.guild_rule_set_configs (
+    set_id   BIGINT  NOT NULL,
+    guild_id BIGINT  NOT NULL,
+    enabled  BOOLEAN NOT NULL DEFAULT TRUE,
+    config   JSONB,
+    PRIMARY KEY (set_id, guild_id),
+    CONSTRAINT fk_guild_rule_set_config_set_id
+        FOREIGN KEY(set_id)
+            REFERENCES app_public.guild_rule_sets(id)
+            ON DELETE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS app_public.guild_rules (
+    id         BIGINT  GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+    set_id     BIGINT  NOT NULL,
+    name       TEXT    NOT NULL,
+    enabled    BOOLEAN NOT NULL DEFAULT TRUE,
+    trigger    JSONB   NOT NULL,
+    conditions JSONB   NOT NULL,
+    actions    JSONB   NOT NULL,
+    CONSTRAINT fk_guild_rule_set_id
+        FOREIGN KEY(set_id)
+            REFERENCES app_public.guild_rule_sets(id)
+            ON DELETE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS app_public.rule_gauges (
+    time     TIMESTAMPTZ           NOT NULL,
+    guild_id BIGINT                NOT NULL,
+    scope    app_public.rule_scope NOT NULL,
+    scope_id BIGINT                NOT NULL,
+    name     TEXT                  NOT NULL,
+    value    BIGINT                NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS rule_gauges_guild_id_name_time_idx
+    ON app_public.rule_gauges (guild_id, scope, scope_id, name, time DESC);
@@ -0,0 +1,19 @@
+-- Composite and role scopes for rule counters.
+--
+-- sqlx runs every migration in a transaction, and ALTER TYPE ... ADD VALUE
+-- can't run in one before Postgres 12, so the type is replaced instead.
+ALTER TYPE app_public.rule_scope RENAME TO rule_scope_old;
+
+CREATE TYPE app_public.rule_scope AS ENUM (
+    'GUILD',
+    'CHANNEL',
+    'USER',
+    'USER_CHANNEL',
+    'ROLE'
+);
+
+ALTER TABLE app_public.rule_gauges
+ALTER COLUMN scope TYPE app_public.rule_scope
+       USING scope::TEXT::app_public.rule_scope;
+
+DROP TYPE app_public.rule_scope_old;
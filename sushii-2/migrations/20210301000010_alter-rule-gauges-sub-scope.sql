@@ -0,0 +1,3 @@
+-- Secondary ID for composite scopes, e.g. the channel ID for USER_CHANNEL
+ALTER TABLE app_public.rule_gauges
+ ADD COLUMN sub_scope_id BIGINT;
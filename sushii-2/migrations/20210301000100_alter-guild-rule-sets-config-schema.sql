@@ -0,0 +1,3 @@
+-- Config parameters a rule set expects each guild to set
+ALTER TABLE app_public.guild_rule_sets
+ ADD COLUMN config_schema JSONB;
@@ -0,0 +1,4 @@
+-- Version of the rule conditions and actions JSON, existing rules are from
+-- before versions were added
+ALTER TABLE app_public.guild_rules
+ ADD COLUMN schema_version INTEGER NOT NULL DEFAULT 1;
@@ -0,0 +1,3 @@
+-- Suppresses repeat runs of a rule in a guild, channel or for a user
+ALTER TABLE app_public.guild_rules
+ ADD COLUMN cooldown JSONB;
@@ -0,0 +1,3 @@
+-- Why a rule was disabled by the rules engine, e.g. a permanent action error
+ALTER TABLE app_public.guild_rules
+ ADD COLUMN disabled_reason TEXT;
@@ -0,0 +1,5 @@
+-- Rules with a higher priority run first, and can stop lower priority rules
+-- from running for the same event
+ALTER TABLE app_public.guild_rules
+ ADD COLUMN priority INTEGER NOT NULL DEFAULT 0,
+ ADD COLUMN stop_processing BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,24 @@
+-- Log of rules that ran actions, pruned after the retention period
+CREATE TABLE app_public.rule_executions (
+    time       TIMESTAMPTZ NOT NULL,
+    guild_id   BIGINT      NOT NULL,
+    set_id     BIGINT      NOT NULL,
+    rule_id    BIGINT      NOT NULL,
+    -- Source ID of the triggering event, the same for each retry
+    event_id   TEXT        NOT NULL,
+    user_id    BIGINT,
+    actions    JSONB       NOT NULL,
+    latency_ms BIGINT      NOT NULL
+);
+
+CREATE INDEX rule_executions_guild_id_time_idx
+          ON app_public.rule_executions (guild_id, time DESC);
+
+CREATE INDEX rule_executions_guild_id_rule_id_time_idx
+          ON app_public.rule_executions (guild_id, rule_id, time DESC);
+
+CREATE INDEX rule_executions_guild_id_user_id_time_idx
+          ON app_public.rule_executions (guild_id, user_id, time DESC);
+
+CREATE INDEX rule_executions_time_idx
+          ON app_public.rule_executions (time);
@@ -0,0 +1,3 @@
+-- Test cases run against a rule set before it is saved
+ALTER TABLE app_public.guild_rule_sets
+ ADD COLUMN tests JSONB;
@@ -0,0 +1,3 @@
+-- Error of a rule that failed with a permanent error, these aren't retried
+ALTER TABLE app_public.rule_executions
+ ADD COLUMN error TEXT;
@@ -2,7 +2,7 @@ pub mod failure;
 pub mod feeds;
 pub mod guild;
 pub mod mute;
-// pub mod rules;
+pub mod rules;
 pub mod stats;
 pub mod user;
 
@@ -19,7 +19,10 @@ pub use self::{
         tags::Tag,
     },
     mute::{delete_mute, Mute},
-    // rules::gauge::{RuleGauge, RuleScope},
+    rules::{
+        execution::{RuleActionResult, RuleExecution},
+        gauge::{RuleGauge, RuleScope},
+    },
     stats::BotStat,
     user::{
         cached_user::CachedUser,
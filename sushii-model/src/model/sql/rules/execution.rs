@@ -0,0 +1,158 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::types::PgInterval;
+use sqlx::types::Json;
+use std::convert::TryFrom;
+
+use crate::error::{Error, Result};
+
+/// Outcome of a single action in a rule execution
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct RuleActionResult {
+    /// Action name, e.g. Reply or Mute
+    pub action: String,
+    /// Error message if the action failed
+    pub error: Option<String>,
+}
+
+/// Record of a rule running its actions or failing with a permanent error
+#[derive(Deserialize, Serialize, sqlx::FromRow, Clone, Debug)]
+pub struct RuleExecution {
+    pub time: DateTime<Utc>,
+    pub guild_id: i64,
+    pub set_id: i64,
+    pub rule_id: i64,
+    /// Source ID of the event that triggered the rule, the same for every
+    /// attempt of a retried event
+    pub event_id: String,
+    /// User the rule acted on
+    pub user_id: Option<i64>,
+    /// Actions in the order they ran, actions after a failed one don't run
+    pub actions: Json<Vec<RuleActionResult>>,
+    /// Time to check conditions and run actions, in milliseconds
+    pub latency_ms: i64,
+    /// Error the rule failed with, only permanent errors are saved since
+    /// events with transient errors are retried
+    pub error: Option<String>,
+}
+
+impl RuleExecution {
+    pub async fn save(&self, pool: &sqlx::PgPool) -> Result<()> {
+        sqlx::query!(
+            r#"
+            INSERT INTO app_public.rule_executions (time, guild_id, set_id, rule_id, event_id, user_id, actions, latency_ms, error)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+            "#,
+            self.time,
+            self.guild_id,
+            self.set_id,
+            self.rule_id,
+            self.event_id,
+            self.user_id,
+            &self.actions as _,
+            self.latency_ms,
+            self.error,
+        )
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Most recent executions of all rules in a guild
+    pub async fn recent_in_guild(
+        pool: &sqlx::PgPool,
+        guild_id: u64,
+        limit: i64,
+    ) -> Result<Vec<Self>> {
+        sqlx::query_as!(
+            RuleExecution,
+            r#"
+                SELECT time, guild_id, set_id, rule_id, event_id, user_id,
+                       actions as "actions!: Json<Vec<RuleActionResult>>",
+                       latency_ms, error
+                  FROM app_public.rule_executions
+                 WHERE guild_id = $1
+              ORDER BY time DESC
+                 LIMIT $2
+            "#,
+            guild_id as i64,
+            limit,
+        )
+        .fetch_all(pool)
+        .await
+        .map_err(Into::into)
+    }
+
+    /// Most recent executions of a single rule in a guild
+    pub async fn recent_for_rule(
+        pool: &sqlx::PgPool,
+        guild_id: u64,
+        rule_id: i64,
+        limit: i64,
+    ) -> Result<Vec<Self>> {
+        sqlx::query_as!(
+            RuleExecution,
+            r#"
+                SELECT time, guild_id, set_id, rule_id, event_id, user_id,
+                       actions as "actions!: Json<Vec<RuleActionResult>>",
+                       latency_ms, error
+                  FROM app_public.rule_executions
+                 WHERE guild_id = $1
+                   AND rule_id = $2
+              ORDER BY time DESC
+                 LIMIT $3
+            "#,
+            guild_id as i64,
+            rule_id,
+            limit,
+        )
+        .fetch_all(pool)
+        .await
+        .map_err(Into::into)
+    }
+
+    /// Most recent executions that acted on a user in a guild
+    pub async fn recent_for_user(
+        pool: &sqlx::PgPool,
+        guild_id: u64,
+        user_id: u64,
+        limit: i64,
+    ) -> Result<Vec<Self>> {
+        sqlx::query_as!(
+            RuleExecution,
+            r#"
+                SELECT time, guild_id, set_id, rule_id, event_id, user_id,
+                       actions as "actions!: Json<Vec<RuleActionResult>>",
+                       latency_ms, error
+                  FROM app_public.rule_executions
+                 WHERE guild_id = $1
+                   AND user_id = $2
+              ORDER BY time DESC
+                 LIMIT $3
+            "#,
+            guild_id as i64,
+            user_id as i64,
+            limit,
+        )
+        .fetch_all(pool)
+        .await
+        .map_err(Into::into)
+    }
+
+    /// Deletes executions older than the retention period, returns the number
+    /// of deleted rows
+    pub async fn prune(pool: &sqlx::PgPool, retention: Duration) -> Result<u64> {
+        let res = sqlx::query!(
+            r#"
+                DELETE FROM app_public.rule_executions
+                      WHERE time < NOW() - ($1)::interval
+            "#,
+            PgInterval::try_from(retention).map_err(Error::PgInterval)?,
+        )
+        .execute(pool)
+        .await?;
+
+        Ok(res.rows_affected())
+    }
+}
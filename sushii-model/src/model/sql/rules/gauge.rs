@@ -13,6 +13,10 @@ pub enum RuleScope {
     Guild,
     Channel,
     User,
+    /// A user in a single channel, the channel ID is the sub scope ID
+    UserChannel,
+    /// All members with a role, the role ID is the scope ID
+    Role,
 }
 
 #[derive(Deserialize, Serialize, sqlx::FromRow, Clone, Debug)]
@@ -21,6 +25,9 @@ pub struct RuleGauge {
     pub guild_id: i64,
     pub scope: RuleScope,
     pub scope_id: i64,
+    /// Secondary ID for composite scopes, e.g. the channel ID for UserChannel
+    #[serde(default)]
+    pub sub_scope_id: Option<i64>,
     pub name: String,
     pub value: i64,
 }
@@ -37,7 +44,7 @@ impl RuleGauge {
             RuleGauge,
             r#"
                 SELECT time, guild_id, scope as "scope: RuleScope",
-                       scope_id, name, value
+                       scope_id, sub_scope_id, name, value
                   FROM app_public.rule_gauges
                  WHERE guild_id = $1
                    AND scope = $2
@@ -109,6 +116,28 @@ impl RuleGauge {
         .map_err(Into::into)
     }
 
+    /// Saves the current value of a counter. Live counter values are stored
+    /// in Redis by the rules engine, this is only used for periodic snapshots.
+    pub async fn save_snapshot(&self, pool: &sqlx::PgPool) -> Result<()> {
+        sqlx::query!(
+            r#"
+            INSERT INTO app_public.rule_gauges (time, guild_id, scope, scope_id, sub_scope_id, name, value)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7)
+            "#,
+            self.time,
+            self.guild_id,
+            self.scope as _,
+            self.scope_id,
+            self.sub_scope_id,
+            self.name,
+            self.value,
+        )
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
     pub async fn inc(
         pool: &sqlx::PgPool,
         guild_id: u64,
@@ -134,7 +163,7 @@ impl RuleGauge {
                     )
                 )
                 RETURNING time, guild_id, scope as "scope: RuleScope",
-                          scope_id, name, value
+                          scope_id, sub_scope_id, name, value
             "#,
             guild_id as i64,
             scope as _,
@@ -171,7 +200,7 @@ impl RuleGauge {
                     )
                 )
                 RETURNING time, guild_id, scope as "scope: RuleScope",
-                          scope_id, name, value
+                          scope_id, sub_scope_id, name, value
             "#,
             guild_id as i64,
             scope as _,
@@ -196,7 +225,7 @@ impl RuleGauge {
             INSERT INTO app_public.rule_gauges (time, guild_id, scope, scope_id, name, value)
                 VALUES (NOW(), $1, $2, $3, $4, 0)
                 RETURNING time, guild_id, scope as "scope: RuleScope",
-                          scope_id, name, value
+                          scope_id, sub_scope_id, name, value
             "#,
             guild_id as i64,
             scope as _,
@@ -1 +1,2 @@
+pub mod execution;
 pub mod gauge;
@@ -1,7 +1,10 @@
 use language_api_wrapper::error::Error as LanguageApiError;
 use lapin::Error as LapinError;
 use std::borrow::Cow;
+use std::error::Error as StdError;
+use std::io::Error as IoError;
 use std::num::TryFromIntError;
+use std::path::PathBuf;
 use std::result::Result as StdResult;
 use sushii_model::Error as SushiiModelError;
 use thiserror::Error as ThisError;
@@ -41,10 +44,14 @@ pub enum Error {
     MissingMessageId,
     #[error("Event is missing member data")]
     MissingMember,
+    #[error("Counter with a role scope is missing a role ID")]
+    MissingRoleId,
     #[error("Rule config does not have {0:?} set")]
     RuleConfigMissingField(Cow<'static, str>),
     #[error("Rule config field {0:?} is not the correct type {1:?}")]
     RuleConfigMismatchedType(Cow<'static, str>, Cow<'static, str>),
+    #[error("Unknown timezone {0:?}")]
+    InvalidTimezone(String),
     #[error("Guild config does not have {0:?} set")]
     GuildConfigMissingField(Cow<'static, str>),
     #[error("Unknown data store error")]
@@ -57,6 +64,18 @@ pub enum Error {
     InvalidEventConstraint(&'static str, Trigger),
     #[error("Unsupported gateway event")]
     UnsupportedEvent,
+    #[error("Action {action} failed, {message}")]
+    ActionFailed {
+        action: &'static str,
+        message: String,
+        transient: bool,
+    },
+    #[error("Rule {rule_id} failed, {message}")]
+    RuleFailed {
+        rule_id: i64,
+        message: String,
+        transient: bool,
+    },
     #[error(transparent)]
     LanguageApi(#[from] LanguageApiError),
     #[error("Failed to deserialize event `{0}`, {1}")]
@@ -72,6 +91,18 @@ pub enum Error {
     #[error(transparent)]
     SerdeJson(#[from] serde_json::Error),
     #[error(transparent)]
+    SerdeYaml(#[from] serde_yaml::Error),
+    #[error(transparent)]
+    Io(#[from] IoError),
+    #[error(transparent)]
+    Join(#[from] tokio::task::JoinError),
+    #[error(transparent)]
+    Render(#[from] handlebars::RenderError),
+    #[error("Template render exceeded the {0} budget")]
+    RenderBudgetExceeded(&'static str),
+    #[error("Invalid rule set file {0:?}, {1}")]
+    InvalidRuleSetFile(PathBuf, String),
+    #[error(transparent)]
     Config(#[from] config::ConfigError),
     #[error(transparent)]
     Sqlx(#[from] sqlx::Error),
@@ -80,3 +111,89 @@ pub enum Error {
     #[error(transparent)]
     Redis(#[from] redis::RedisError),
 }
+
+impl Error {
+    /// If the error is temporary, e.g. a dropped connection or a Discord
+    /// server error, and the event that caused it can be retried
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::ActionFailed { transient, .. } | Self::RuleFailed { transient, .. } => *transient,
+            Self::LanguageApi(_) | Self::Lapin(_) | Self::Io(_) | Self::RedisPool(_) => true,
+            Self::Sqlx(e) => is_sqlx_transient(e),
+            Self::Redis(e) => is_redis_transient(e),
+            Self::SushiiModel(SushiiModelError::Sqlx(e)) => is_sqlx_transient(e),
+            Self::SushiiModel(SushiiModelError::Io(_)) => true,
+            _ => false,
+        }
+    }
+}
+
+/// If any error in the chain is temporary. Errors from other crates that
+/// aren't wrapped in [`Error`] are checked too, like Discord API errors from
+/// actions.
+pub fn is_transient(err: &(dyn StdError + 'static)) -> bool {
+    std::iter::successors(Some(err), |e| e.source()).any(|e| {
+        if let Some(e) = e.downcast_ref::<Error>() {
+            e.is_transient()
+        } else if let Some(e) = e.downcast_ref::<SushiiModelError>() {
+            matches!(e, SushiiModelError::Io(_))
+                || matches!(e, SushiiModelError::Sqlx(e) if is_sqlx_transient(e))
+        } else if let Some(e) = e.downcast_ref::<sqlx::Error>() {
+            is_sqlx_transient(e)
+        } else if let Some(e) = e.downcast_ref::<redis::RedisError>() {
+            is_redis_transient(e)
+        } else if let Some(e) = e.downcast_ref::<twilight_http::Error>() {
+            match e {
+                // Client errors like missing permissions fail again on retry
+                twilight_http::Error::Response { status, .. } => {
+                    status.is_server_error() || status.as_u16() == 429
+                }
+                _ => true,
+            }
+        } else {
+            e.is::<deadpool_redis::PoolError>() || e.is::<IoError>() || e.is::<LapinError>()
+        }
+    })
+}
+
+fn is_sqlx_transient(e: &sqlx::Error) -> bool {
+    matches!(
+        e,
+        sqlx::Error::Io(_)
+            | sqlx::Error::PoolTimedOut
+            | sqlx::Error::PoolClosed
+            | sqlx::Error::WorkerCrashed
+    )
+}
+
+fn is_redis_transient(e: &redis::RedisError) -> bool {
+    e.is_io_error() || e.is_timeout() || e.is_connection_dropped() || e.is_connection_refusal()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transient_errors() {
+        let io = Error::Io(IoError::new(std::io::ErrorKind::ConnectionReset, "reset"));
+        assert!(io.is_transient());
+        assert!(is_transient(&io));
+        assert!(is_transient(&sqlx::Error::PoolTimedOut));
+
+        assert!(!Error::MissingUserId.is_transient());
+        assert!(!is_transient(&sqlx::Error::RowNotFound));
+    }
+
+    #[test]
+    fn rule_failures_keep_transient() {
+        let err = Error::RuleFailed {
+            rule_id: 1,
+            message: "Action Ban failed".into(),
+            transient: true,
+        };
+
+        assert!(err.is_transient());
+        assert!(is_transient(&err));
+    }
+}
@@ -1,6 +1,31 @@
-use sushii_rules::model;
+use std::env;
+use std::path::Path;
+use std::process;
 
-fn main() {
-    let schema = schemars::schema_for!(model::Rule);
-    println!("{}", serde_json::to_string_pretty(&schema).unwrap());
+use sushii_rules::model::config::config_json_schema;
+use sushii_rules::persistence::file::load_rule_set_file;
+use sushii_rules::schema;
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = env::args().collect();
+
+    // With a rule set file, print the schema of its config for the dashboard
+    // config form instead of the rule schema bundle
+    let json = match args.get(1) {
+        Some(path) => match load_rule_set_file(Path::new(path)).await {
+            Ok(rule_set) => {
+                serde_json::to_string_pretty(&config_json_schema(&rule_set.config_schema))
+            }
+            Err(e) => {
+                eprintln!("invalid: {}", e);
+                process::exit(1);
+            }
+        },
+        // Serialized directly to keep the definition order, schema.json is
+        // generated from this
+        None => serde_json::to_string_pretty(&schema::schema_bundle()),
+    };
+
+    println!("{}", json.unwrap());
 }
@@ -0,0 +1,30 @@
+use std::env;
+use std::fs;
+use std::process;
+
+use sushii_rules::legacy;
+use sushii_rules::model::validation::ValidationContext;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+
+    if args.len() < 2 {
+        eprintln!("Usage: {} <legacy automod.json> [rule set name]", args[0]);
+        process::exit(1);
+    }
+
+    let contents = fs::read_to_string(&args[1]).expect("Failed to read legacy config");
+    let name = args.get(2).map_or("Imported automod", String::as_str);
+
+    let res = legacy::import_str(&contents, name).expect("Failed to parse legacy config");
+
+    for warning in &res.warnings {
+        eprintln!("warning: {}", warning);
+    }
+
+    for error in res.rule_set.validate(&ValidationContext::default()) {
+        eprintln!("invalid: {}", error);
+    }
+
+    println!("{}", serde_json::to_string_pretty(&res.rule_set).unwrap());
+}
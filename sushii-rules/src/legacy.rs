@@ -0,0 +1,457 @@
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::model::{
+    config::{DurationVar, IntegerVar},
+    constraint::*,
+    Action, Condition, Constraint, Rule, RuleSet, Trigger,
+};
+use crate::schema::SCHEMA_VERSION;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct LegacyConfig {
+    #[serde(default)]
+    pub defaults: HashMap<String, Value>,
+    #[serde(default)]
+    pub rules: Vec<LegacyRule>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct LegacyRule {
+    pub name: String,
+    #[serde(default)]
+    pub scopes: Vec<LegacyItem>,
+    #[serde(default)]
+    pub conditions: Vec<LegacyItem>,
+    #[serde(default)]
+    pub triggers: Vec<LegacyItem>,
+    #[serde(default)]
+    pub actions: Vec<LegacyItem>,
+}
+
+/// A single scope, condition, trigger or action
+#[derive(Debug, Clone, Deserialize)]
+pub struct LegacyItem {
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(default)]
+    pub attributes: Map<String, Value>,
+    /// Some items have attributes at the top level instead
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+impl LegacyItem {
+    fn attr(&self, key: &str) -> Option<&Value> {
+        self.attributes.get(key).or_else(|| self.extra.get(key))
+    }
+}
+
+/// Something in the legacy config that couldn't be translated
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImportWarning {
+    /// JSON pointer to the legacy config item
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for ImportWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ImportResult {
+    pub rule_set: RuleSet,
+    pub warnings: Vec<ImportWarning>,
+}
+
+#[derive(Default)]
+struct Importer {
+    warnings: Vec<ImportWarning>,
+}
+
+impl Importer {
+    fn warn(&mut self, path: String, message: impl Into<String>) {
+        self.warnings.push(ImportWarning {
+            path,
+            message: message.into(),
+        });
+    }
+
+    fn import(mut self, config: &LegacyConfig, name: &str) -> ImportResult {
+        let mut keys: Vec<&String> = config.defaults.keys().collect();
+        keys.sort();
+
+        for key in keys {
+            self.warn(
+                format!("/defaults/{}", key),
+                "defaults are not supported, set this on each action instead",
+            );
+        }
+
+        let rules = config
+            .rules
+            .iter()
+            .enumerate()
+            .map(|(i, rule)| self.import_rule(rule, &format!("/rules/{}", i)))
+            .collect();
+
+        let rule_set = RuleSet {
+            id: 0,
+            schema_version: SCHEMA_VERSION,
+            guild_id: None,
+            name: name.to_string(),
+            description: Some("Imported from legacy automod config".into()),
+            enabled: true,
+            editable: true,
+            author: None,
+            category: Some("Auto-moderator".into()),
+            config: HashMap::new(),
+            config_schema: Vec::new(),
+            rules,
+            tests: Vec::new(),
+        };
+
+        ImportResult {
+            rule_set,
+            warnings: self.warnings,
+        }
+    }
+
+    fn import_rule(&mut self, rule: &LegacyRule, path: &str) -> Rule {
+        for (i, scope) in rule.scopes.iter().enumerate() {
+            self.import_scope(scope, &format!("{}/scopes/{}", path, i));
+        }
+
+        let mut enabled = true;
+        let mut conditions = Vec::new();
+
+        for (i, condition) in rule.conditions.iter().enumerate() {
+            let condition_path = format!("{}/conditions/{}", path, i);
+
+            match self.import_condition(condition, &condition_path) {
+                Some(c) => conditions.push(c),
+                // Dropping a condition would make the rule match more than
+                // it used to
+                None => {
+                    self.warn(
+                        condition_path,
+                        "condition could not be translated, rule is disabled",
+                    );
+
+                    enabled = false;
+                }
+            }
+        }
+
+        let mut required = Vec::new();
+        let mut optional = Vec::new();
+
+        for (i, trigger) in rule.triggers.iter().enumerate() {
+            let trigger_path = format!("{}/triggers/{}", path, i);
+            let is_required = trigger
+                .attr("required")
+                .and_then(Value::as_bool)
+                .unwrap_or(false);
+
+            match self.import_trigger(trigger, &trigger_path) {
+                Some(c) if is_required => required.push(c),
+                Some(c) => optional.push(c),
+                None if is_required => {
+                    self.warn(
+                        trigger_path,
+                        "required trigger could not be translated, rule is disabled",
+                    );
+
+                    enabled = false;
+                }
+                None => {}
+            }
+        }
+
+        // Rules without any triggers left would match every message
+        if !rule.triggers.is_empty() && required.is_empty() && optional.is_empty() {
+            self.warn(
+                format!("{}/triggers", path),
+                "none of the triggers could be translated, rule is disabled",
+            );
+
+            enabled = false;
+        }
+
+        // Required triggers all have to match, otherwise any trigger matches
+        conditions.extend(required);
+        if !optional.is_empty() {
+            conditions.push(Condition::Or { or: optional });
+        }
+
+        let actions = rule
+            .actions
+            .iter()
+            .enumerate()
+            .filter_map(|(i, action)| {
+                self.import_action(action, &format!("{}/actions/{}", path, i))
+            })
+            .collect();
+
+        Rule {
+            id: 0,
+            name: rule.name.clone(),
+            enabled,
+            trigger: Trigger::MessageCreate,
+            conditions: Condition::And { and: conditions },
+            actions,
+            cooldown: None,
+            priority: 0,
+            stop_processing: false,
+        }
+    }
+
+    fn import_scope(&mut self, scope: &LegacyItem, path: &str) {
+        match scope.kind.as_str() {
+            // Rules already apply to all members and channels
+            "member" | "channel" if scope.attributes.is_empty() => {}
+            kind => self.warn(
+                path.to_string(),
+                format!(
+                    "scope {:?} is not supported, rule applies to the whole server",
+                    kind
+                ),
+            ),
+        }
+    }
+
+    fn import_condition(&mut self, condition: &LegacyItem, path: &str) -> Option<Condition> {
+        match condition.kind.as_str() {
+            "member age" => {
+                let age = condition.attr("age").and_then(Value::as_str);
+                let secs = match age.map(humantime::parse_duration) {
+                    Some(Ok(d)) => d.as_secs(),
+                    _ => {
+                        self.warn(path.to_string(), "member age is missing a valid age");
+                        return None;
+                    }
+                };
+
+                // Member joined less than age ago
+                Some(Condition::Not {
+                    not: Box::new(message_condition(MessageConstraint::Member(
+                        MemberConstraint::JoinedAt(DateConstraint::OlderThan(DurationVar::Value(
+                            secs,
+                        ))),
+                    ))),
+                })
+            }
+            kind => {
+                self.warn(
+                    path.to_string(),
+                    format!("condition {:?} is not supported", kind),
+                );
+                None
+            }
+        }
+    }
+
+    fn import_trigger(&mut self, trigger: &LegacyItem, path: &str) -> Option<Condition> {
+        match trigger.kind.as_str() {
+            "word list match" => {
+                let words: Vec<String> = match trigger.attr("words").and_then(Value::as_array) {
+                    Some(words) => words
+                        .iter()
+                        .filter_map(|w| w.as_str().map(ToString::to_string))
+                        .collect(),
+                    None => {
+                        self.warn(path.to_string(), "word list match is missing words");
+                        return None;
+                    }
+                };
+
+                self.warn(
+                    path.to_string(),
+                    "word list match is now case sensitive, consider using a word list",
+                );
+
+                Some(message_condition(MessageConstraint::Content(
+                    StringConstraint::ContainsAny(words),
+                )))
+            }
+            "message content regex match" => {
+                self.warn(path.to_string(), "regex matches are not supported");
+                None
+            }
+            "message lines" => {
+                self.warn(path.to_string(), "message line counts are not supported");
+                None
+            }
+            "message mentions" => {
+                self.warn(path.to_string(), "message mention counts are not supported");
+                None
+            }
+            kind => {
+                self.warn(
+                    path.to_string(),
+                    format!("trigger {:?} is not supported", kind),
+                );
+                None
+            }
+        }
+    }
+
+    fn import_action(&mut self, action: &LegacyItem, path: &str) -> Option<Action> {
+        let reason = action
+            .attr("log_message")
+            .and_then(Value::as_str)
+            .map(ToString::to_string);
+
+        match action.kind.as_str() {
+            "ban" => Some(Action::Ban {
+                delete_days: IntegerVar::Value(0),
+                reason,
+            }),
+            "mute" => {
+                let duration = match action.attr("duration").and_then(Value::as_str) {
+                    Some(d) => match humantime::parse_duration(d) {
+                        Ok(d) => Some(DurationVar::Value(d.as_secs())),
+                        Err(e) => {
+                            self.warn(path.to_string(), format!("invalid mute duration, {}", e));
+                            return None;
+                        }
+                    },
+                    None => None,
+                };
+
+                Some(Action::Mute { duration, reason })
+            }
+            "delete message" => {
+                self.warn(path.to_string(), "deleting messages is not supported");
+                None
+            }
+            "send message" => {
+                self.warn(
+                    path.to_string(),
+                    "send message has no content to send, add a Reply or SendMessage action",
+                );
+                None
+            }
+            kind => {
+                self.warn(
+                    path.to_string(),
+                    format!("action {:?} is not supported", kind),
+                );
+                None
+            }
+        }
+    }
+}
+
+fn message_condition(constraint: MessageConstraint) -> Condition {
+    Condition::Condition {
+        constraint: Constraint::Message(constraint),
+    }
+}
+
+/// Converts a legacy automod config (scopes, conditions, triggers and
+/// defaults) into a rule set with the given name. Anything that can't be
+/// translated is reported as a warning with a JSON pointer to the legacy config.
+pub fn import(config: &LegacyConfig, name: &str) -> ImportResult {
+    Importer::default().import(config, name)
+}
+
+/// Parses and converts a legacy automod config json string
+pub fn import_str(s: &str, name: &str) -> serde_json::Result<ImportResult> {
+    let config: LegacyConfig = serde_json::from_str(s)?;
+
+    Ok(import(&config, name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AUTOMOD_JSON: &str = include_str!("../../automod.json");
+
+    #[test]
+    fn imports_automod_json() {
+        let res = import_str(AUTOMOD_JSON, "automod").unwrap();
+        let rule = &res.rule_set.rules[0];
+
+        assert_eq!(rule.name, "auto ban");
+        assert!(rule.enabled);
+        assert!(matches!(rule.actions[0], Action::Ban { .. }));
+        assert_eq!(rule.actions.len(), 1);
+    }
+
+    #[test]
+    fn reports_untranslated() {
+        let res = import_str(AUTOMOD_JSON, "automod").unwrap();
+        let paths: Vec<&str> = res.warnings.iter().map(|w| w.path.as_str()).collect();
+
+        for path in &[
+            "/defaults/channel_message_if_dms_disabled",
+            "/rules/0/triggers/1",
+            "/rules/0/triggers/2",
+            "/rules/0/triggers/3",
+            "/rules/0/actions/1",
+            "/rules/0/actions/2",
+        ] {
+            assert!(paths.contains(path), "missing warning for {}", path);
+        }
+    }
+
+    #[test]
+    fn disables_rule_without_triggers() {
+        let res = import_str(
+            r#"{"rules": [{
+                "name": "regex only",
+                "triggers": [{"type": "message content regex match", "attributes": {"regex": "a+"}}],
+                "actions": [{"type": "ban"}]
+            }]}"#,
+            "automod",
+        )
+        .unwrap();
+
+        assert!(!res.rule_set.rules[0].enabled);
+    }
+
+    #[test]
+    fn disables_rule_with_untranslated_condition() {
+        let res = import_str(
+            r#"{"rules": [{
+                "name": "bad age",
+                "conditions": [{"type": "member age", "attributes": {"age": "soon"}}],
+                "triggers": [{"type": "word list match", "attributes": {"words": ["a"]}}],
+                "actions": [{"type": "ban"}]
+            }]}"#,
+            "automod",
+        )
+        .unwrap();
+
+        assert!(!res.rule_set.rules[0].enabled);
+        assert!(res
+            .warnings
+            .iter()
+            .any(|w| w.path == "/rules/0/conditions/0" && w.message.contains("disabled")));
+    }
+
+    #[test]
+    fn disables_rule_with_untranslated_required_trigger() {
+        let res = import_str(
+            r#"{"rules": [{
+                "name": "required regex",
+                "triggers": [
+                    {"type": "word list match", "attributes": {"words": ["a"]}},
+                    {"type": "message content regex match", "attributes": {"regex": "a+", "required": true}}
+                ],
+                "actions": [{"type": "ban"}]
+            }]}"#,
+            "automod",
+        )
+        .unwrap();
+
+        assert!(!res.rule_set.rules[0].enabled);
+    }
+}
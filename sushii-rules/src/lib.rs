@@ -27,5 +27,9 @@
 // DEALINGS IN THE SOFTWARE.
 
 pub mod error;
+pub mod legacy;
+pub mod migration;
 pub mod model;
 pub mod persistence;
+pub mod schema;
+pub mod source;
@@ -1,22 +1,27 @@
 use dotenv::dotenv;
-use futures::pin_mut;
 use metrics_exporter_prometheus::PrometheusBuilder;
 use metrics_util::layers::{Layer, PrefixLayer};
 use serde::Deserialize;
-use std::pin::Pin;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::mpsc;
-use tokio_stream::{Stream, StreamExt};
+use tokio_stream::StreamExt;
 use tracing_subscriber::EnvFilter;
 use twilight_http::Client;
 
+use sushii_model::model::sql::RuleExecution;
+
 use sushii_rules::{
-    error::Result,
-    model::{Event, RulesEngine},
+    error::{Error, Result},
+    model::{
+        engine::{ActionBudget, BudgetMode, LanguageBackend},
+        EngineOptions, Event, RulesEngine, WorkerPool,
+    },
+    persistence::{counter::save_snapshots, FileStore, PostgresStore, RuleStore},
+    source::{AmqpSource, EventSource, JsonlSource},
 };
 
-mod gateway;
-
 #[derive(Debug, Deserialize)]
 pub struct RabbitMq {
     pub host: String,
@@ -25,17 +30,165 @@ pub struct RabbitMq {
     pub password: String,
 }
 
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleStoreKind {
+    /// Rule sets managed by the web dashboard
+    Postgres,
+    /// Rule sets loaded from a directory of JSON or YAML files
+    File,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventSourceKind {
+    /// Gateway events from the RabbitMQ queue
+    Amqp,
+    /// Gateway payloads replayed from a file, one JSON payload per line
+    Jsonl,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LanguageDetectorKind {
+    /// In process with lingua
+    Lingua,
+    /// Language API over HTTP
+    Http,
+}
+
+impl Default for LanguageDetectorKind {
+    fn default() -> Self {
+        Self::Lingua
+    }
+}
+
+impl Default for EventSourceKind {
+    fn default() -> Self {
+        Self::Amqp
+    }
+}
+
+impl Default for RuleStoreKind {
+    fn default() -> Self {
+        Self::Postgres
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Config {
     pub twilight_api_proxy_url: String,
-    pub language_api_endpoint: String,
+    /// Where language constraints detect languages
+    #[serde(default)]
+    pub language_detector: LanguageDetectorKind,
+    /// Required for the http language detector
+    pub language_api_endpoint: Option<String>,
 
     pub database_url: String,
 
-    pub rabbit: RabbitMq,
+    /// Where gateway events are received from
+    #[serde(default)]
+    pub event_source: EventSourceKind,
+    /// Required for the amqp event source
+    pub rabbit: Option<RabbitMq>,
+    /// File of gateway payloads, required for the jsonl event source
+    pub events_file: Option<String>,
 
     #[serde(default)]
     pub redis: deadpool_redis::Config,
+
+    /// How often modified counters are saved to Postgres, in seconds
+    #[serde(default = "default_counter_snapshot_interval")]
+    pub counter_snapshot_interval: u64,
+
+    /// Where rule sets are loaded from
+    #[serde(default)]
+    pub rule_store: RuleStoreKind,
+    /// Directory of rule set files, required for the file rule store
+    pub rules_dir: Option<String>,
+    /// How often to check rule set files for changes, in seconds
+    #[serde(default = "default_rules_reload_interval")]
+    pub rules_reload_interval: u64,
+
+    /// Fail rendering templates with undefined variables
+    #[serde(default)]
+    pub strict_templates: bool,
+
+    /// Max Discord API actions per guild in each window, unlimited if unset
+    pub action_budget_limit: Option<u64>,
+    /// Length of the action budget window, in seconds
+    #[serde(default = "default_action_budget_window")]
+    pub action_budget_window: u64,
+    /// Drop or queue actions past the action budget
+    #[serde(default)]
+    pub action_budget_mode: BudgetMode,
+
+    /// Max chain of counter events from a single gateway event
+    #[serde(default = "default_max_counter_depth")]
+    pub max_counter_depth: usize,
+
+    /// Times an event is processed before it is dead lettered
+    #[serde(default = "default_max_event_attempts")]
+    pub max_event_attempts: u32,
+    /// Delay before retrying a failed event, doubled on each retry, in
+    /// milliseconds
+    #[serde(default = "default_retry_backoff_ms")]
+    pub retry_backoff_ms: u64,
+
+    /// Number of workers processing events, each processes one event at a time
+    #[serde(default = "default_worker_count")]
+    pub worker_count: usize,
+    /// Max gateway events waiting for or being processed by workers
+    #[serde(default = "default_max_pending_events")]
+    pub max_pending_events: usize,
+    /// How long to wait for pending events to finish when stopping, in
+    /// seconds
+    #[serde(default = "default_shutdown_timeout")]
+    pub shutdown_timeout: u64,
+
+    /// How long rule executions are kept, in days
+    #[serde(default = "default_execution_retention_days")]
+    pub execution_retention_days: i64,
+}
+
+fn default_counter_snapshot_interval() -> u64 {
+    60
+}
+
+fn default_rules_reload_interval() -> u64 {
+    5
+}
+
+fn default_action_budget_window() -> u64 {
+    60
+}
+
+fn default_max_counter_depth() -> usize {
+    5
+}
+
+fn default_max_event_attempts() -> u32 {
+    3
+}
+
+fn default_retry_backoff_ms() -> u64 {
+    1000
+}
+
+fn default_worker_count() -> usize {
+    16
+}
+
+fn default_max_pending_events() -> usize {
+    256
+}
+
+fn default_shutdown_timeout() -> u64 {
+    30
+}
+
+fn default_execution_retention_days() -> i64 {
+    30
 }
 
 impl Config {
@@ -46,6 +199,11 @@ impl Config {
     }
 }
 
+/// Error for a config option that is only required by some other option
+fn missing_config(message: &str) -> Error {
+    config::ConfigError::Message(message.to_string()).into()
+}
+
 fn start_metrics() {
     // Start metrics server
     let (recorder, exporter) = PrometheusBuilder::new()
@@ -72,7 +230,7 @@ async fn main() -> Result<()> {
         .with_env_filter(EnvFilter::from_default_env())
         .init();
 
-    let cfg = Config::from_env().expect("Failed to create config");
+    let cfg = Config::from_env()?;
 
     tracing::info!("Config {:?}", &cfg);
 
@@ -101,30 +259,145 @@ async fn main() -> Result<()> {
         current_user.discriminator
     );
 
-    // Trigger events from other rules, like counter updates or timers
-    let (channel_tx, mut channel_rx) = mpsc::channel(32);
+    // Counter events sent by rules, these are queued on the worker pool
+    // directly instead of waiting for a pending slot
+    let (channel_tx, counter_rx) = mpsc::unbounded_channel();
+
+    let rule_store: Box<dyn RuleStore> = match cfg.rule_store {
+        RuleStoreKind::Postgres => {
+            Box::new(PostgresStore::new(pg_pool.clone(), redis_pool.clone()))
+        }
+        RuleStoreKind::File => {
+            let rules_dir = cfg
+                .rules_dir
+                .as_ref()
+                .ok_or_else(|| missing_config("RULES_DIR is required for the file rule store"))?;
+
+            let store = FileStore::new(rules_dir).await?;
+            store.watch(Duration::from_secs(cfg.rules_reload_interval));
+
+            Box::new(store)
+        }
+    };
+
+    let language_backend = match cfg.language_detector {
+        LanguageDetectorKind::Lingua => LanguageBackend::Lingua,
+        LanguageDetectorKind::Http => {
+            LanguageBackend::Http(cfg.language_api_endpoint.clone().ok_or_else(|| {
+                missing_config("LANGUAGE_API_ENDPOINT is required for the http language detector")
+            })?)
+        }
+    };
 
     let engine = RulesEngine::new(
+        rule_store,
         http,
         pg_pool,
         redis_pool,
-        &cfg.language_api_endpoint,
         channel_tx,
+        EngineOptions {
+            strict_templates: cfg.strict_templates,
+            action_budget: cfg.action_budget_limit.map(|limit| ActionBudget {
+                limit,
+                window: Duration::from_secs(cfg.action_budget_window),
+                mode: cfg.action_budget_mode,
+            }),
+            max_counter_depth: cfg.max_counter_depth,
+            max_event_attempts: cfg.max_event_attempts,
+            retry_backoff: Duration::from_millis(cfg.retry_backoff_ms),
+            language_backend,
+        },
     );
 
-    let rabbit_stream = gateway::get_events(&cfg).await?;
-    pin_mut!(rabbit_stream);
+    // Drop message history of users that haven't sent anything recently
+    let message_history = engine.handles.message_history.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(60));
+
+        loop {
+            interval.tick().await;
+            message_history.prune();
+        }
+    });
+
+    // Periodically save counters to Postgres, live values are only in Redis
+    let counters = engine.handles.counters.clone();
+    let snapshot_pool = engine.handles.pg_pool.clone();
+    let snapshot_interval = Duration::from_secs(cfg.counter_snapshot_interval);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(snapshot_interval);
+
+        loop {
+            interval.tick().await;
 
-    let trigger_stream = Box::pin(async_stream::stream! {
-        while let Some(event) = channel_rx.recv().await {
-            yield Ok(event);
+            match save_snapshots(counters.as_ref(), &snapshot_pool).await {
+                Ok(count) => tracing::debug!("Saved {} counter snapshots", count),
+                Err(e) => tracing::warn!("Failed to save counter snapshots: {}", e),
+            }
         }
-    }) as Pin<Box<dyn Stream<Item = Result<Event>> + Send>>;
+    });
 
-    // Merge both redis events and triggered events
-    let mut rx = rabbit_stream.merge(trigger_stream);
+    // Delete old rule executions
+    let prune_pool = engine.handles.pg_pool.clone();
+    let retention = chrono::Duration::days(cfg.execution_retention_days);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(60 * 60));
+
+        loop {
+            interval.tick().await;
+
+            match RuleExecution::prune(&prune_pool, retention).await {
+                Ok(count) => tracing::debug!("Pruned {} rule executions", count),
+                Err(e) => tracing::warn!("Failed to prune rule executions: {}", e),
+            }
+        }
+    });
+
+    let event_source: Box<dyn EventSource> = match cfg.event_source {
+        EventSourceKind::Amqp => {
+            let rabbit = cfg.rabbit.as_ref().ok_or_else(|| {
+                missing_config("RABBIT config is required for the amqp event source")
+            })?;
+
+            Box::new(AmqpSource::new(format!(
+                "amqp://{}:{}@{}:{}/%2f",
+                rabbit.username, rabbit.password, rabbit.host, rabbit.port
+            )))
+        }
+        EventSourceKind::Jsonl => {
+            let events_file = cfg.events_file.as_ref().ok_or_else(|| {
+                missing_config("EVENTS_FILE is required for the jsonl event source")
+            })?;
+
+            Box::new(JsonlSource::new(events_file))
+        }
+    };
+
+    let mut rx = event_source.events().await?;
+
+    let engine = Arc::new(engine);
+    let discord_cache = engine.handles.discord_cache.clone();
+
+    let workers = WorkerPool::new(engine, cfg.worker_count, cfg.max_pending_events, counter_rx);
+
+    let mut sigterm = signal(SignalKind::terminate())?;
+
+    loop {
+        let event = tokio::select! {
+            event = rx.next() => match event {
+                Some(e) => e,
+                None => break,
+            },
+            _ = sigterm.recv() => {
+                tracing::info!("Received SIGTERM, finishing pending events");
+                break;
+            }
+            _ = tokio::signal::ctrl_c() => {
+                tracing::info!("Received SIGINT, finishing pending events");
+                break;
+            }
+        };
 
-    while let Some(event) = rx.next().await {
         let event = match event {
             Ok(e) => e,
             Err(e) => {
@@ -133,10 +406,30 @@ async fn main() -> Result<()> {
             }
         };
 
-        if let Err(e) = engine.process_event(Arc::new(event)).await {
-            tracing::error!("Failed to process event: {}", e);
+        // Cache is updated here instead of in workers so updates are applied
+        // in the order events are received, events that don't trigger rules
+        // are cached too
+        if let Event::Twilight(e) = &event.event {
+            discord_cache.update(e);
         }
+
+        // Waits when workers are behind, which stops consuming events until
+        // they catch up
+        workers.submit(event).await;
     }
 
+    // The stream isn't dropped until workers finish since the AMQP connection
+    // is needed to ack events. Counter events sent by pending events are
+    // processed before the workers stop.
+    let shutdown_timeout = Duration::from_secs(cfg.shutdown_timeout);
+    if tokio::time::timeout(shutdown_timeout, workers.shutdown())
+        .await
+        .is_err()
+    {
+        tracing::warn!("Timed out finishing pending events, unacked events will be redelivered");
+    }
+
+    drop(rx);
+
     Ok(())
 }
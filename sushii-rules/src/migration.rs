@@ -0,0 +1,191 @@
+use serde_json::{Map, Value};
+
+use crate::schema::SCHEMA_VERSION;
+
+/// Version of rule JSON stored before versions were added
+pub const UNVERSIONED: u32 = 1;
+
+/// Upgrades rule JSON from the previous version. Migrations only run on JSON
+/// older than the version they upgrade to, database rules store their own
+/// `schema_version` and rule sets have one for all of their rules.
+type Migration = fn(&mut Value);
+
+/// Migrations indexed by the version they upgrade to
+const MIGRATIONS: &[(u32, Migration)] = &[(2, wrap_config_vars)];
+
+/// Upgrades a rule, condition, action or any part of them from the given
+/// version to the current schema version
+pub fn migrate(value: &mut Value, from_version: u32) {
+    for (version, migration) in MIGRATIONS {
+        if *version > from_version {
+            migration(value);
+        }
+    }
+}
+
+/// Upgrades a rule set and all of its rules, using the `schema_version` in the
+/// rule set
+pub fn migrate_rule_set(value: &mut Value) {
+    let obj = match value.as_object_mut() {
+        Some(obj) => obj,
+        None => return,
+    };
+
+    let from_version = obj
+        .get("schema_version")
+        .and_then(Value::as_u64)
+        .map_or(UNVERSIONED, |v| v as u32);
+
+    if let Some(rules) = obj.get_mut("rules") {
+        migrate(rules, from_version);
+    }
+
+    obj.insert("schema_version".into(), SCHEMA_VERSION.into());
+}
+
+// Version 2: Numbers, bools, durations and IDs became config vars, so bare
+// values need to be wrapped in {"value": ...}
+
+/// Integer and bool constraint keys. Counter values use the same keys with
+/// plain numbers so counters are handled separately.
+const CONSTRAINT_VAR_KEYS: &[&str] = &["equals", "not_equals", "greater_than", "less_than"];
+
+fn wrap_config_vars(value: &mut Value) {
+    match value {
+        Value::Object(obj) => {
+            for (key, child) in obj.iter_mut() {
+                match key.as_str() {
+                    "counter" => wrap_counter_constraint(child),
+                    k if CONSTRAINT_VAR_KEYS.contains(&k) => wrap_value(child),
+                    "older_than" => wrap_value(child),
+                    "inclusive_between" | "exclusive_between" => {
+                        wrap_fields(child, &["lower", "upper"])
+                    }
+                    "duplicates" => wrap_fields(child, &["count", "duration"]),
+                    "SendMessage" => wrap_fields(child, &["channel_id"]),
+                    "Ban" => wrap_fields(child, &["delete_days", "duration"]),
+                    "Mute" => wrap_fields(child, &["duration"]),
+                    "AddCounter" | "SubtractCounter" | "ResetCounter" => {
+                        wrap_fields(child, &["role_id"])
+                    }
+                    _ => wrap_config_vars(child),
+                }
+            }
+        }
+        Value::Array(vec) => vec.iter_mut().for_each(wrap_config_vars),
+        _ => {}
+    }
+}
+
+fn wrap_counter_constraint(counter: &mut Value) {
+    wrap_fields(counter, &["role_id"]);
+
+    if let Some(counts) = counter.pointer_mut("/value/counts_in_duration") {
+        wrap_fields(counts, &["increased_by", "duration"]);
+    }
+}
+
+fn wrap_fields(value: &mut Value, fields: &[&str]) {
+    if let Some(obj) = value.as_object_mut() {
+        for field in fields {
+            if let Some(child) = obj.get_mut(*field) {
+                wrap_value(child);
+            }
+        }
+    }
+}
+
+/// Wraps bare numbers and bools, config vars and nulls are left as is
+fn wrap_value(value: &mut Value) {
+    if value.is_number() || value.is_boolean() {
+        let mut obj = Map::new();
+        obj.insert("value".into(), value.take());
+
+        *value = Value::Object(obj);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{Action, Rule, RuleSet};
+    use serde_json::json;
+
+    fn v1_rule() -> Value {
+        json!({
+            "name": "new member spam",
+            "enabled": true,
+            "trigger": "MESSAGE_CREATE",
+            "conditions": {
+                "And": {
+                    "and": [
+                        { "Condition": { "message": { "member": { "joined_at": { "older_than": 3600 } } } } },
+                        { "Condition": { "message": { "content": { "length": { "inclusive_between": { "lower": 1, "upper": 5 } } } } } },
+                        { "Condition": { "message": { "author": { "is_bot": { "equals": false } } } } },
+                        { "Condition": { "counter": { "name": "spam", "scope": "User", "value": { "equals": 3 } } } }
+                    ]
+                }
+            },
+            "actions": [
+                { "Ban": { "delete_days": 1, "duration": null, "reason": "spam" } },
+                { "Mute": { "duration": 600, "reason": null } },
+                { "SendMessage": { "channel_id": 1234, "content": "hi" } }
+            ]
+        })
+    }
+
+    #[test]
+    fn migrates_unversioned_rule() {
+        let mut rule = v1_rule();
+        migrate(&mut rule, UNVERSIONED);
+
+        assert_eq!(
+            rule.pointer("/conditions/And/and/0/Condition/message/member/joined_at/older_than"),
+            Some(&json!({ "value": 3600 }))
+        );
+        assert_eq!(
+            rule.pointer(
+                "/conditions/And/and/1/Condition/message/content/length/inclusive_between/upper"
+            ),
+            Some(&json!({ "value": 5 }))
+        );
+        // Counter values are still plain numbers
+        assert_eq!(
+            rule.pointer("/conditions/And/and/3/Condition/counter/value/equals"),
+            Some(&json!(3))
+        );
+        assert_eq!(rule.pointer("/actions/0/Ban/duration"), Some(&Value::Null));
+
+        let rule: Rule = serde_json::from_value(rule).unwrap();
+        assert!(matches!(rule.actions[1], Action::Mute { .. }));
+    }
+
+    #[test]
+    fn migration_is_idempotent() {
+        let mut once = v1_rule();
+        migrate(&mut once, UNVERSIONED);
+
+        let mut twice = once.clone();
+        migrate(&mut twice, UNVERSIONED);
+
+        assert_eq!(once, twice);
+    }
+
+    #[test]
+    fn migrates_rule_set() {
+        let mut set = json!({
+            "name": "test",
+            "description": null,
+            "enabled": true,
+            "editable": true,
+            "author": null,
+            "category": null,
+            "rules": [v1_rule()],
+        });
+
+        migrate_rule_set(&mut set);
+
+        let set: RuleSet = serde_json::from_value(set).unwrap();
+        assert_eq!(set.schema_version, SCHEMA_VERSION);
+    }
+}
@@ -1,27 +1,48 @@
 use anyhow::Result;
 use async_recursion::async_recursion;
-use chrono::Duration;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::convert::TryInto;
 use std::sync::Arc;
 use twilight_http::request::AuditLogReason;
 use twilight_model::id::RoleId;
 
-use sushii_model::model::sql::{ModLogEntry, Mute, RuleGauge, RuleScope};
+use sushii_model::model::sql::{ModLogEntry, Mute, RuleScope};
 
 use crate::error::Error;
 use crate::model::has_id::*;
-use crate::model::{Condition, Event, RuleContext};
+use crate::model::{
+    config::{ChannelIdVar, ConfigGet, DurationVar, IntegerVar, RoleIdVar},
+    message::{send_content, MentionPolicy, Overflow},
+    mod_log::post_case,
+    Condition, Event, RuleContext,
+};
+use crate::persistence::counter::{CounterKey, CounterOptions};
 
 #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub enum Action {
     /// # Reply
     /// Sends a reply to a message trigger
-    Reply { content: String },
+    Reply {
+        content: String,
+        /// Mentions that are allowed to ping, defaults to only the replied user
+        #[serde(default)]
+        allowed_mentions: MentionPolicy,
+        /// Handling of content longer than 2000 characters
+        #[serde(default)]
+        overflow: Overflow,
+    },
     /// # Send message
     /// Sends a message to a channel
-    SendMessage { channel_id: u64, content: String },
+    SendMessage {
+        channel_id: ChannelIdVar,
+        content: String,
+        /// Mentions that are allowed to ping, defaults to none
+        #[serde(default)]
+        allowed_mentions: MentionPolicy,
+        /// Handling of content longer than 2000 characters
+        #[serde(default)]
+        overflow: Overflow,
+    },
     // Counters
     /// # Add to a counter
     AddCounter {
@@ -29,6 +50,12 @@ pub enum Action {
         name: String,
         /// Scope this counter applies to
         scope: RuleScope,
+        /// Role this counter applies to, only used for the Role scope
+        #[serde(default)]
+        role_id: Option<RoleIdVar>,
+        /// Expiry and decay of this counter
+        #[serde(flatten)]
+        options: CounterOptions,
     },
     /// # Subtract from a counter
     SubtractCounter {
@@ -36,6 +63,12 @@ pub enum Action {
         name: String,
         /// Scope this counter applies to
         scope: RuleScope,
+        /// Role this counter applies to, only used for the Role scope
+        #[serde(default)]
+        role_id: Option<RoleIdVar>,
+        /// Expiry and decay of this counter
+        #[serde(flatten)]
+        options: CounterOptions,
     },
     /// # Reset a counter
     ResetCounter {
@@ -43,15 +76,16 @@ pub enum Action {
         name: String,
         /// Scope this counter applies to
         scope: RuleScope,
+        /// Role this counter applies to, only used for the Role scope
+        #[serde(default)]
+        role_id: Option<RoleIdVar>,
     },
     // Moderation stuff
     /// # Ban
-    /// Bans a user
+    /// Bans a user permanently
     Ban {
-        /// Days of messages to delete, max 8
-        delete_days: u64,
-        /// None for permanent, otherwise duration in seconds
-        duration: Option<u64>,
+        /// Days of messages to delete, max 7
+        delete_days: IntegerVar,
         /// Reason for ban
         reason: Option<String>,
     },
@@ -59,7 +93,7 @@ pub enum Action {
     /// Mutes a user
     Mute {
         /// None for permanent, otherwise duration in seconds
-        duration: Option<u64>,
+        duration: Option<DurationVar>,
         /// Reason for mute
         reason: Option<String>,
     },
@@ -76,48 +110,178 @@ pub enum Action {
 }
 
 impl Action {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Reply { .. } => "Reply",
+            Self::SendMessage { .. } => "SendMessage",
+            Self::AddCounter { .. } => "AddCounter",
+            Self::SubtractCounter { .. } => "SubtractCounter",
+            Self::ResetCounter { .. } => "ResetCounter",
+            Self::Ban { .. } => "Ban",
+            Self::Mute { .. } => "Mute",
+            Self::SubCondition { .. } => "SubCondition",
+        }
+    }
+
+    /// If this action makes requests to Discord, these count towards the
+    /// guild's action budget
+    pub fn uses_discord_api(&self) -> bool {
+        matches!(
+            self,
+            Self::Reply { .. } | Self::SendMessage { .. } | Self::Ban { .. } | Self::Mute { .. }
+        )
+    }
+
+    /// Runs the action unless it already ran for this event, e.g. when the
+    /// event is retried after a later action failed
+    #[async_recursion]
+    pub async fn execute(&self, event: Arc<Event>, ctx: &mut RuleContext<'_>) -> Result<()> {
+        // Sub conditions don't do anything on their own, their actions are
+        // checked individually
+        if let Self::SubCondition { .. } = self {
+            return self.run(event, ctx).await;
+        }
+
+        let key = ctx.action_key();
+
+        if ctx.progress.is_done(&key) {
+            tracing::debug!(%key, action = self.name(), "Action already ran for event");
+            return Ok(());
+        }
+
+        if ctx.dry_run && self.uses_discord_api() {
+            self.dry_run(event, ctx).await?;
+            ctx.actions_run.push(self.name());
+
+            return Ok(());
+        }
+
+        if self.uses_discord_api() && !ctx.take_action_budget(&event).await? {
+            return Ok(());
+        }
+
+        self.run(event, ctx).await?;
+        ctx.progress.mark_done(&key);
+        ctx.actions_run.push(self.name());
+
+        Ok(())
+    }
+
+    /// Checks the parts of a Discord action that don't make requests, like
+    /// rendering its content and reading its config values
+    async fn dry_run(&self, event: Arc<Event>, ctx: &mut RuleContext<'_>) -> Result<()> {
+        match self {
+            Self::Reply { content, .. } => {
+                ctx.render_string(event, content).await?;
+            }
+            Self::SendMessage {
+                channel_id,
+                content,
+                ..
+            } => {
+                channel_id.get(ctx)?;
+                ctx.render_string(event, content).await?;
+            }
+            Self::Ban { delete_days, .. } => {
+                delete_days.get(ctx)?;
+            }
+            Self::Mute { duration, .. } => {
+                duration.get(ctx)?;
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
     #[async_recursion]
-    pub async fn execute(&self, event: Arc<Event>, mut ctx: &mut RuleContext<'_>) -> Result<()> {
+    async fn run(&self, event: Arc<Event>, mut ctx: &mut RuleContext<'_>) -> Result<()> {
         match *self {
-            Self::Reply { ref content } => {
+            Self::Reply {
+                ref content,
+                ref allowed_mentions,
+                overflow,
+            } => {
                 let channel_id = event.channel_id()?;
                 let message_id = event.message_id()?;
 
                 let rendered_content = ctx.render_string(event, content).await?;
 
-                ctx.http
-                    .create_message(channel_id)
-                    .content(rendered_content)?
-                    .reply(message_id)
-                    // Add required mentions in order to ping the user
-                    .allowed_mentions()
-                    .replied_user(true)
-                    .build()
-                    .await?;
+                send_content(
+                    &ctx.http,
+                    channel_id,
+                    Some(message_id),
+                    &rendered_content,
+                    allowed_mentions,
+                    overflow,
+                )
+                .await?;
+            }
+            Self::SendMessage {
+                ref channel_id,
+                ref content,
+                ref allowed_mentions,
+                overflow,
+            } => {
+                let channel_id = channel_id.get(ctx)?;
+                let rendered_content = ctx.render_string(event, content).await?;
+
+                send_content(
+                    &ctx.http,
+                    channel_id,
+                    None,
+                    &rendered_content,
+                    allowed_mentions,
+                    overflow,
+                )
+                .await?;
             }
             // Moderation
             Self::Ban {
-                delete_days,
-                duration: _,
+                ref delete_days,
                 ref reason,
             } => {
                 let guild_id = event.guild_id()?;
                 let user_id = event.user_id()?;
+                let user_tag = event.user().map_or_else(
+                    |_| user_id.0.to_string(),
+                    |u| format!("{}#{:0>4}", u.name, u.discriminator),
+                );
 
+                // Build the request first, an invalid delete_days or reason
+                // would otherwise leave a pending case behind
                 let mut fut = ctx
                     .http
                     .create_ban(guild_id, user_id)
-                    .delete_message_days(delete_days)?;
+                    .delete_message_days(delete_days.get(ctx)?)?;
 
                 // TODO: Add default reason
                 if let Some(reason) = reason {
                     fut = fut.reason(reason)?;
                 }
 
-                fut.await?;
+                // Pending case so the ban isn't logged again when the ban
+                // event is received
+                let entry = ModLogEntry::new("ban", true, guild_id.0, user_id.0, &user_tag)
+                    .reason(reason)
+                    .save_exec(&ctx.pg_pool)
+                    .await?;
+
+                if let Err(e) = fut.await {
+                    entry.delete_exec(&ctx.pg_pool).await?;
+
+                    return Err(e.into());
+                }
+
+                // Retries of the event shouldn't ban again or create another
+                // case if the case fails to post
+                ctx.progress.mark_done(&ctx.action_key());
+
+                let entry = post_case(ctx, &event, entry).await?;
+                ctx.data.actions.push(serde_json::to_value(&entry)?);
             }
             Self::Mute {
-                duration,
+                ref duration,
                 ref reason,
             } => {
                 let guild_id = event.guild_id()?;
@@ -145,16 +309,10 @@ impl Action {
                 .await?;
 
                 // Add new mute entry
-                let mute_entry = Mute::new(
-                    guild_id.0,
-                    user.id.0,
-                    duration
-                        .and_then(|s| s.try_into().ok())
-                        .map(Duration::seconds),
-                )
-                .pending(true)
-                .save_exec(&mut txn)
-                .await?;
+                let mute_entry = Mute::new(guild_id.0, user.id.0, duration.get(ctx)?)
+                    .pending(true)
+                    .save_exec(&mut txn)
+                    .await?;
 
                 // After everything else successful, commit
                 // Can't do this after the role add since we need it in the db
@@ -178,68 +336,64 @@ impl Action {
                     return Err(e.into());
                 }
 
+                ctx.progress.mark_done(&ctx.action_key());
+
+                let entry = post_case(ctx, &event, entry).await?;
+
                 // Add mute entry to handlebars ctx data
                 ctx.data.actions.push(serde_json::to_value(&entry)?);
             }
             // Counters
-            Self::AddCounter { ref name, scope } => {
-                let guild_id = event.guild_id()?;
-                let scope_id = event.scope_id(scope)?;
-
-                let counter =
-                    RuleGauge::inc(&ctx.pg_pool, guild_id.0, scope, scope_id, name).await?;
+            Self::AddCounter {
+                ref name,
+                scope,
+                ref role_id,
+                ref options,
+            } => {
+                let role_id = role_id.get(ctx)?.map(|id| id.0);
+                let key = CounterKey::from_event(&event, scope, role_id, name)?;
+                let counter = ctx.counters.inc(&key, options).await?;
 
                 ctx.data.actions.push(serde_json::to_value(&counter)?);
 
-                // Only trigger if incrementing from a twilight event Don't
-                // trigger another if this is currently a counter otherwise that
-                // would cause infinite loops
-                if let Event::Twilight(original_event) = (*event).clone() {
-                    tracing::debug!(?counter, "Triggering new Counter event");
-                    ctx.channel_tx
-                        .send(Event::Counter {
-                            counter,
-                            original_event,
-                        })
-                        .await?;
+                // Counter events can trigger rules that change counters again, the
+                // engine stops these chains when they loop or get too deep
+                if let Some(counter_event) = event.counter_event(counter, ctx.rule_id) {
+                    tracing::debug!(?counter_event, "Triggering new Counter event");
+                    ctx.channel_tx.send(counter_event)?;
                 }
             }
-            Self::SubtractCounter { ref name, scope } => {
-                let guild_id = event.guild_id()?;
-                let scope_id = event.scope_id(scope)?;
-
-                let counter =
-                    RuleGauge::dec(&ctx.pg_pool, guild_id.0, scope, scope_id, name).await?;
+            Self::SubtractCounter {
+                ref name,
+                scope,
+                ref role_id,
+                ref options,
+            } => {
+                let role_id = role_id.get(ctx)?.map(|id| id.0);
+                let key = CounterKey::from_event(&event, scope, role_id, name)?;
+                let counter = ctx.counters.dec(&key, options).await?;
 
                 ctx.data.actions.push(serde_json::to_value(&counter)?);
 
-                if let Event::Twilight(original_event) = (*event).clone() {
-                    tracing::debug!(?counter, "Triggering new Counter event");
-                    ctx.channel_tx
-                        .send(Event::Counter {
-                            counter,
-                            original_event,
-                        })
-                        .await?;
+                if let Some(counter_event) = event.counter_event(counter, ctx.rule_id) {
+                    tracing::debug!(?counter_event, "Triggering new Counter event");
+                    ctx.channel_tx.send(counter_event)?;
                 }
             }
-            Self::ResetCounter { ref name, scope } => {
-                let guild_id = event.guild_id()?;
-                let scope_id = event.scope_id(scope)?;
-
-                let counter =
-                    RuleGauge::reset(&ctx.pg_pool, guild_id.0, scope, scope_id, name).await?;
+            Self::ResetCounter {
+                ref name,
+                scope,
+                ref role_id,
+            } => {
+                let role_id = role_id.get(ctx)?.map(|id| id.0);
+                let key = CounterKey::from_event(&event, scope, role_id, name)?;
+                let counter = ctx.counters.reset(&key).await?;
 
                 ctx.data.actions.push(serde_json::to_value(&counter)?);
 
-                if let Event::Twilight(original_event) = (*event).clone() {
-                    tracing::debug!(?counter, "Triggering new Counter event");
-                    ctx.channel_tx
-                        .send(Event::Counter {
-                            counter,
-                            original_event,
-                        })
-                        .await?;
+                if let Some(counter_event) = event.counter_event(counter, ctx.rule_id) {
+                    tracing::debug!(?counter_event, "Triggering new Counter event");
+                    ctx.channel_tx.send(counter_event)?;
                 }
             }
             Self::SubCondition {
@@ -247,17 +401,25 @@ impl Action {
                 ref actions,
                 ref actions_else,
             } => {
-                if condition.check_event(event.clone(), ctx).await? {
-                    for action in actions {
-                        action.execute(event.clone(), &mut ctx).await?;
-                    }
+                let condition_name = format!("{}/condition", ctx.action_path.join("/"));
+                let passed = ctx
+                    .check_condition_once(condition, event.clone(), &condition_name)
+                    .await?;
+
+                let (branch, actions) = if passed {
+                    ("actions", actions)
                 } else {
-                    for action in actions_else {
-                        action.execute(event.clone(), &mut ctx).await?;
-                    }
+                    ("actions_else", actions_else)
+                };
+
+                for (i, action) in actions.iter().enumerate() {
+                    ctx.action_path.push(format!("{}/{}", branch, i));
+                    let res = action.execute(event.clone(), &mut ctx).await;
+                    ctx.action_path.pop();
+
+                    res?;
                 }
             }
-            _ => {}
         }
 
         Ok(())
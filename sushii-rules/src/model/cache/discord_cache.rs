@@ -0,0 +1,455 @@
+use dashmap::DashMap;
+use std::collections::HashMap;
+use std::sync::Arc;
+use twilight_model::channel::permission_overwrite::{PermissionOverwrite, PermissionOverwriteType};
+use twilight_model::channel::{Channel, ChannelType, GuildChannel};
+use twilight_model::gateway::event::DispatchEvent;
+use twilight_model::guild::{Member, Role};
+use twilight_model::id::{ChannelId, GuildId, RoleId, UserId};
+
+/// Owners and administrators have every permission
+const ALL_PERMISSIONS: u64 = u64::MAX;
+
+const ADMINISTRATOR: u64 = 1 << 3;
+
+#[derive(Debug, Clone)]
+pub struct CachedGuild {
+    pub id: GuildId,
+    pub name: String,
+    pub owner_id: UserId,
+}
+
+#[derive(Debug, Clone)]
+pub struct CachedChannel {
+    pub id: ChannelId,
+    pub guild_id: GuildId,
+    pub name: String,
+    pub kind: ChannelType,
+    /// Category the channel is in, categories don't have a parent
+    pub parent_id: Option<ChannelId>,
+    /// Only text channels can be NSFW
+    pub nsfw: bool,
+    pub position: i64,
+    pub permission_overwrites: Vec<PermissionOverwrite>,
+}
+
+impl CachedChannel {
+    fn from_guild_channel(
+        channel: &GuildChannel,
+        fallback_guild_id: Option<GuildId>,
+    ) -> Option<Self> {
+        let cached = match channel {
+            GuildChannel::Category(c) => Self {
+                id: c.id,
+                guild_id: c.guild_id.or(fallback_guild_id)?,
+                name: c.name.clone(),
+                kind: c.kind,
+                parent_id: None,
+                nsfw: false,
+                position: c.position,
+                permission_overwrites: c.permission_overwrites.clone(),
+            },
+            GuildChannel::Text(c) => Self {
+                id: c.id,
+                guild_id: c.guild_id.or(fallback_guild_id)?,
+                name: c.name.clone(),
+                kind: c.kind,
+                parent_id: c.parent_id,
+                nsfw: c.nsfw,
+                position: c.position,
+                permission_overwrites: c.permission_overwrites.clone(),
+            },
+            GuildChannel::Voice(c) => Self {
+                id: c.id,
+                guild_id: c.guild_id.or(fallback_guild_id)?,
+                name: c.name.clone(),
+                kind: c.kind,
+                parent_id: c.parent_id,
+                nsfw: false,
+                position: c.position,
+                permission_overwrites: c.permission_overwrites.clone(),
+            },
+            #[allow(unreachable_patterns)]
+            _ => return None,
+        };
+
+        Some(cached)
+    }
+}
+
+/// Member data merged from member events and the partial members in messages
+#[derive(Debug, Clone, Default)]
+pub struct CachedMember {
+    pub roles: Vec<RoleId>,
+    pub nick: Option<String>,
+    pub joined_at: Option<String>,
+    pub premium_since: Option<String>,
+    pub pending: bool,
+}
+
+impl From<&Member> for CachedMember {
+    fn from(member: &Member) -> Self {
+        Self {
+            roles: member.roles.clone(),
+            nick: member.nick.clone(),
+            joined_at: member.joined_at.clone(),
+            premium_since: member.premium_since.clone(),
+            pending: member.pending,
+        }
+    }
+}
+
+/// Guilds, channels, roles and members from the gateway events, so that
+/// constraints can use full guild data without making requests. Members are
+/// only cached once they send a message or a member event is received.
+#[derive(Debug, Clone, Default)]
+pub struct DiscordCache {
+    guilds: Arc<DashMap<GuildId, CachedGuild>>,
+    channels: Arc<DashMap<ChannelId, CachedChannel>>,
+    roles: Arc<DashMap<GuildId, HashMap<RoleId, Role>>>,
+    members: Arc<DashMap<(GuildId, UserId), CachedMember>>,
+}
+
+impl DiscordCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Updates the cache from a gateway event, this should be called for
+    /// every event including ones that don't trigger rules
+    pub fn update(&self, event: &DispatchEvent) {
+        match event {
+            DispatchEvent::GuildCreate(guild) => {
+                let guild = &guild.0;
+
+                self.guilds.insert(
+                    guild.id,
+                    CachedGuild {
+                        id: guild.id,
+                        name: guild.name.clone(),
+                        owner_id: guild.owner_id,
+                    },
+                );
+
+                self.roles.insert(guild.id, guild.roles.clone());
+
+                for channel in guild.channels.values() {
+                    self.insert_channel(channel, Some(guild.id));
+                }
+
+                for member in guild.members.values() {
+                    self.members
+                        .insert((guild.id, member.user.id), CachedMember::from(member));
+                }
+            }
+            DispatchEvent::GuildUpdate(guild) => {
+                let guild = &guild.0;
+
+                self.guilds.insert(
+                    guild.id,
+                    CachedGuild {
+                        id: guild.id,
+                        name: guild.name.clone(),
+                        owner_id: guild.owner_id,
+                    },
+                );
+
+                self.roles.insert(guild.id, guild.roles.clone());
+            }
+            DispatchEvent::GuildDelete(guild) => self.remove_guild(guild.id),
+            DispatchEvent::ChannelCreate(channel) => {
+                if let Channel::Guild(channel) = &channel.0 {
+                    self.insert_channel(channel, None);
+                }
+            }
+            DispatchEvent::ChannelUpdate(channel) => {
+                if let Channel::Guild(channel) = &channel.0 {
+                    self.insert_channel(channel, None);
+                }
+            }
+            DispatchEvent::ChannelDelete(channel) => {
+                if let Channel::Guild(channel) = &channel.0 {
+                    self.channels.remove(&channel.id());
+                }
+            }
+            DispatchEvent::RoleCreate(e) => {
+                self.roles
+                    .entry(e.guild_id)
+                    .or_insert_with(HashMap::new)
+                    .insert(e.role.id, e.role.clone());
+            }
+            DispatchEvent::RoleUpdate(e) => {
+                self.roles
+                    .entry(e.guild_id)
+                    .or_insert_with(HashMap::new)
+                    .insert(e.role.id, e.role.clone());
+            }
+            DispatchEvent::RoleDelete(e) => {
+                if let Some(mut roles) = self.roles.get_mut(&e.guild_id) {
+                    roles.remove(&e.role_id);
+                }
+            }
+            DispatchEvent::MemberAdd(member) => {
+                let member = &member.0;
+
+                self.members.insert(
+                    (member.guild_id, member.user.id),
+                    CachedMember::from(member),
+                );
+            }
+            DispatchEvent::MemberUpdate(update) => {
+                let mut member = self
+                    .members
+                    .entry((update.guild_id, update.user.id))
+                    .or_insert_with(CachedMember::default);
+
+                member.roles = update.roles.clone();
+                member.nick = update.nick.clone();
+                member.premium_since = update.premium_since.clone();
+                member.pending = update.pending;
+            }
+            DispatchEvent::MemberRemove(e) => {
+                self.members.remove(&(e.guild_id, e.user.id));
+            }
+            DispatchEvent::MessageCreate(msg) => {
+                let (guild_id, partial) = match (msg.guild_id, &msg.member) {
+                    (Some(guild_id), Some(partial)) => (guild_id, partial),
+                    _ => return,
+                };
+
+                let mut member = self
+                    .members
+                    .entry((guild_id, msg.author.id))
+                    .or_insert_with(CachedMember::default);
+
+                member.roles = partial.roles.clone();
+                member.nick = partial.nick.clone();
+                member.joined_at = partial.joined_at.clone();
+                member.premium_since = partial.premium_since.clone();
+            }
+            _ => {}
+        }
+    }
+
+    fn insert_channel(&self, channel: &GuildChannel, guild_id: Option<GuildId>) {
+        if let Some(channel) = CachedChannel::from_guild_channel(channel, guild_id) {
+            self.channels.insert(channel.id, channel);
+        }
+    }
+
+    fn remove_guild(&self, guild_id: GuildId) {
+        self.guilds.remove(&guild_id);
+        self.roles.remove(&guild_id);
+        self.channels.retain(|_, c| c.guild_id != guild_id);
+        self.members.retain(|(id, _), _| *id != guild_id);
+    }
+
+    pub fn guild(&self, guild_id: GuildId) -> Option<CachedGuild> {
+        self.guilds.get(&guild_id).map(|g| g.clone())
+    }
+
+    pub fn channel(&self, channel_id: ChannelId) -> Option<CachedChannel> {
+        self.channels.get(&channel_id).map(|c| c.clone())
+    }
+
+    pub fn role(&self, guild_id: GuildId, role_id: RoleId) -> Option<Role> {
+        self.roles.get(&guild_id)?.get(&role_id).cloned()
+    }
+
+    /// All roles in the guild, including @everyone
+    pub fn guild_roles(&self, guild_id: GuildId) -> Vec<Role> {
+        self.roles
+            .get(&guild_id)
+            .map(|roles| roles.values().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn member(&self, guild_id: GuildId, user_id: UserId) -> Option<CachedMember> {
+        self.members.get(&(guild_id, user_id)).map(|m| m.clone())
+    }
+
+    /// Roles of a cached member, highest position first. Roles that aren't
+    /// cached are skipped
+    pub fn member_roles(&self, guild_id: GuildId, user_id: UserId) -> Vec<Role> {
+        let member = match self.member(guild_id, user_id) {
+            Some(m) => m,
+            None => return Vec::new(),
+        };
+
+        let mut roles = self.roles(guild_id, &member.roles).unwrap_or_default();
+        roles.sort_by(|a, b| b.position.cmp(&a.position));
+
+        roles
+    }
+
+    /// Looks up roles by ID, e.g. from a partial member. None if the guild's
+    /// roles aren't cached, unknown roles are skipped
+    pub fn roles(&self, guild_id: GuildId, role_ids: &[RoleId]) -> Option<Vec<Role>> {
+        let guild_roles = self.roles.get(&guild_id)?;
+
+        Some(
+            role_ids
+                .iter()
+                .filter_map(|id| guild_roles.get(id).cloned())
+                .collect(),
+        )
+    }
+
+    /// Permissions integer of a member with the given roles, with the
+    /// channel's overwrites applied if the channel is cached. None if the
+    /// guild or its roles aren't cached.
+    pub fn member_permissions(
+        &self,
+        guild_id: GuildId,
+        user_id: UserId,
+        role_ids: &[RoleId],
+        channel_id: Option<ChannelId>,
+    ) -> Option<u64> {
+        let guild = self.guild(guild_id)?;
+
+        if guild.owner_id == user_id {
+            return Some(ALL_PERMISSIONS);
+        }
+
+        let guild_roles = self.roles.get(&guild_id)?;
+
+        // @everyone has the same ID as the guild
+        let everyone_id = RoleId(guild_id.0);
+        let mut permissions = guild_roles
+            .get(&everyone_id)
+            .map_or(0, |r| r.permissions.bits());
+
+        for role in role_ids.iter().filter_map(|id| guild_roles.get(id)) {
+            permissions |= role.permissions.bits();
+        }
+
+        if permissions & ADMINISTRATOR != 0 {
+            return Some(ALL_PERMISSIONS);
+        }
+
+        let channel = match channel_id.and_then(|id| self.channel(id)) {
+            Some(c) => c,
+            None => return Some(permissions),
+        };
+
+        // Overwrites apply in order of @everyone, all roles at once, then the
+        // member
+        let overwrites = &channel.permission_overwrites;
+
+        for overwrite in overwrites {
+            if matches!(overwrite.kind, PermissionOverwriteType::Role(id) if id == everyone_id) {
+                permissions &= !overwrite.deny.bits();
+                permissions |= overwrite.allow.bits();
+            }
+        }
+
+        let (mut allow, mut deny) = (0, 0);
+
+        for overwrite in overwrites {
+            if let PermissionOverwriteType::Role(id) = overwrite.kind {
+                if id != everyone_id && role_ids.contains(&id) {
+                    allow |= overwrite.allow.bits();
+                    deny |= overwrite.deny.bits();
+                }
+            }
+        }
+
+        permissions &= !deny;
+        permissions |= allow;
+
+        for overwrite in overwrites {
+            if matches!(overwrite.kind, PermissionOverwriteType::Member(id) if id == user_id) {
+                permissions &= !overwrite.deny.bits();
+                permissions |= overwrite.allow.bits();
+            }
+        }
+
+        Some(permissions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::de::DeserializeSeed;
+    use serde_json::json;
+    use twilight_model::gateway::event::DispatchEventWithTypeDeserializer;
+
+    fn event(kind: &str, payload: serde_json::Value) -> DispatchEvent {
+        DispatchEventWithTypeDeserializer::new(kind)
+            .deserialize(payload)
+            .unwrap()
+    }
+
+    fn role(id: &str, name: &str, position: i64) -> serde_json::Value {
+        json!({
+            "id": id,
+            "name": name,
+            "color": 0,
+            "hoist": false,
+            "managed": false,
+            "mentionable": false,
+            "permissions": "0",
+            "position": position,
+        })
+    }
+
+    #[test]
+    fn caches_roles_and_members() {
+        let cache = DiscordCache::new();
+        let guild_id = GuildId(1);
+        let user_id = UserId(5);
+
+        cache.update(&event(
+            "GUILD_ROLE_CREATE",
+            json!({ "guild_id": "1", "role": role("10", "Mods", 5) }),
+        ));
+        cache.update(&event(
+            "GUILD_ROLE_CREATE",
+            json!({ "guild_id": "1", "role": role("11", "Members", 1) }),
+        ));
+        cache.update(&event(
+            "GUILD_MEMBER_UPDATE",
+            json!({
+                "guild_id": "1",
+                "roles": ["11", "10"],
+                "nick": "nick",
+                "user": {
+                    "id": "5",
+                    "username": "user",
+                    "discriminator": "0001",
+                    "avatar": null,
+                },
+                "joined_at": "2021-01-01T00:00:00+00:00",
+                "pending": false,
+            }),
+        ));
+
+        let names: Vec<String> = cache
+            .member_roles(guild_id, user_id)
+            .into_iter()
+            .map(|r| r.name)
+            .collect();
+
+        assert_eq!(names, vec!["Mods", "Members"]);
+        assert_eq!(
+            cache.member(guild_id, user_id).unwrap().nick.as_deref(),
+            Some("nick")
+        );
+
+        cache.update(&event(
+            "GUILD_ROLE_DELETE",
+            json!({ "guild_id": "1", "role_id": "10" }),
+        ));
+
+        assert_eq!(cache.member_roles(guild_id, user_id).len(), 1);
+
+        cache.update(&event(
+            "GUILD_DELETE",
+            json!({ "id": "1", "unavailable": false }),
+        ));
+
+        assert!(cache.member(guild_id, user_id).is_none());
+        assert!(cache.guild_roles(guild_id).is_empty());
+    }
+}
@@ -0,0 +1,321 @@
+use chrono::{DateTime, Duration, Utc};
+use dashmap::DashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::VecDeque;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use twilight_model::channel::message::Message;
+use twilight_model::id::{ChannelId, GuildId, MessageId, UserId};
+
+/// How long messages are kept in a user's history, duplicate constraints with
+/// a longer duration are capped to this
+pub const MAX_HISTORY_SECS: i64 = 10 * 60;
+
+/// Max number of messages kept per user, older messages are dropped first
+pub const MAX_HISTORY_LEN: usize = 50;
+
+/// Max number of differing bits between two fuzzy hashes for messages to be
+/// considered near duplicates
+pub const NEAR_DUPLICATE_MAX_DISTANCE: u32 = 6;
+
+/// Hashes of a single message, the content itself is not stored
+#[derive(Debug, Clone)]
+pub struct MessageFingerprint {
+    pub message_id: MessageId,
+    pub channel_id: ChannelId,
+    pub timestamp: DateTime<Utc>,
+    /// Hash of the raw message content
+    pub exact_hash: u64,
+    /// Hash of the normalized content, ignoring case, punctuation and spacing
+    pub normalized_hash: u64,
+    /// Simhash of the normalized content to find nearly identical messages
+    pub fuzzy_hash: u64,
+}
+
+impl MessageFingerprint {
+    /// Returns None for messages without any text content, e.g. only
+    /// attachments
+    pub fn from_content(
+        message_id: MessageId,
+        channel_id: ChannelId,
+        timestamp: DateTime<Utc>,
+        content: &str,
+    ) -> Option<Self> {
+        let normalized = normalize(content);
+
+        if normalized.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            message_id,
+            channel_id,
+            timestamp,
+            exact_hash: hash_str(content.trim()),
+            normalized_hash: hash_str(&normalized),
+            fuzzy_hash: simhash(&normalized),
+        })
+    }
+
+    pub fn is_duplicate_of(&self, other: &Self, fuzzy: bool) -> bool {
+        if self.exact_hash == other.exact_hash {
+            return true;
+        }
+
+        if !fuzzy {
+            return false;
+        }
+
+        self.normalized_hash == other.normalized_hash
+            || (self.fuzzy_hash ^ other.fuzzy_hash).count_ones() <= NEAR_DUPLICATE_MAX_DISTANCE
+    }
+}
+
+type UserHistory = VecDeque<MessageFingerprint>;
+
+/// Short per user window of recent message hashes, used to detect repeated
+/// messages in a single channel or across multiple channels
+#[derive(Debug, Clone)]
+pub struct MessageHistory {
+    cache: Arc<DashMap<(GuildId, UserId), UserHistory>>,
+}
+
+impl MessageHistory {
+    pub fn new() -> Self {
+        Self {
+            cache: Arc::new(DashMap::new()),
+        }
+    }
+
+    /// Adds a message to the author's history. This should be called before
+    /// any rules are checked, messages that are already recorded are skipped
+    pub fn record(&self, msg: &Message) {
+        let guild_id = match msg.guild_id {
+            Some(id) => id,
+            None => return,
+        };
+
+        let timestamp = DateTime::parse_from_rfc3339(&msg.timestamp)
+            .map(Into::into)
+            .unwrap_or_else(|_| Utc::now());
+
+        let fingerprint =
+            match MessageFingerprint::from_content(msg.id, msg.channel_id, timestamp, &msg.content)
+            {
+                Some(f) => f,
+                None => return,
+            };
+
+        let mut history = self
+            .cache
+            .entry((guild_id, msg.author.id))
+            .or_insert_with(VecDeque::new);
+
+        prune_history(&mut history, Utc::now());
+
+        // Retried and redelivered events record the same message again
+        if history.iter().any(|f| f.message_id == msg.id) {
+            return;
+        }
+
+        if history.len() >= MAX_HISTORY_LEN {
+            history.pop_front();
+        }
+
+        history.push_back(fingerprint);
+    }
+
+    /// Counts messages by a user in the past `duration` that are duplicates of
+    /// the given message, including the message itself if it was recorded.
+    /// Only messages in the same channel are counted if `channel_id` is
+    /// provided.
+    pub fn count_duplicates(
+        &self,
+        guild_id: GuildId,
+        msg: &Message,
+        duration: Duration,
+        channel_id: Option<ChannelId>,
+        fuzzy: bool,
+    ) -> usize {
+        let target = match MessageFingerprint::from_content(
+            msg.id,
+            msg.channel_id,
+            Utc::now(),
+            &msg.content,
+        ) {
+            Some(f) => f,
+            None => return 0,
+        };
+
+        let history = match self.cache.get(&(guild_id, msg.author.id)) {
+            Some(h) => h,
+            None => return 0,
+        };
+
+        let duration = std::cmp::min(duration, Duration::seconds(MAX_HISTORY_SECS));
+        let since = Utc::now() - duration;
+
+        history
+            .iter()
+            .filter(|f| f.timestamp >= since)
+            .filter(|f| channel_id.map_or(true, |id| f.channel_id == id))
+            .filter(|f| f.is_duplicate_of(&target, fuzzy))
+            .count()
+    }
+
+    /// Removes expired messages and users without any recent messages
+    pub fn prune(&self) {
+        let now = Utc::now();
+
+        self.cache.retain(|_, history| {
+            prune_history(history, now);
+
+            !history.is_empty()
+        });
+    }
+}
+
+fn prune_history(history: &mut UserHistory, now: DateTime<Utc>) {
+    let since = now - Duration::seconds(MAX_HISTORY_SECS);
+
+    while history.front().map_or(false, |f| f.timestamp < since) {
+        history.pop_front();
+    }
+}
+
+fn hash_str(s: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    s.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Lowercases and strips everything except letters and numbers, so that
+/// messages only differing in case, punctuation or spacing are the same
+fn normalize(s: &str) -> String {
+    s.chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// 64 bit simhash of character trigrams. Similar strings result in hashes
+/// with only a few differing bits
+fn simhash(s: &str) -> u64 {
+    let chars: Vec<char> = s.chars().collect();
+
+    if chars.len() < 3 {
+        return hash_str(s);
+    }
+
+    let mut weights = [0i32; 64];
+
+    for shingle in chars.windows(3) {
+        let mut hasher = DefaultHasher::new();
+        shingle.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        for (i, weight) in weights.iter_mut().enumerate() {
+            if hash & (1 << i) != 0 {
+                *weight += 1;
+            } else {
+                *weight -= 1;
+            }
+        }
+    }
+
+    weights
+        .iter()
+        .enumerate()
+        .filter(|(_, w)| **w > 0)
+        .fold(0, |acc, (i, _)| acc | (1 << i))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fingerprint(content: &str) -> MessageFingerprint {
+        MessageFingerprint::from_content(MessageId(1), ChannelId(1), Utc::now(), content).unwrap()
+    }
+
+    fn message(id: u64, content: &str) -> Message {
+        serde_json::from_value(serde_json::json!({
+            "id": id.to_string(),
+            "channel_id": "1",
+            "guild_id": "1",
+            "author": {
+                "id": "1",
+                "username": "test",
+                "discriminator": "0001",
+                "avatar": null,
+            },
+            "content": content,
+            "timestamp": Utc::now().to_rfc3339(),
+            "edited_timestamp": null,
+            "tts": false,
+            "mention_everyone": false,
+            "mentions": [],
+            "mention_roles": [],
+            "attachments": [],
+            "embeds": [],
+            "pinned": false,
+            "type": 0,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn record_skips_recorded_messages() {
+        let history = MessageHistory::new();
+        let first = message(1, "free nitro");
+
+        history.record(&first);
+        history.record(&first);
+        history.record(&message(2, "free nitro"));
+
+        let count = history.count_duplicates(GuildId(1), &first, Duration::minutes(1), None, false);
+
+        assert_eq!(count, 2);
+    }
+
+    #[test]
+    fn exact_duplicates() {
+        let a = fingerprint("free nitro at https://example.com");
+        let b = fingerprint("free nitro at https://example.com");
+
+        assert!(a.is_duplicate_of(&b, false));
+    }
+
+    #[test]
+    fn normalized_duplicates() {
+        let a = fingerprint("Free Nitro!! at example.com");
+        let b = fingerprint("free nitro at example com");
+
+        assert!(!a.is_duplicate_of(&b, false));
+        assert!(a.is_duplicate_of(&b, true));
+    }
+
+    #[test]
+    fn near_duplicates() {
+        let a = fingerprint("get your free discord nitro here at example.com/gift/abcdef");
+        let b = fingerprint("get your free discord nitro here at example.com/gift/abcdeg");
+
+        assert!(a.is_duplicate_of(&b, true));
+    }
+
+    #[test]
+    fn different_messages() {
+        let a = fingerprint("hello how is everyone doing today");
+        let b = fingerprint("does anyone know when the next comeback is");
+
+        assert!(!a.is_duplicate_of(&b, true));
+    }
+
+    #[test]
+    fn empty_content() {
+        let fingerprint =
+            MessageFingerprint::from_content(MessageId(1), ChannelId(1), Utc::now(), "  ...  ");
+
+        assert!(fingerprint.is_none());
+    }
+}
@@ -1,6 +1,11 @@
+pub mod discord_cache;
 pub mod guild_config_cache;
+pub mod message_history;
 pub mod rule_sets;
 
-pub use self::{guild_config_cache::GuildConfigCache, rule_sets::RuleSetsCache};
+pub use self::{
+    discord_cache::DiscordCache, guild_config_cache::GuildConfigCache,
+    message_history::MessageHistory, rule_sets::RuleSetsCache,
+};
 
 pub struct RuleContextCache {}
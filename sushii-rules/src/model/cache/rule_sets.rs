@@ -36,12 +36,12 @@ impl RuleSetsCache {
     /// Fetches all of a guild's rule sets from cache or from persistent store
     /// if not cached
     #[tracing::instrument]
-    fn get_guild_rule_sets(&self, guild_id: GuildId) -> Result<GuildRuleSet> {
+    async fn get_guild_rule_sets(&self, guild_id: GuildId) -> Result<GuildRuleSet> {
         // If cached, return all
         if let Some(rule_sets) = self.guild_rule_sets.get(&guild_id) {
             Ok(rule_sets.clone())
         } else {
-            let guild_rule_sets = self.rules_store.get_guild_rule_sets(guild_id.0)?;
+            let guild_rule_sets = self.rules_store.get_guild_rule_sets(guild_id.0).await?;
 
             let mut sets = Vec::new();
 
@@ -67,7 +67,7 @@ impl RuleSetsCache {
 
     /// Fetches the matching rules corresponding to a given event or trigger.
     /// These rules can come from any enabled rule set
-    pub fn get_matching_rules(&self, event: &Arc<Event>) -> Result<Vec<Arc<Rule>>> {
+    pub async fn get_matching_rules(&self, event: &Arc<Event>) -> Result<Vec<Arc<Rule>>> {
         let guild_id = match event.guild_id() {
             Ok(id) => id,
             Err(_) => {
@@ -75,7 +75,7 @@ impl RuleSetsCache {
             }
         };
 
-        let guild_rule_sets = self.get_guild_rule_sets(guild_id)?;
+        let guild_rule_sets = self.get_guild_rule_sets(guild_id).await?;
 
         let mut matching_rules = Vec::new();
 
@@ -1,13 +1,20 @@
+use chrono_tz::Tz;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use serde_json::{json, Value};
 use sqlx::types::Json;
 use sqlx::types::Uuid;
 use std::borrow::Cow;
 use std::collections::HashMap;
+use std::convert::TryInto;
+use std::result::Result as StdResult;
+use twilight_model::id::{ChannelId, RoleId};
 
 use crate::error::{Error, Result};
-use crate::model::RuleContext;
+use crate::model::{
+    validation::{ValidationError, ValidationErrorKind},
+    RuleContext,
+};
 
 pub type RuleConfig = HashMap<String, serde_json::Value>;
 
@@ -51,12 +58,381 @@ pub enum StringVecVar {
     ConfigKey(String),
 }
 
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum IntegerVar {
+    /// # Value
+    /// Value to match directly
+    Value(u64),
+    /// # Configuration Key
+    /// Key to fetch from the rule configuration
+    ConfigKey(String),
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum BoolVar {
+    /// # Value
+    /// Value to match directly
+    Value(bool),
+    /// # Configuration Key
+    /// Key to fetch from the rule configuration
+    ConfigKey(String),
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum DurationVar {
+    /// # Value
+    /// Duration in seconds
+    Value(u64),
+    /// # Configuration Key
+    /// Key to fetch from the rule configuration, either a number of seconds or
+    /// a duration string such as "10m" or "1h 30m"
+    ConfigKey(String),
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ChannelIdVar {
+    /// # Value
+    /// Channel ID
+    Value(u64),
+    /// # Configuration Key
+    /// Key to fetch from the rule configuration
+    ConfigKey(String),
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum RoleIdVar {
+    /// # Value
+    /// Role ID
+    Value(u64),
+    /// # Configuration Key
+    /// Key to fetch from the rule configuration
+    ConfigKey(String),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum TimezoneVar {
+    /// # Value
+    /// IANA timezone name, e.g. "America/Los_Angeles"
+    Value(String),
+    /// # Configuration Key
+    /// Key to fetch from the rule configuration
+    ConfigKey(String),
+}
+
+/// Integer, bool, duration and ID vars used to be plain values before they
+/// could be read from the config, so bare values are still accepted
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum VarRepr<T> {
+    Tagged(TaggedVar<T>),
+    Bare(T),
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum TaggedVar<T> {
+    Value(T),
+    ConfigKey(String),
+}
+
+macro_rules! impl_var_deserialize {
+    ($var:ident, $ty:ty) => {
+        impl<'de> Deserialize<'de> for $var {
+            fn deserialize<D>(deserializer: D) -> StdResult<Self, D::Error>
+            where
+                D: serde::Deserializer<'de>,
+            {
+                Ok(match VarRepr::<$ty>::deserialize(deserializer)? {
+                    VarRepr::Tagged(TaggedVar::Value(v)) | VarRepr::Bare(v) => Self::Value(v),
+                    VarRepr::Tagged(TaggedVar::ConfigKey(key)) => Self::ConfigKey(key),
+                })
+            }
+        }
+    };
+}
+
+impl_var_deserialize!(IntegerVar, u64);
+impl_var_deserialize!(BoolVar, bool);
+impl_var_deserialize!(DurationVar, u64);
+impl_var_deserialize!(ChannelIdVar, u64);
+impl_var_deserialize!(RoleIdVar, u64);
+
+fn config_value<'a>(ctx: &'a RuleContext<'_>, key: &str) -> Result<&'a Value> {
+    ctx.data
+        .rule_config
+        .get(key)
+        .ok_or_else(|| Error::RuleConfigMissingField(key.to_string().into()))
+}
+
+fn mismatched_type(key: &str, expected: &'static str) -> Error {
+    Error::RuleConfigMismatchedType(key.to_string().into(), expected.into())
+}
+
+/// IDs can be stored as either numbers or strings, since snowflakes don't fit
+/// in a JavaScript number
+pub fn value_as_id(value: &Value) -> Option<u64> {
+    match value {
+        Value::Number(n) => n.as_u64(),
+        Value::String(s) => s.parse().ok(),
+        _ => None,
+    }
+}
+
+/// Durations can be stored as either a number of seconds or a human readable
+/// duration string
+pub fn value_as_duration(value: &Value) -> Option<std::time::Duration> {
+    match value {
+        Value::Number(n) => n.as_u64().map(std::time::Duration::from_secs),
+        Value::String(s) => humantime::parse_duration(s).ok(),
+        _ => None,
+    }
+}
+
+/// Timezones are stored as IANA names
+pub fn value_as_timezone(value: &Value) -> Option<Tz> {
+    value.as_str().and_then(|s| s.parse().ok())
+}
+
+/// Type of a declared rule set config parameter, matching the config var types
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub enum ConfigParamType {
+    String,
+    StringVec,
+    Integer,
+    Bool,
+    Duration,
+    ChannelId,
+    RoleId,
+    Timezone,
+}
+
+impl ConfigParamType {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::String => "String",
+            Self::StringVec => "Vec<String>",
+            Self::Integer => "Integer",
+            Self::Bool => "Bool",
+            Self::Duration => "Duration",
+            Self::ChannelId => "ChannelId",
+            Self::RoleId => "RoleId",
+            Self::Timezone => "Timezone",
+        }
+    }
+
+    pub fn is_type(self, value: &Value) -> bool {
+        match self {
+            Self::String => value.is_string(),
+            Self::StringVec => value
+                .as_array()
+                .map_or(false, |vec| vec.iter().all(Value::is_string)),
+            Self::Integer => value.is_u64(),
+            Self::Bool => value.is_boolean(),
+            Self::Duration => value_as_duration(value).is_some(),
+            Self::ChannelId | Self::RoleId => value_as_id(value).is_some(),
+            Self::Timezone => value_as_timezone(value).is_some(),
+        }
+    }
+
+    /// Size of a value compared against the parameter bounds: the number for
+    /// integers, seconds for durations, length for strings and lists
+    fn size(self, value: &Value) -> Option<u64> {
+        match self {
+            Self::String => value.as_str().map(|s| s.chars().count() as u64),
+            Self::StringVec => value.as_array().map(|vec| vec.len() as u64),
+            Self::Integer => value.as_u64(),
+            Self::Duration => value_as_duration(value).map(|d| d.as_secs()),
+            Self::Bool | Self::ChannelId | Self::RoleId | Self::Timezone => None,
+        }
+    }
+}
+
+/// A config parameter a rule set expects. Parameters without a default are
+/// required to be set in each guild's rule set config.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ConfigParam {
+    /// # Name
+    /// Config key used by rules with `config_key`
+    pub name: String,
+    /// # Type
+    #[serde(rename = "type")]
+    pub kind: ConfigParamType,
+    /// # Description
+    #[serde(default)]
+    pub description: Option<String>,
+    /// # Default
+    /// Value used when a guild doesn't set this parameter
+    #[serde(default)]
+    pub default: Option<Value>,
+    /// # Minimum
+    /// Minimum number, duration in seconds, or length of text and lists
+    #[serde(default)]
+    pub min: Option<u64>,
+    /// # Maximum
+    /// Maximum number, duration in seconds, or length of text and lists
+    #[serde(default)]
+    pub max: Option<u64>,
+}
+
+impl ConfigParam {
+    /// Returns the problem with a value, if any
+    fn check(&self, value: &Value) -> Option<ValidationErrorKind> {
+        if !self.kind.is_type(value) {
+            return Some(ValidationErrorKind::MismatchedConfigType(
+                self.name.clone(),
+                self.kind.name(),
+            ));
+        }
+
+        let size = self.kind.size(value)?;
+
+        let in_bounds =
+            self.min.map_or(true, |min| size >= min) && self.max.map_or(true, |max| size <= max);
+
+        if in_bounds {
+            None
+        } else {
+            Some(ValidationErrorKind::ConfigOutOfBounds(
+                self.name.clone(),
+                self.min,
+                self.max,
+            ))
+        }
+    }
+
+    /// JSON schema of this parameter for rendering config forms
+    pub fn json_schema(&self) -> Value {
+        let mut schema = match self.kind {
+            ConfigParamType::String => json!({ "type": "string" }),
+            ConfigParamType::StringVec => json!({
+                "type": "array",
+                "items": { "type": "string" },
+            }),
+            ConfigParamType::Integer => json!({ "type": "integer", "minimum": 0 }),
+            ConfigParamType::Bool => json!({ "type": "boolean" }),
+            ConfigParamType::Duration => json!({ "type": ["integer", "string"] }),
+            ConfigParamType::Timezone => json!({ "type": "string" }),
+            ConfigParamType::ChannelId | ConfigParamType::RoleId => json!({
+                "type": ["integer", "string"],
+                "pattern": "^[0-9]+$",
+            }),
+        };
+
+        let (min_key, max_key) = match self.kind {
+            ConfigParamType::String => ("minLength", "maxLength"),
+            ConfigParamType::StringVec => ("minItems", "maxItems"),
+            _ => ("minimum", "maximum"),
+        };
+
+        // Duration strings can't be bounded in JSON schema, only checked
+        // when resolving
+        if self.kind != ConfigParamType::Duration {
+            if let Some(min) = self.min {
+                schema[min_key] = min.into();
+            }
+
+            if let Some(max) = self.max {
+                schema[max_key] = max.into();
+            }
+        }
+
+        schema["title"] = self.name.clone().into();
+
+        if let Some(description) = &self.description {
+            schema["description"] = description.clone().into();
+        }
+
+        if let Some(default) = &self.default {
+            schema["default"] = default.clone();
+        }
+
+        schema
+    }
+}
+
+/// Checks a guild's rule set config against the declared parameters and
+/// fills in defaults. Keys that aren't declared are kept as is. Returns every
+/// invalid or missing parameter.
+pub fn resolve_config(
+    params: &[ConfigParam],
+    config: &RuleConfig,
+) -> StdResult<RuleConfig, Vec<ValidationError>> {
+    let mut resolved = config.clone();
+    let mut errors = Vec::new();
+
+    for param in params {
+        let path = format!("/config/{}", param.name);
+
+        let value = match config.get(&param.name).or_else(|| param.default.as_ref()) {
+            Some(v) => v,
+            None => {
+                errors.push(ValidationError {
+                    path,
+                    kind: ValidationErrorKind::MissingConfigKey(param.name.clone()),
+                });
+
+                continue;
+            }
+        };
+
+        if let Some(kind) = param.check(value) {
+            errors.push(ValidationError { path, kind });
+            continue;
+        }
+
+        resolved.insert(param.name.clone(), value.clone());
+    }
+
+    if errors.is_empty() {
+        Ok(resolved)
+    } else {
+        Err(errors)
+    }
+}
+
+/// JSON schema of a rule set's declared config, for the dashboard to render a
+/// form for each guild's config
+pub fn config_json_schema(params: &[ConfigParam]) -> Value {
+    let properties: serde_json::Map<String, Value> = params
+        .iter()
+        .map(|p| (p.name.clone(), p.json_schema()))
+        .collect();
+
+    let required: Vec<&str> = params
+        .iter()
+        .filter(|p| p.default.is_none())
+        .map(|p| p.name.as_str())
+        .collect();
+
+    json!({
+        "$schema": "http://json-schema.org/draft-07/schema#",
+        "type": "object",
+        "properties": properties,
+        "required": required,
+    })
+}
+
 pub trait ConfigGet<'a> {
     type Output;
 
     fn get(&'a self, ctx: &'a RuleContext<'_>) -> Result<Self::Output>;
 }
 
+/// Optional vars such as durations, None if the var isn't set at all
+impl<'a, T: ConfigGet<'a>> ConfigGet<'a> for Option<T> {
+    type Output = Option<T::Output>;
+
+    fn get(&'a self, ctx: &'a RuleContext<'_>) -> Result<Self::Output> {
+        self.as_ref().map(|var| var.get(ctx)).transpose()
+    }
+}
+
 impl<'a> ConfigGet<'a> for StringVar {
     type Output = Cow<'a, str>;
 
@@ -107,3 +483,187 @@ impl<'a> ConfigGet<'a> for StringVecVar {
         }
     }
 }
+
+impl<'a> ConfigGet<'a> for IntegerVar {
+    type Output = u64;
+
+    fn get(&'a self, ctx: &'a RuleContext<'_>) -> Result<Self::Output> {
+        match self {
+            Self::ConfigKey(key) => config_value(ctx, key)?
+                .as_u64()
+                .ok_or_else(|| mismatched_type(key, "Integer")),
+            Self::Value(val) => Ok(*val),
+        }
+    }
+}
+
+impl<'a> ConfigGet<'a> for BoolVar {
+    type Output = bool;
+
+    fn get(&'a self, ctx: &'a RuleContext<'_>) -> Result<Self::Output> {
+        match self {
+            Self::ConfigKey(key) => config_value(ctx, key)?
+                .as_bool()
+                .ok_or_else(|| mismatched_type(key, "Bool")),
+            Self::Value(val) => Ok(*val),
+        }
+    }
+}
+
+impl<'a> ConfigGet<'a> for DurationVar {
+    type Output = chrono::Duration;
+
+    fn get(&'a self, ctx: &'a RuleContext<'_>) -> Result<Self::Output> {
+        let secs = match self {
+            Self::ConfigKey(key) => value_as_duration(config_value(ctx, key)?)
+                .ok_or_else(|| mismatched_type(key, "Duration"))?
+                .as_secs(),
+            Self::Value(val) => *val,
+        };
+
+        Ok(chrono::Duration::seconds(secs.try_into()?))
+    }
+}
+
+impl<'a> ConfigGet<'a> for ChannelIdVar {
+    type Output = ChannelId;
+
+    fn get(&'a self, ctx: &'a RuleContext<'_>) -> Result<Self::Output> {
+        match self {
+            Self::ConfigKey(key) => value_as_id(config_value(ctx, key)?)
+                .map(ChannelId)
+                .ok_or_else(|| mismatched_type(key, "ChannelId")),
+            Self::Value(val) => Ok(ChannelId(*val)),
+        }
+    }
+}
+
+impl<'a> ConfigGet<'a> for RoleIdVar {
+    type Output = RoleId;
+
+    fn get(&'a self, ctx: &'a RuleContext<'_>) -> Result<Self::Output> {
+        match self {
+            Self::ConfigKey(key) => value_as_id(config_value(ctx, key)?)
+                .map(RoleId)
+                .ok_or_else(|| mismatched_type(key, "RoleId")),
+            Self::Value(val) => Ok(RoleId(*val)),
+        }
+    }
+}
+
+impl<'a> ConfigGet<'a> for TimezoneVar {
+    type Output = Tz;
+
+    fn get(&'a self, ctx: &'a RuleContext<'_>) -> Result<Self::Output> {
+        match self {
+            Self::ConfigKey(key) => value_as_timezone(config_value(ctx, key)?)
+                .ok_or_else(|| mismatched_type(key, "Timezone")),
+            Self::Value(name) => name
+                .parse()
+                .map_err(|_| Error::InvalidTimezone(name.clone())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deserializes_bare_var_values() {
+        let bare: IntegerVar = serde_json::from_value(json!(5)).unwrap();
+        assert!(matches!(bare, IntegerVar::Value(5)));
+
+        let tagged: BoolVar = serde_json::from_value(json!({ "value": true })).unwrap();
+        assert!(matches!(tagged, BoolVar::Value(true)));
+
+        let key: DurationVar =
+            serde_json::from_value(json!({ "config_key": "mute_duration" })).unwrap();
+        assert!(matches!(key, DurationVar::ConfigKey(k) if k == "mute_duration"));
+
+        assert!(serde_json::from_value::<RoleIdVar>(json!("123")).is_err());
+    }
+
+    fn param(name: &str, kind: ConfigParamType, default: Option<Value>) -> ConfigParam {
+        ConfigParam {
+            name: name.into(),
+            kind,
+            description: None,
+            default,
+            min: None,
+            max: Some(10),
+        }
+    }
+
+    #[test]
+    fn fills_defaults() {
+        let params = vec![
+            param("max_mentions", ConfigParamType::Integer, Some(json!(5))),
+            param(
+                "mute_duration",
+                ConfigParamType::Duration,
+                Some(json!("10m")),
+            ),
+        ];
+
+        let mut config = RuleConfig::new();
+        config.insert("max_mentions".into(), json!(3));
+
+        let resolved = resolve_config(&params, &config).unwrap();
+
+        assert_eq!(resolved["max_mentions"], json!(3));
+        assert_eq!(resolved["mute_duration"], json!("10m"));
+    }
+
+    #[test]
+    fn reports_invalid_params() {
+        let params = vec![
+            param("max_mentions", ConfigParamType::Integer, None),
+            param("log_channel", ConfigParamType::ChannelId, None),
+            param("words", ConfigParamType::StringVec, None),
+        ];
+
+        let mut config = RuleConfig::new();
+        config.insert("max_mentions".into(), json!(20));
+        config.insert("words".into(), json!("not a list"));
+
+        let errors = resolve_config(&params, &config).unwrap_err();
+
+        assert_eq!(
+            errors,
+            vec![
+                ValidationError {
+                    path: "/config/max_mentions".into(),
+                    kind: ValidationErrorKind::ConfigOutOfBounds(
+                        "max_mentions".into(),
+                        None,
+                        Some(10)
+                    ),
+                },
+                ValidationError {
+                    path: "/config/log_channel".into(),
+                    kind: ValidationErrorKind::MissingConfigKey("log_channel".into()),
+                },
+                ValidationError {
+                    path: "/config/words".into(),
+                    kind: ValidationErrorKind::MismatchedConfigType("words".into(), "Vec<String>"),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn config_schema_requires_params_without_defaults() {
+        let params = vec![
+            param("max_mentions", ConfigParamType::Integer, Some(json!(5))),
+            param("log_channel", ConfigParamType::ChannelId, None),
+        ];
+
+        let schema = config_json_schema(&params);
+
+        assert_eq!(schema["type"], "object");
+        assert_eq!(schema["required"], json!(["log_channel"]));
+        assert_eq!(schema["properties"]["max_mentions"]["maximum"], 10);
+        assert_eq!(schema["properties"]["log_channel"]["title"], "log_channel");
+    }
+}
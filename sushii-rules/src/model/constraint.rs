@@ -1,28 +1,34 @@
-use chrono::{DateTime, Duration, Utc};
+use chrono::{DateTime, Datelike, NaiveDateTime, Timelike, Utc};
+use chrono_tz::Tz;
 use futures_util::FutureExt;
 use lingua::Language;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::convert::TryInto;
 use std::future::Future;
 use std::pin::Pin;
 use std::sync::Arc;
+use twilight_model::channel::message::Message;
 use twilight_model::gateway::event::DispatchEvent;
+use twilight_model::id::ChannelId;
 use twilight_model::user::User;
 use twilight_model::user::UserFlags;
 
-use sushii_model::model::sql::{RuleGauge, RuleScope};
+use sushii_model::model::sql::RuleScope;
 
 use crate::error::{Error, Result};
-use crate::model::has_id::*;
 use crate::model::{
-    config::{ConfigGet, StringVar, StringVecVar},
+    config::{
+        BoolVar, ConfigGet, DurationVar, IntegerVar, RoleIdVar, StringVar, StringVecVar,
+        TimezoneVar,
+    },
     Event, RuleContext,
 };
+use crate::persistence::counter::CounterKey;
 
 #[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize, JsonSchema)]
 #[serde(rename_all(serialize = "UPPERCASE", deserialize = "UPPERCASE"))]
 #[serde(remote = "Language")]
+#[schemars(rename = "Language")]
 pub enum LanguageType {
     Afrikaans,
     Albanian,
@@ -103,7 +109,8 @@ pub enum LanguageType {
 
 // This is needed so that we can use the remote Language struct
 #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, PartialEq)]
-pub struct LanguageWrapper(#[serde(with = "LanguageType")] Language);
+#[serde(transparent)]
+pub struct LanguageWrapper(#[serde(with = "LanguageType")] pub Language);
 
 #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "snake_case")]
@@ -262,29 +269,25 @@ impl StringConstraint {
                 in_str == in_str.to_lowercase()
             }
             Self::IsLanguage(lang) => {
-                ctx.language_client
-                    .detect_language(in_str)
+                ctx.detect_language(in_str)
                     .await?
                     .map(|detected_lang| detected_lang == *lang)
                     .unwrap_or(false)
             }
             Self::IsNotLanguage(lang) => {
-                ctx.language_client
-                    .detect_language(in_str)
+                ctx.detect_language(in_str)
                     .await?
                     .map(|detected_lang| detected_lang != *lang)
                     .unwrap_or(false)
             }
             Self::IsInLanguage(langs) => {
-                ctx.language_client
-                    .detect_language(in_str)
+                ctx.detect_language(in_str)
                     .await?
                     .map(|detected_lang| langs.contains(&LanguageWrapper(detected_lang)))
                     .unwrap_or(false)
             }
             Self::IsNotInLanguage(langs) => {
-                ctx.language_client
-                    .detect_language(in_str)
+                ctx.detect_language(in_str)
                     .await?
                     .map(|detected_lang| !langs.contains(&LanguageWrapper(detected_lang)))
                     .unwrap_or(false)
@@ -300,34 +303,44 @@ impl StringConstraint {
 pub enum IntegerConstraint {
     /// # Equals
     /// Equals given number
-    Equals(u64),
+    Equals(IntegerVar),
     /// # Not equals
     /// Does not equal given number
-    NotEquals(u64),
+    NotEquals(IntegerVar),
     /// # Greater than
     /// Is greater than given number
-    GreaterThan(u64),
+    GreaterThan(IntegerVar),
     /// # Less than
     /// Is less than given number
-    LessThan(u64),
+    LessThan(IntegerVar),
     /// # Inclusive between
     /// Is between given range, including the range boundaries
-    InclusiveBetween { lower: u64, upper: u64 },
+    InclusiveBetween {
+        lower: IntegerVar,
+        upper: IntegerVar,
+    },
     /// # Exclusive between
     /// Is between given range, excluding the range boundaries
-    ExclusiveBetween { lower: u64, upper: u64 },
+    ExclusiveBetween {
+        lower: IntegerVar,
+        upper: IntegerVar,
+    },
 }
 
 impl IntegerConstraint {
     #[rustfmt::skip]
-    pub async fn check_integer(&self, _ctx: &RuleContext<'_>, input: u64) -> Result<bool> {
-        let res = match *self {
-            Self::Equals(target) => input == target,
-            Self::NotEquals(target) => input != target,
-            Self::GreaterThan(target) => input > target,
-            Self::LessThan(target) => input < target,
-            Self::InclusiveBetween { lower, upper } => lower <= input && input <= upper,
-            Self::ExclusiveBetween { lower, upper } => lower < input && input < upper,
+    pub async fn check_integer(&self, ctx: &RuleContext<'_>, input: u64) -> Result<bool> {
+        let res = match self {
+            Self::Equals(target) => input == target.get(ctx)?,
+            Self::NotEquals(target) => input != target.get(ctx)?,
+            Self::GreaterThan(target) => input > target.get(ctx)?,
+            Self::LessThan(target) => input < target.get(ctx)?,
+            Self::InclusiveBetween { lower, upper } => {
+                lower.get(ctx)? <= input && input <= upper.get(ctx)?
+            }
+            Self::ExclusiveBetween { lower, upper } => {
+                lower.get(ctx)? < input && input < upper.get(ctx)?
+            }
         };
 
         Ok(res)
@@ -345,22 +358,124 @@ pub enum IntegerListConstraint {
     DoesNotInclude(u64),
 }
 
+impl IntegerListConstraint {
+    pub fn check_list(&self, list: &[u64]) -> bool {
+        match self {
+            Self::Includes(n) => list.contains(n),
+            Self::DoesNotInclude(n) => !list.contains(n),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum IdListConstraint {
+    /// # In list
+    /// ID is one of the given IDs
+    InList(Vec<u64>),
+    /// # Not in list
+    /// ID is not any of the given IDs
+    NotInList(Vec<u64>),
+}
+
+impl IdListConstraint {
+    /// Missing IDs are never in the list, e.g. channels without a category
+    pub fn check_id(&self, id: Option<u64>) -> bool {
+        let in_list = |list: &Vec<u64>| id.map_or(false, |id| list.contains(&id));
+
+        match self {
+            Self::InList(list) => in_list(list),
+            Self::NotInList(list) => !in_list(list),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize, JsonSchema)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Permission {
+    CreateInvite,
+    KickMembers,
+    BanMembers,
+    Administrator,
+    ManageChannels,
+    ManageGuild,
+    AddReactions,
+    ViewAuditLog,
+    PrioritySpeaker,
+    Stream,
+    ViewChannel,
+    SendMessages,
+    SendTtsMessages,
+    ManageMessages,
+    EmbedLinks,
+    AttachFiles,
+    ReadMessageHistory,
+    MentionEveryone,
+    UseExternalEmojis,
+    ViewGuildInsights,
+    Connect,
+    Speak,
+    MuteMembers,
+    DeafenMembers,
+    MoveMembers,
+    UseVad,
+    ChangeNickname,
+    ManageNicknames,
+    ManageRoles,
+    ManageWebhooks,
+    ManageEmojis,
+}
+
+impl Permission {
+    /// Bit of this permission in Discord's permission integer, in the same
+    /// order as the variants
+    pub fn bits(self) -> u64 {
+        1 << self as u64
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum PermissionsConstraint {
+    /// # Has all
+    /// Has every one of the given permissions
+    HasAll(Vec<Permission>),
+    /// # Has any
+    /// Has at least one of the given permissions
+    HasAny(Vec<Permission>),
+    /// # Has none
+    /// Has none of the given permissions
+    HasNone(Vec<Permission>),
+}
+
+impl PermissionsConstraint {
+    pub fn check_permissions(&self, permissions: u64) -> bool {
+        let has = |p: &Permission| permissions & p.bits() != 0;
+
+        match self {
+            Self::HasAll(list) => list.iter().all(has),
+            Self::HasAny(list) => list.iter().any(has),
+            Self::HasNone(list) => !list.iter().any(has),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum BoolConstraint {
     /// # Equals
     /// Equals given value
-    Equals(bool),
+    Equals(BoolVar),
     /// # Not equals
     /// Does not equal given value
-    NotEquals(bool),
+    NotEquals(BoolVar),
 }
 
 impl BoolConstraint {
-    pub async fn check_bool(&self, _ctx: &RuleContext<'_>, input: bool) -> Result<bool> {
-        let res = match *self {
-            Self::Equals(target) => input == target,
-            Self::NotEquals(target) => input != target,
+    pub async fn check_bool(&self, ctx: &RuleContext<'_>, input: bool) -> Result<bool> {
+        let res = match self {
+            Self::Equals(target) => input == target.get(ctx)?,
+            Self::NotEquals(target) => input != target.get(ctx)?,
         };
 
         Ok(res)
@@ -378,20 +493,15 @@ pub enum DateConstraint {
     NotEquals(DateTime<Utc>),
     /// # Older than a duration
     /// Is older than a given duration in seconds
-    OlderThan(u64),
+    OlderThan(DurationVar),
 }
 
 impl DateConstraint {
-    pub async fn check_date(&self, _ctx: &RuleContext<'_>, input: DateTime<Utc>) -> Result<bool> {
-        let res = match *self {
-            Self::Equals(target) => input == target,
-            Self::NotEquals(target) => input != target,
-            Self::OlderThan(secs) => {
-                let d = Duration::seconds(secs.try_into()?);
-                let now = Utc::now();
-
-                input < now - d
-            }
+    pub async fn check_date(&self, ctx: &RuleContext<'_>, input: DateTime<Utc>) -> Result<bool> {
+        let res = match self {
+            Self::Equals(target) => input == *target,
+            Self::NotEquals(target) => input != *target,
+            Self::OlderThan(duration) => input < Utc::now() - duration.get(ctx)?,
         };
 
         Ok(res)
@@ -470,6 +580,28 @@ pub enum MemberConstraint {
     /// # Boosting date
     /// When the member boosted the server
     PremiumSince(DateConstraint),
+    /// # Permissions
+    /// Member's permissions in the channel, from their roles and the
+    /// channel's permission overwrites. Server owners and administrators have
+    /// every permission. If the channel isn't cached, only the server wide
+    /// permissions of the roles in the message's member are checked.
+    Permissions(PermissionsConstraint),
+    /// # Highest role position
+    /// Position of the member's highest role, 0 if they don't have any roles
+    HighestRolePosition(IntegerConstraint),
+    /// # Role name
+    /// Name of any of the member's roles
+    RoleName(StringConstraint),
+}
+
+/// Result of a constraint that needs data that isn't cached yet, e.g. a guild
+/// that hasn't been received since startup. These don't match instead of
+/// failing the event, since retrying wouldn't cache it.
+fn not_cached(data: &'static str) -> bool {
+    metrics::increment_counter!("constraint_not_cached", "data" => data);
+    tracing::warn!("Cached {} not found, constraint doesn't match", data);
+
+    false
 }
 
 impl MemberConstraint {
@@ -503,8 +635,16 @@ impl MemberConstraint {
                     false
                 }
             }
-            // pending not in partial member
-            // MemberConstraint::Pending(b) => b.check_bool(ctx, member.pending).await?,
+            // Pending isn't in partial members, only in member events
+            MemberConstraint::Pending(b) => {
+                let guild_id = msg.guild_id.ok_or(Error::MissingGuildId)?;
+                let pending = ctx
+                    .discord_cache
+                    .member(guild_id, msg.author.id)
+                    .map_or(false, |m| m.pending);
+
+                b.check_bool(ctx, pending).await?
+            }
             MemberConstraint::PremiumSince(b) => {
                 if let Some(premium_since) = &member.premium_since {
                     b.check_date(ctx, DateTime::parse_from_rfc3339(premium_since)?.into())
@@ -513,10 +653,56 @@ impl MemberConstraint {
                     false
                 }
             }
-            _ => {
-                tracing::warn!("Unhandled member constraint check");
+            MemberConstraint::Roles(c) => {
+                let role_ids: Vec<u64> = member.roles.iter().map(|id| id.0).collect();
 
-                false
+                c.check_list(&role_ids)
+            }
+            MemberConstraint::Permissions(c) => {
+                let guild_id = msg.guild_id.ok_or(Error::MissingGuildId)?;
+                // Uses the message's member roles, so only the guild and its
+                // roles need to be cached. Channel overwrites are skipped if
+                // the channel isn't cached.
+                let permissions = ctx.discord_cache.member_permissions(
+                    guild_id,
+                    msg.author.id,
+                    &member.roles,
+                    Some(msg.channel_id),
+                );
+
+                match permissions {
+                    Some(permissions) => c.check_permissions(permissions),
+                    None => not_cached("guild roles"),
+                }
+            }
+            MemberConstraint::HighestRolePosition(c) => {
+                let guild_id = msg.guild_id.ok_or(Error::MissingGuildId)?;
+                match ctx.discord_cache.roles(guild_id, &member.roles) {
+                    Some(roles) => {
+                        let position = roles.iter().map(|r| r.position).max().unwrap_or(0);
+
+                        c.check_integer(ctx, position.max(0) as u64).await?
+                    }
+                    None => not_cached("guild roles"),
+                }
+            }
+            MemberConstraint::RoleName(c) => {
+                let guild_id = msg.guild_id.ok_or(Error::MissingGuildId)?;
+                let roles = match ctx.discord_cache.roles(guild_id, &member.roles) {
+                    Some(roles) => roles,
+                    None => return Ok(not_cached("guild roles")),
+                };
+
+                let mut matched = false;
+
+                for role in roles {
+                    if c.check_string(ctx, &role.name).await? {
+                        matched = true;
+                        break;
+                    }
+                }
+
+                matched
             }
         };
 
@@ -542,6 +728,12 @@ pub enum MessageConstraint {
     /// # Channel ID
     /// Which channel this message was sent in
     ChannelId(IntegerConstraint),
+    /// # Duplicate messages
+    /// Same or nearly the same message sent multiple times in a short duration
+    Duplicates(DuplicateConstraint),
+    /// # Channel
+    /// Channel this message was sent in
+    Channel(ChannelConstraint),
 }
 
 impl MessageConstraint {
@@ -562,6 +754,8 @@ impl MessageConstraint {
             MessageConstraint::Author(author) => author.check_event(ctx, &msg.author).await?,
             MessageConstraint::Member(member) => member.check_event(ctx, event).await?,
             MessageConstraint::ChannelId(id) => id.check_integer(ctx, msg.channel_id.0).await?,
+            MessageConstraint::Duplicates(d) => d.check_message(ctx, msg)?,
+            MessageConstraint::Channel(c) => c.check_channel(ctx, msg.channel_id).await?,
             _ => {
                 tracing::warn!("Unhandled message constraint check");
 
@@ -573,6 +767,114 @@ impl MessageConstraint {
     }
 }
 
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ChannelKind {
+    Text,
+    Voice,
+    Category,
+    News,
+    Store,
+    Stage,
+}
+
+impl ChannelKind {
+    /// Discord's channel type number
+    fn code(self) -> u8 {
+        match self {
+            Self::Text => 0,
+            Self::Voice => 2,
+            Self::Category => 4,
+            Self::News => 5,
+            Self::Store => 6,
+            Self::Stage => 13,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ChannelConstraint {
+    /// # Channel ID
+    Id(IdListConstraint),
+    /// # Category
+    /// ID of the category the channel is in
+    Category(IdListConstraint),
+    /// # NSFW
+    /// If the channel is marked as NSFW
+    Nsfw(BoolConstraint),
+    /// # Channel name
+    Name(StringConstraint),
+    /// # Channel type
+    /// Channel is one of the given types
+    Type(Vec<ChannelKind>),
+}
+
+impl ChannelConstraint {
+    async fn check_channel(&self, ctx: &RuleContext<'_>, channel_id: ChannelId) -> Result<bool> {
+        // Only the ID is known without the cache
+        if let Self::Id(c) = self {
+            return Ok(c.check_id(Some(channel_id.0)));
+        }
+
+        let channel = match ctx.discord_cache.channel(channel_id) {
+            Some(channel) => channel,
+            None => return Ok(not_cached("channel")),
+        };
+
+        let val = match self {
+            Self::Id(c) => c.check_id(Some(channel_id.0)),
+            Self::Category(c) => c.check_id(channel.parent_id.map(|id| id.0)),
+            Self::Nsfw(b) => b.check_bool(ctx, channel.nsfw).await?,
+            Self::Name(s) => s.check_string(ctx, &channel.name).await?,
+            Self::Type(kinds) => kinds.iter().any(|k| k.code() == channel.kind as u8),
+        };
+
+        Ok(val)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct DuplicateConstraint {
+    /// # Count
+    /// Number of duplicate messages, including the current message
+    pub count: IntegerVar,
+    /// # Duration
+    /// Duration in seconds to look for duplicates, up to 10 minutes
+    pub duration: DurationVar,
+    /// # Across channels
+    /// Count duplicates sent in any channel instead of only the current one
+    #[serde(default)]
+    pub across_channels: bool,
+    /// # Fuzzy
+    /// Also count messages that are nearly the same, e.g. only differing in
+    /// case, punctuation or a few characters
+    #[serde(default)]
+    pub fuzzy: bool,
+}
+
+impl DuplicateConstraint {
+    fn check_message(&self, ctx: &RuleContext<'_>, msg: &Message) -> Result<bool> {
+        let guild_id = msg.guild_id.ok_or(Error::MissingGuildId)?;
+        let channel_id = if self.across_channels {
+            None
+        } else {
+            Some(msg.channel_id)
+        };
+
+        let count = ctx.message_history.count_duplicates(
+            guild_id,
+            msg,
+            self.duration.get(ctx)?,
+            channel_id,
+            self.fuzzy,
+        );
+
+        Ok(count as u64 >= self.count.get(ctx)?)
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub struct CounterConstraint {
@@ -580,6 +882,10 @@ pub struct CounterConstraint {
     pub name: String,
     /// # Counter scope
     pub scope: RuleScope,
+    /// # Counter role
+    /// Role this counter applies to, only used for the Role scope
+    #[serde(default)]
+    pub role_id: Option<RoleIdVar>,
     /// # Value of counter
     pub value: CounterValueConstraint,
 }
@@ -608,28 +914,29 @@ pub enum CounterValueConstraint {
     /// How much the counter increased in a given duration, e.g. +5 in 1 minute
     CountsInDuration {
         /// How many counts the counter increased by
-        increased_by: u64,
+        increased_by: IntegerVar,
         /// Duration in seconds the counts increased by
-        duration: u64,
+        duration: DurationVar,
     },
 }
 
 impl CounterConstraint {
     async fn check_event(&self, ctx: &RuleContext<'_>, event: Arc<Event>) -> Result<bool> {
+        let role_id = self.role_id.get(ctx)?.map(|id| id.0);
+
         // Check if the triggered counter matches the constraint
         let triggered_counter = match event.as_ref() {
             Event::Counter { counter, .. } => Some(counter),
             _ => None,
         };
 
-        // Other events, fetch counter from db below need to do separate since
-        // above counter is borrowed. This is so it doesn't fetch from db on
-        // every event including counter trigger
+        // Other events, fetch counter from store below need to do separate
+        // since above counter is borrowed. This is so it doesn't fetch from the
+        // store on every event including counter trigger
         let db_counter = if triggered_counter.is_none() {
-            let guild_id = event.guild_id()?;
-            let scope_id = event.scope_id(self.scope)?;
+            let key = CounterKey::from_event(&event, self.scope, role_id, &self.name)?;
 
-            RuleGauge::get(&ctx.pg_pool, guild_id.0, self.scope, scope_id, &self.name).await?
+            ctx.counters.get(&key).await?
         } else {
             None
         };
@@ -650,6 +957,11 @@ impl CounterConstraint {
             return Ok(false);
         }
 
+        // Role counters with the same name can be for different roles
+        if self.scope == RuleScope::Role && Some(triggered_counter.scope_id as u64) != role_id {
+            return Ok(false);
+        }
+
         let val = match self.value {
             CounterValueConstraint::Equals(num) => triggered_counter.value == num,
             CounterValueConstraint::GreaterThan(num) => triggered_counter.value > num,
@@ -657,25 +969,121 @@ impl CounterConstraint {
             CounterValueConstraint::LessThan(num) => triggered_counter.value < num,
             CounterValueConstraint::LessThanOrEqual(num) => triggered_counter.value <= num,
             CounterValueConstraint::CountsInDuration {
-                increased_by,
-                duration,
+                ref increased_by,
+                ref duration,
             } => {
-                let guild_id = event.guild_id()?;
-                let scope_id = event.scope_id(self.scope)?;
-
-                let d = Duration::seconds(duration.try_into()?);
-
-                let curr_count = RuleGauge::get_interval_count(
-                    &ctx.pg_pool,
-                    guild_id.0,
-                    self.scope,
-                    scope_id,
-                    &self.name,
-                    d,
-                )
-                .await?;
+                let key = CounterKey::from_event(&event, self.scope, role_id, &self.name)?;
+
+                let curr_count = ctx
+                    .counters
+                    .get_interval_count(&key, duration.get(ctx)?)
+                    .await?;
+
+                curr_count as u64 >= increased_by.get(ctx)?
+            }
+        };
+
+        Ok(val)
+    }
+}
+
+/// Rule set config key of the timezone used by time constraints that don't
+/// set one. Guilds set their timezone through this key, there isn't a
+/// separate guild-level setting.
+pub const TIMEZONE_CONFIG_KEY: &str = "timezone";
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Weekday {
+    Monday,
+    Tuesday,
+    Wednesday,
+    Thursday,
+    Friday,
+    Saturday,
+    Sunday,
+}
+
+impl From<Weekday> for chrono::Weekday {
+    fn from(day: Weekday) -> Self {
+        match day {
+            Weekday::Monday => Self::Mon,
+            Weekday::Tuesday => Self::Tue,
+            Weekday::Wednesday => Self::Wed,
+            Weekday::Thursday => Self::Thu,
+            Weekday::Friday => Self::Fri,
+            Weekday::Saturday => Self::Sat,
+            Weekday::Sunday => Self::Sun,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct TimeConstraint {
+    /// # Timezone
+    /// Defaults to the rule set's `timezone` config, or UTC if it isn't set
+    #[serde(default)]
+    pub timezone: Option<TimezoneVar>,
+    /// # Time
+    pub value: TimeValueConstraint,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum TimeValueConstraint {
+    /// # Hours
+    /// Hour of the day is from `start` up to but not including `end`, from 0
+    /// to 24. Ranges can wrap past midnight, e.g. 22 to 6 for overnight.
+    Hours { start: IntegerVar, end: IntegerVar },
+    /// # Weekdays
+    /// Day of the week is one of the given days
+    Weekdays(Vec<Weekday>),
+    /// # Dates
+    /// Time is from `start` up to but not including `end`, e.g. during an
+    /// event
+    Dates {
+        start: NaiveDateTime,
+        end: NaiveDateTime,
+    },
+}
+
+/// End is exclusive, ranges where start is after end wrap past midnight
+fn hour_in_range(hour: u64, start: u64, end: u64) -> bool {
+    if start <= end {
+        start <= hour && hour < end
+    } else {
+        start <= hour || hour < end
+    }
+}
+
+impl TimeConstraint {
+    fn timezone(&self, ctx: &RuleContext<'_>) -> Result<Tz> {
+        if let Some(timezone) = &self.timezone {
+            return timezone.get(ctx);
+        }
+
+        if ctx.data.rule_config.contains_key(TIMEZONE_CONFIG_KEY) {
+            return TimezoneVar::ConfigKey(TIMEZONE_CONFIG_KEY.into()).get(ctx);
+        }
+
+        Ok(Tz::UTC)
+    }
+
+    fn check_time(&self, ctx: &RuleContext<'_>, time: DateTime<Utc>) -> Result<bool> {
+        let local = time.with_timezone(&self.timezone(ctx)?);
 
-                curr_count as u64 >= increased_by
+        let val = match &self.value {
+            TimeValueConstraint::Hours { start, end } => {
+                hour_in_range(u64::from(local.hour()), start.get(ctx)?, end.get(ctx)?)
+            }
+            TimeValueConstraint::Weekdays(days) => days
+                .iter()
+                .any(|day| chrono::Weekday::from(*day) == local.weekday()),
+            TimeValueConstraint::Dates { start, end } => {
+                let time = local.naive_local();
+
+                *start <= time && time < *end
             }
         };
 
@@ -690,6 +1098,9 @@ pub enum Constraint {
     Message(MessageConstraint),
     /// # Counters
     Counter(CounterConstraint),
+    /// # Time
+    /// When the event happened
+    Time(TimeConstraint),
 }
 
 // Requires to box future since it recurses below
@@ -711,6 +1122,9 @@ impl Constraint {
                     Constraint::Counter(counter_constraint) => {
                         counter_constraint.check_event(ctx, event).await?
                     }
+                    Constraint::Time(time_constraint) => {
+                        time_constraint.check_time(ctx, event.timestamp())?
+                    }
                 },
                 // COUNTER MODIFIED
                 Event::Counter { original_event, .. } => match self {
@@ -737,6 +1151,9 @@ impl Constraint {
 
                         counter_constraint.check_event(ctx, event).await?
                     }
+                    Constraint::Time(time_constraint) => {
+                        time_constraint.check_time(ctx, event.timestamp())?
+                    }
                 },
                 _ => {
                     tracing::warn!("Unhandled event");
@@ -750,3 +1167,51 @@ impl Constraint {
         .boxed()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_permissions() {
+        let permissions = Permission::ManageMessages.bits() | Permission::KickMembers.bits();
+
+        assert_eq!(Permission::ManageMessages.bits(), 1 << 13);
+        assert_eq!(Permission::ManageEmojis.bits(), 1 << 30);
+
+        let c = PermissionsConstraint::HasAll(vec![
+            Permission::ManageMessages,
+            Permission::KickMembers,
+        ]);
+        assert!(c.check_permissions(permissions));
+
+        let c =
+            PermissionsConstraint::HasAll(vec![Permission::ManageMessages, Permission::BanMembers]);
+        assert!(!c.check_permissions(permissions));
+
+        let c =
+            PermissionsConstraint::HasAny(vec![Permission::BanMembers, Permission::KickMembers]);
+        assert!(c.check_permissions(permissions));
+
+        let c = PermissionsConstraint::HasNone(vec![Permission::ManageMessages]);
+        assert!(!c.check_permissions(permissions));
+    }
+
+    #[test]
+    fn checks_hour_ranges() {
+        assert!(hour_in_range(9, 9, 17));
+        assert!(!hour_in_range(17, 9, 17));
+        assert!(hour_in_range(23, 22, 6));
+        assert!(hour_in_range(3, 22, 6));
+        assert!(!hour_in_range(12, 22, 6));
+        assert!(hour_in_range(0, 0, 24));
+    }
+
+    #[test]
+    fn checks_id_lists() {
+        assert!(IdListConstraint::InList(vec![1, 2]).check_id(Some(2)));
+        assert!(!IdListConstraint::InList(vec![1, 2]).check_id(None));
+        assert!(IdListConstraint::NotInList(vec![1, 2]).check_id(None));
+        assert!(!IdListConstraint::NotInList(vec![1, 2]).check_id(Some(1)));
+    }
+}
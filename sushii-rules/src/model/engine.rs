@@ -1,74 +1,231 @@
 use aho_corasick::AhoCorasick;
-use handlebars::Handlebars;
+use chrono::Utc;
+use serde::Deserialize;
+use sqlx::types::Json;
+use std::cmp::Reverse;
 use std::collections::HashMap;
 
 use std::sync::Arc;
-use std::time::Instant;
-use tokio::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::UnboundedSender;
 use tokio::sync::RwLock;
 use twilight_http::client::Client;
+use twilight_model::gateway::event::DispatchEvent;
 use twilight_model::id::GuildId;
 
-use crate::error::{Error, Result};
-use crate::model::has_id::HasGuildId;
+use sushii_model::model::sql::RuleExecution;
+
+use crate::error::{is_transient, Error, Result};
+use crate::model::has_id::*;
 use crate::model::{
-    cache::{GuildConfigCache, RuleSetsCache},
-    Event, RuleContext, RuleSet,
+    cache::{DiscordCache, GuildConfigCache, MessageHistory},
+    event::CounterCause,
+    language::{
+        referenced_languages, CachedDetector, HttpDetector, LanguageDetector, LinguaDetector,
+    },
+    template, EngineHandles, Event, RuleContext,
+};
+use crate::persistence::counter::RedisCounterStore;
+use crate::persistence::{
+    EventProgress, IdempotencyStore, RedisIdempotencyStore, RedisRateLimitStore, RuleStore,
 };
-use crate::persistence::RuleStore;
+use crate::source::SourceEvent;
+
+/// Engine behaviour that can be changed in the config
+#[derive(Debug, Clone)]
+pub struct EngineOptions {
+    /// Fail rendering templates with undefined variables instead of rendering
+    /// them as empty strings
+    pub strict_templates: bool,
+    /// Limit on actions that call the Discord API per guild, none for no limit
+    pub action_budget: Option<ActionBudget>,
+    /// Max number of counter events caused by a single gateway event
+    pub max_counter_depth: usize,
+    /// Times an event is processed before it is dead lettered
+    pub max_event_attempts: u32,
+    /// Delay before the first retry of a failed event, doubled for each retry
+    pub retry_backoff: Duration,
+    /// Where language constraints detect languages
+    pub language_backend: LanguageBackend,
+}
+
+impl Default for EngineOptions {
+    fn default() -> Self {
+        Self {
+            strict_templates: false,
+            action_budget: None,
+            max_counter_depth: 5,
+            max_event_attempts: 3,
+            retry_backoff: Duration::from_secs(1),
+            language_backend: LanguageBackend::Lingua,
+        }
+    }
+}
+
+/// Language detection used by language constraints
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LanguageBackend {
+    /// Detect in process, only out of the languages a guild's rules reference
+    Lingua,
+    /// Detect with the language API at the given endpoint
+    Http(String),
+}
+
+/// Max number of Discord API actions a guild's rules can run in a window
+#[derive(Debug, Clone, Copy)]
+pub struct ActionBudget {
+    pub limit: u64,
+    pub window: Duration,
+    pub mode: BudgetMode,
+}
+
+/// What happens to actions past the action budget
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BudgetMode {
+    /// Skip the action
+    Drop,
+    /// Wait a few seconds for the budget to reset before dropping
+    Queue,
+}
+
+impl Default for BudgetMode {
+    fn default() -> Self {
+        Self::Drop
+    }
+}
 
 pub struct RulesEngine {
     /// Stores rules fetched from file or database
+    pub rule_store: Box<dyn RuleStore>,
+    /// Guild configs fetched from database
     pub guild_configs: GuildConfigCache,
-    /// Shared handlebars template to prevent reparsing
-    /// This is a RwLock since registering templates requires mut self
-    pub handlebars_templates: Arc<RwLock<Handlebars<'static>>>,
-    /// Postgres database pool
-    pub pg_pool: sqlx::PgPool,
+    /// Clients, stores and caches passed to each rule context. Counters and
+    /// rate limits are stored in Redis.
+    pub handles: EngineHandles<'static>,
     /// Redis connection pool
     pub redis_pool: deadpool_redis::Pool,
+    pub max_counter_depth: usize,
+    /// Progress of events, stored in Redis for redelivered events
+    pub idempotency: Arc<dyn IdempotencyStore>,
+    pub max_event_attempts: u32,
+    pub retry_backoff: Duration,
+    /// Rules disabled for looping counter events, by guild and rule ID along
+    /// with the reason. These are skipped even if the rule store can't
+    /// disable them.
+    pub disabled_rules: Arc<RwLock<HashMap<(u64, i64), String>>>,
     /// Guild specific word lists
     pub word_lists: Arc<RwLock<HashMap<GuildId, Arc<RwLock<HashMap<String, AhoCorasick>>>>>>,
-    /// Twilight HTTP client
-    pub http: Client,
-    pub reqwest: reqwest::Client,
-    /// Wraps the reqwest client
-    pub language_client: language_api_wrapper::LanguageApiClient,
-    /// Counter triggers from other events
-    /// Events can send a new counter event
-    pub channel_tx: Sender<Event>,
 }
 
 impl RulesEngine {
     pub fn new(
+        rule_store: Box<dyn RuleStore>,
         http: Client,
         pg_pool: sqlx::PgPool,
         redis_pool: deadpool_redis::Pool,
-        language_api_endpoint: &str,
-        channel_tx: Sender<Event>,
+        channel_tx: UnboundedSender<Event>,
+        options: EngineOptions,
     ) -> Self {
         let reqwest = reqwest::Client::new();
 
+        let language_detector: Arc<dyn LanguageDetector> = match options.language_backend {
+            LanguageBackend::Lingua => Arc::new(CachedDetector::new(LinguaDetector::new())),
+            LanguageBackend::Http(endpoint) => Arc::new(CachedDetector::new(HttpDetector::new(
+                language_api_wrapper::LanguageApiClient::new(reqwest.clone(), &endpoint),
+            ))),
+        };
+
         Self {
+            rule_store,
             guild_configs: GuildConfigCache::new(),
-            handlebars_templates: Arc::new(RwLock::new(Handlebars::new())),
-            pg_pool,
+            handles: EngineHandles {
+                http,
+                pg_pool,
+                counters: Arc::new(RedisCounterStore::new(redis_pool.clone())),
+                rate_limits: Arc::new(RedisRateLimitStore::new(redis_pool.clone())),
+                action_budget: options.action_budget,
+                reqwest,
+                language_detector,
+                handlebars_templates: Arc::new(RwLock::new(template::registry(
+                    options.strict_templates,
+                ))),
+                message_history: MessageHistory::new(),
+                discord_cache: DiscordCache::new(),
+                channel_tx,
+            },
+            max_counter_depth: options.max_counter_depth,
+            idempotency: Arc::new(RedisIdempotencyStore::new(redis_pool.clone())),
+            max_event_attempts: options.max_event_attempts,
+            retry_backoff: options.retry_backoff,
+            disabled_rules: Arc::new(RwLock::new(HashMap::new())),
             redis_pool,
             word_lists: Arc::new(RwLock::new(HashMap::new())),
-            http,
-            reqwest: reqwest.clone(),
-            language_client: language_api_wrapper::LanguageApiClient::new(
-                reqwest,
-                language_api_endpoint,
-            ),
-            channel_tx,
         }
     }
 
-    /// Events that modify counters also trigger this to process the counter.
-    /// It provides the original event that triggered this counter
-    #[tracing::instrument(skip(self))]
-    pub async fn process_event(&self, event: Arc<Event>) -> Result<()> {
+    /// Processes an event from a source, retrying transient failures with
+    /// backoff. The event is acked once it is processed or dead lettered if
+    /// transient failures outlast the retry budget. Rules that fail with a
+    /// permanent error are only logged.
+    pub async fn process_source_event(&self, source_event: SourceEvent) {
+        let SourceEvent { id, event, acker } = source_event;
+        let event = Arc::new(event);
+        let progress = Arc::new(self.load_progress(&id).await);
+        let mut attempts = 0;
+
+        let res = loop {
+            attempts += 1;
+
+            let res = self
+                .process_event(event.clone(), &id, progress.clone())
+                .await;
+            self.save_progress(&id, &progress).await;
+
+            match res {
+                Err(e) if e.is_transient() && attempts < self.max_event_attempts => {
+                    let backoff = self.retry_backoff * 2u32.pow(attempts - 1);
+                    tracing::warn!(event_id = %id, attempts, ?backoff, "Retrying event: {}", e);
+                    metrics::increment_counter!("event_retried");
+
+                    tokio::time::sleep(backoff).await;
+                }
+                res => break res,
+            }
+        };
+
+        let ack_res = match res {
+            Ok(()) => acker.ack().await,
+            Err(e) if e.is_transient() => {
+                tracing::error!(event_id = %id, attempts, "Dead lettering event: {}", e);
+                metrics::increment_counter!("event_dead_lettered");
+
+                acker.dead_letter(&e.to_string(), attempts).await
+            }
+            // Retrying doesn't help, e.g. an invalid event or rule store error
+            Err(e) => {
+                tracing::error!(event_id = %id, "Failed processing event: {}", e);
+
+                acker.ack().await
+            }
+        };
+
+        if let Err(e) = ack_res {
+            tracing::error!(event_id = %id, "Failed to ack event: {}", e);
+        }
+    }
+
+    /// Runs every rule triggered by an event, returns the first rule that
+    /// failed with a transient error after running the rest. Events that
+    /// modify counters also trigger this to process the counter. It provides
+    /// the original event that triggered this counter
+    #[tracing::instrument(skip(self, progress))]
+    pub async fn process_event(
+        &self,
+        event: Arc<Event>,
+        event_id: &str,
+        progress: Arc<EventProgress>,
+    ) -> Result<()> {
         let event_type = match event.kind() {
             Ok(t) => t,
             Err(Error::UnsupportedEvent) => return Ok(()),
@@ -81,15 +238,39 @@ impl RulesEngine {
             Err(_) => return Ok(()),
         };
 
+        if let Event::Counter { cause, .. } = event.as_ref() {
+            if let Some(reason) = self.check_counter_cause(cause) {
+                if let Some(rule_id) = cause.rule_id() {
+                    self.disable_rule(guild_id.0, rule_id, reason).await;
+                }
+
+                return Ok(());
+            }
+        }
+
         // Fetch guild rule sets
-        let guild_rule_sets =
-            RuleSet::sets_from_guild_id(self.redis_pool.clone(), &self.pg_pool, guild_id.0).await?;
+        let guild_rule_sets = self.rule_store.get_guild_rule_sets(guild_id.0).await?;
 
         if guild_rule_sets.is_empty() {
             return Ok(());
         }
 
-        let guild_config = self.guild_configs.get(&self.pg_pool, guild_id).await?;
+        // Record messages before any rules run so that every rule sees the
+        // same history, counter events reuse the original message so they
+        // shouldn't be recorded again. Retries record the same message ID,
+        // which is only kept once
+        if let Event::Twilight(DispatchEvent::MessageCreate(msg)) = event.as_ref() {
+            self.handles.message_history.record(msg);
+        }
+
+        let guild_config = self
+            .guild_configs
+            .get(&self.handles.pg_pool, guild_id)
+            .await?;
+        let languages = Arc::new(referenced_languages(
+            guild_rule_sets.iter().flat_map(|set| set.rules.iter()),
+        ));
+        let mut rules = Vec::new();
 
         for rule_set in guild_rule_sets {
             if !rule_set.enabled {
@@ -105,34 +286,176 @@ impl RulesEngine {
                     continue;
                 }
 
+                if self
+                    .disabled_rules
+                    .read()
+                    .await
+                    .contains_key(&(guild_id.0, rule.id))
+                {
+                    continue;
+                }
+
                 // Create a new context on every rule trigger
                 let mut context = RuleContext::new(
+                    &self.handles,
                     guild_config.clone(),
-                    self.http.clone(),
-                    self.pg_pool.clone(),
-                    self.reqwest.clone(),
-                    self.language_client.clone(),
-                    self.handlebars_templates.clone(),
+                    progress.clone(),
                     self.word_lists.read().await.get(&guild_id).cloned(),
-                    self.channel_tx.clone(),
+                    languages.clone(),
                 );
+                context.data.rule_config = rule_set.config.clone();
+                context.rule_id = rule.id;
+                context.rule_name = rule.name.clone();
+                context.rule_set_id = rule_set.id;
+                context.event_id = event_id.to_string();
+
+                rules.push((rule.clone(), context));
+            }
+        }
+
+        // Stable sort, so rules with the same priority keep the order of their
+        // rule sets
+        rules.sort_by_key(|(rule, _)| Reverse(rule.priority));
+
+        let mut failure = None;
+
+        // Rules run one at a time so a rule can stop lower priority rules from
+        // running
+        for (rule, mut context) in rules {
+            let start = Instant::now();
+            // Error isn't Send so it can't be held while saving below
+            let res = rule
+                .check_event(event.clone(), &mut context)
+                .await
+                .map_err(|e| (e.to_string(), is_transient(e.as_ref())));
+
+            let delta = start.elapsed();
+            metrics::histogram!("rule_execution", delta);
+
+            // Rules that errored in actions still fired, but errors in
+            // conditions didn't run anything
+            let fired = matches!(res, Ok(true)) || !context.action_results.is_empty();
+
+            // Retried events skip actions that already ran, only attempts
+            // that ran or failed an action are logged
+            let ran_action = !context.actions_run.is_empty()
+                || context.action_results.iter().any(|r| r.error.is_some());
+
+            // Permanent errors fail the same way on a retry, so they are only
+            // logged and the event still succeeds
+            let permanent_error = match &res {
+                Err((message, false)) => Some(message.clone()),
+                _ => None,
+            };
+
+            if ran_action || permanent_error.is_some() {
+                let execution = RuleExecution {
+                    time: Utc::now(),
+                    guild_id: guild_id.0 as i64,
+                    set_id: context.rule_set_id,
+                    rule_id: rule.id,
+                    event_id: event_id.to_string(),
+                    user_id: event.user_id().ok().map(|id| id.0 as i64),
+                    actions: Json(std::mem::take(&mut context.action_results)),
+                    latency_ms: delta.as_millis() as i64,
+                    error: permanent_error,
+                };
+
+                if let Err(e) = execution.save(&self.handles.pg_pool).await {
+                    tracing::warn!(rule_id = rule.id, "Failed to save rule execution: {}", e);
+                }
+            }
+
+            match res {
+                Err((message, true)) => {
+                    tracing::warn!(rule_id = rule.id, "Failed checking event: {}", message);
 
-                let event = event.clone();
-                let rule = rule.clone();
+                    failure.get_or_insert(Error::RuleFailed {
+                        rule_id: rule.id,
+                        message,
+                        transient: true,
+                    });
+                }
+                Err((message, false)) => {
+                    tracing::error!(rule_id = rule.id, "Rule failed: {}", message);
+                    metrics::increment_counter!("rule_failed");
+                }
+                Ok(_) => {}
+            }
 
-                tokio::spawn(async move {
-                    let start = Instant::now();
+            // Lower priority rules are skipped even if this rule's actions
+            // failed, since its conditions passed
+            if fired && rule.stop_processing {
+                tracing::debug!(rule_id = rule.id, "Rule stopped processing");
+                break;
+            }
+        }
 
-                    if let Err(e) = rule.check_event(event, &mut context).await {
-                        tracing::warn!("Failed checking event: {}", e);
-                    }
+        match failure {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Progress saved by an earlier delivery of the event. Events are still
+    /// processed if it can't be loaded, since most events are only delivered
+    /// once.
+    async fn load_progress(&self, event_id: &str) -> EventProgress {
+        match self.idempotency.load(event_id).await {
+            Ok(progress) => progress,
+            Err(e) => {
+                tracing::warn!(%event_id, "Failed to load event progress: {}", e);
+                metrics::increment_counter!("event_progress_failed", "op" => "load");
 
-                    let delta = start.elapsed();
-                    metrics::histogram!("rule_execution", delta);
-                });
+                EventProgress::new()
             }
         }
+    }
+
+    /// Saves the progress of an attempt in case the event is redelivered.
+    /// Retries in this process use the progress in memory, so a failed save
+    /// is only logged.
+    async fn save_progress(&self, event_id: &str, progress: &EventProgress) {
+        if let Err(e) = self.idempotency.save(event_id, progress).await {
+            tracing::warn!(%event_id, "Failed to save event progress: {}", e);
+            metrics::increment_counter!("event_progress_failed", "op" => "save");
+        }
+    }
+
+    /// Reason to stop a chain of counter events, if it is too deep or a rule
+    /// is responding to its own counter changes
+    fn check_counter_cause(&self, cause: &CounterCause) -> Option<String> {
+        if cause.is_cycle() {
+            return Some(format!("Counter event loop: {}", cause.describe()));
+        }
+
+        if cause.depth() > self.max_counter_depth {
+            return Some(format!(
+                "Counter events exceeded max depth of {}: {}",
+                self.max_counter_depth,
+                cause.describe()
+            ));
+        }
+
+        None
+    }
+
+    /// Stops running a rule in a guild and disables it in the rule store
+    pub async fn disable_rule(&self, guild_id: u64, rule_id: i64, reason: String) {
+        tracing::warn!(guild_id, rule_id, %reason, "Disabling rule");
+        metrics::increment_counter!("rule_disabled");
+
+        if let Err(e) = self
+            .rule_store
+            .disable_rule(guild_id, rule_id, &reason)
+            .await
+        {
+            tracing::error!(guild_id, rule_id, "Failed to disable rule: {}", e);
+        }
 
-        Ok(())
+        self.disabled_rules
+            .write()
+            .await
+            .insert((guild_id, rule_id), reason);
     }
 }
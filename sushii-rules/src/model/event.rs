@@ -1,8 +1,10 @@
+use chrono::{DateTime, TimeZone, Utc};
 use serde::Serialize;
 use std::convert::TryInto;
 use sushii_model::model::sql::RuleGauge;
 use twilight_model::channel::message::Message;
 use twilight_model::gateway::event::DispatchEvent;
+use twilight_model::id::MessageId;
 
 use crate::error::Result;
 use crate::model::Trigger;
@@ -17,6 +19,9 @@ pub enum Event {
     Counter {
         counter: RuleGauge,
         original_event: DispatchEvent,
+        /// Rules that changed counters leading up to this event
+        #[serde(skip)]
+        cause: CounterCause,
     },
     /// Timer
     LevelUp {
@@ -49,4 +54,155 @@ impl Event {
             Self::LevelUp { .. } => Ok(Trigger::LevelUp),
         }
     }
+
+    /// Number of counter events that led to this event, 0 for events that
+    /// didn't come from a rule
+    pub fn depth(&self) -> usize {
+        match self {
+            Self::Counter { cause, .. } => cause.depth(),
+            _ => 0,
+        }
+    }
+
+    /// When the event happened. Counter events use the event that changed the
+    /// counter, so events still queued behind others are checked against when
+    /// they were sent rather than when they're processed. Falls back to now
+    /// for events without a message.
+    pub fn timestamp(&self) -> DateTime<Utc> {
+        let msg = match self {
+            Self::Twilight(DispatchEvent::MessageCreate(msg)) => &msg.0,
+            Self::Counter {
+                original_event: DispatchEvent::MessageCreate(msg),
+                ..
+            } => &msg.0,
+            Self::LevelUp { message, .. } => message.as_ref(),
+            _ => return Utc::now(),
+        };
+
+        DateTime::parse_from_rfc3339(&msg.timestamp)
+            .map(Into::into)
+            .unwrap_or_else(|_| snowflake_time(msg.id))
+    }
+
+    /// Creates the counter event for a rule changing a counter while handling
+    /// this event. Level up events don't have a gateway event to pass along
+    /// so these don't trigger counter events.
+    pub fn counter_event(&self, counter: RuleGauge, rule_id: i64) -> Option<Self> {
+        let (original_event, mut cause) = match self {
+            Self::Twilight(event) => (event.clone(), CounterCause::default()),
+            Self::Counter {
+                original_event,
+                cause,
+                ..
+            } => (original_event.clone(), cause.clone()),
+            Self::LevelUp { .. } => return None,
+        };
+
+        cause.chain.push(CauseLink {
+            rule_id,
+            counter: format!("{}:{:?}:{}", counter.name, counter.scope, counter.scope_id),
+        });
+
+        Some(Self::Counter {
+            counter,
+            original_event,
+            cause,
+        })
+    }
+}
+
+/// Milliseconds between the Unix epoch and the first second of 2015, which
+/// Discord snowflakes count from
+const DISCORD_EPOCH_MS: i64 = 1_420_070_400_000;
+
+/// Creation time encoded in a message ID
+fn snowflake_time(id: MessageId) -> DateTime<Utc> {
+    Utc.timestamp_millis((id.0 >> 22) as i64 + DISCORD_EPOCH_MS)
+}
+
+/// A rule changing a counter
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CauseLink {
+    pub rule_id: i64,
+    /// Counter name, scope and scope ID
+    pub counter: String,
+}
+
+/// Chain of counter changes leading to a counter event, used to stop rules
+/// from triggering each other forever
+#[derive(Debug, Clone, Default)]
+pub struct CounterCause {
+    /// Oldest change first, the last link is the one that sent this event
+    pub chain: Vec<CauseLink>,
+}
+
+impl CounterCause {
+    pub fn depth(&self) -> usize {
+        self.chain.len()
+    }
+
+    /// Rule that sent this event
+    pub fn rule_id(&self) -> Option<i64> {
+        self.chain.last().map(|link| link.rule_id)
+    }
+
+    /// If the latest rule already changed the same counter earlier in the
+    /// chain, meaning it is responding to its own changes. Different rules
+    /// changing the same counter, e.g. resetting a counter after it reaches
+    /// a limit, aren't cycles.
+    pub fn is_cycle(&self) -> bool {
+        match self.chain.split_last() {
+            Some((last, rest)) => rest.contains(last),
+            None => false,
+        }
+    }
+
+    /// Chain as text for logs, e.g. `rule 1 (spam:User:1234) -> rule 2 (...)`
+    pub fn describe(&self) -> String {
+        self.chain
+            .iter()
+            .map(|link| format!("rule {} ({})", link.rule_id, link.counter))
+            .collect::<Vec<_>>()
+            .join(" -> ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn link(rule_id: i64, counter: &str) -> CauseLink {
+        CauseLink {
+            rule_id,
+            counter: counter.into(),
+        }
+    }
+
+    #[test]
+    fn snowflake_time_is_message_creation() {
+        // ID of a message sent at 2021-03-01T00:00:00Z
+        let id = MessageId(815_735_085_465_600_000);
+
+        assert_eq!(snowflake_time(id), Utc.ymd(2021, 3, 1).and_hms(0, 0, 0));
+    }
+
+    #[test]
+    fn detects_rule_changing_own_counter() {
+        let mut cause = CounterCause {
+            chain: vec![link(1, "spam:User:1"), link(2, "spam:User:1")],
+        };
+
+        // A different rule resetting the counter isn't a loop
+        assert!(!cause.is_cycle());
+
+        cause.chain.push(link(2, "spam:User:1"));
+
+        assert!(cause.is_cycle());
+        assert_eq!(cause.depth(), 3);
+        assert_eq!(cause.rule_id(), Some(2));
+        assert_eq!(
+            cause.describe(),
+            "rule 1 (spam:User:1) -> rule 2 (spam:User:1) -> rule 2 (spam:User:1)"
+        );
+    }
 }
@@ -30,7 +30,8 @@ impl HasScopeId for DispatchEvent {
         match scope {
             RuleScope::Guild => self.guild_id().map(|id| id.0),
             RuleScope::Channel => self.channel_id().map(|id| id.0),
-            RuleScope::User => self.user_id().map(|id| id.0),
+            RuleScope::User | RuleScope::UserChannel => self.user_id().map(|id| id.0),
+            RuleScope::Role => Err(Error::MissingRoleId),
         }
     }
 }
@@ -40,7 +41,8 @@ impl HasScopeId for Message {
         match scope {
             RuleScope::Guild => self.guild_id.map(|id| id.0).ok_or(Error::MissingGuildId),
             RuleScope::Channel => Ok(self.channel_id.0),
-            RuleScope::User => Ok(self.author.id.0),
+            RuleScope::User | RuleScope::UserChannel => Ok(self.author.id.0),
+            RuleScope::Role => Err(Error::MissingRoleId),
         }
     }
 }
@@ -0,0 +1,278 @@
+use async_trait::async_trait;
+use dashmap::DashMap;
+use lingua::{Language, LanguageDetectorBuilder};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+
+use crate::error::Result;
+use crate::model::{
+    constraint::{
+        ChannelConstraint, MemberConstraint, MessageConstraint, StringConstraint, UserConstraint,
+    },
+    Action, Condition, Constraint, Rule,
+};
+
+/// Min difference between the two most likely languages for a detection to
+/// count, so short or mixed text doesn't match any language
+const MIN_RELATIVE_DISTANCE: f64 = 0.25;
+
+/// Languages compared against when rules only reference one, lingua needs at
+/// least two
+const FALLBACK_LANGUAGES: [Language; 2] = [Language::English, Language::Spanish];
+
+/// Max number of cached detections before the cache is cleared
+const MAX_CACHED: usize = 10_000;
+
+#[async_trait]
+pub trait LanguageDetector: fmt::Debug + Send + Sync {
+    /// Detects the language of text out of the candidate languages, None if
+    /// no language is likely enough. Backends that can't limit the languages
+    /// ignore the candidates.
+    async fn detect(&self, text: &str, languages: &[Language]) -> Result<Option<Language>>;
+}
+
+/// Detects languages in process, with a lingua detector for each set of
+/// candidate languages
+#[derive(Clone, Default)]
+pub struct LinguaDetector {
+    detectors: Arc<DashMap<Vec<Language>, Arc<lingua::LanguageDetector>>>,
+}
+
+impl fmt::Debug for LinguaDetector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LinguaDetector")
+            .field("detectors", &self.detectors.len())
+            .finish()
+    }
+}
+
+impl LinguaDetector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn detector(&self, languages: &[Language]) -> Arc<lingua::LanguageDetector> {
+        let mut languages: BTreeSet<Language> = languages.iter().copied().collect();
+
+        for fallback in FALLBACK_LANGUAGES.iter() {
+            if languages.len() < 2 {
+                languages.insert(*fallback);
+            }
+        }
+
+        let languages: Vec<Language> = languages.into_iter().collect();
+
+        if let Some(detector) = self.detectors.get(&languages) {
+            return detector.clone();
+        }
+
+        let detector = Arc::new(
+            LanguageDetectorBuilder::from_languages(&languages)
+                .with_minimum_relative_distance(MIN_RELATIVE_DISTANCE)
+                .build(),
+        );
+
+        self.detectors.insert(languages, detector.clone());
+
+        detector
+    }
+}
+
+#[async_trait]
+impl LanguageDetector for LinguaDetector {
+    async fn detect(&self, text: &str, languages: &[Language]) -> Result<Option<Language>> {
+        let detector = self.detector(languages);
+        let text = text.to_string();
+
+        // Detection is CPU bound, don't block other events
+        let language =
+            tokio::task::spawn_blocking(move || detector.detect_language_of(text)).await?;
+
+        Ok(language)
+    }
+}
+
+/// Detects languages with the language API over HTTP
+#[derive(Clone)]
+pub struct HttpDetector {
+    client: language_api_wrapper::LanguageApiClient,
+}
+
+impl fmt::Debug for HttpDetector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HttpDetector").finish()
+    }
+}
+
+impl HttpDetector {
+    pub fn new(client: language_api_wrapper::LanguageApiClient) -> Self {
+        Self { client }
+    }
+}
+
+#[async_trait]
+impl LanguageDetector for HttpDetector {
+    async fn detect(&self, text: &str, _languages: &[Language]) -> Result<Option<Language>> {
+        Ok(self.client.detect_language(text).await?)
+    }
+}
+
+/// Caches detections by content and candidate languages, so multiple
+/// language constraints on the same message only detect once
+#[derive(Debug, Clone)]
+pub struct CachedDetector<D> {
+    inner: D,
+    cache: Arc<DashMap<u64, Option<Language>>>,
+}
+
+impl<D: LanguageDetector> CachedDetector<D> {
+    pub fn new(inner: D) -> Self {
+        Self {
+            inner,
+            cache: Arc::new(DashMap::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl<D: LanguageDetector> LanguageDetector for CachedDetector<D> {
+    async fn detect(&self, text: &str, languages: &[Language]) -> Result<Option<Language>> {
+        let mut hasher = DefaultHasher::new();
+        text.hash(&mut hasher);
+        languages.hash(&mut hasher);
+        let key = hasher.finish();
+
+        if let Some(language) = self.cache.get(&key) {
+            metrics::increment_counter!("language_detect_cached");
+            return Ok(*language);
+        }
+
+        let language = self.inner.detect(text, languages).await?;
+
+        if self.cache.len() >= MAX_CACHED {
+            self.cache.clear();
+        }
+
+        self.cache.insert(key, language);
+
+        Ok(language)
+    }
+}
+
+/// Every language referenced by language constraints in rules, sorted
+pub fn referenced_languages<'a>(rules: impl IntoIterator<Item = &'a Rule>) -> Vec<Language> {
+    let mut languages = BTreeSet::new();
+
+    for rule in rules {
+        condition_languages(&rule.conditions, &mut languages);
+        actions_languages(&rule.actions, &mut languages);
+    }
+
+    languages.into_iter().collect()
+}
+
+fn condition_languages(condition: &Condition, languages: &mut BTreeSet<Language>) {
+    match condition {
+        Condition::Condition { constraint } => constraint_languages(constraint, languages),
+        Condition::And { and: conditions }
+        | Condition::Or { or: conditions }
+        | Condition::AtLeast { conditions, .. } => {
+            for c in conditions {
+                condition_languages(c, languages);
+            }
+        }
+        Condition::Not { not } => condition_languages(not, languages),
+    }
+}
+
+fn actions_languages(actions: &[Action], languages: &mut BTreeSet<Language>) {
+    for action in actions {
+        if let Action::SubCondition {
+            condition,
+            actions,
+            actions_else,
+        } = action
+        {
+            condition_languages(condition, languages);
+            actions_languages(actions, languages);
+            actions_languages(actions_else, languages);
+        }
+    }
+}
+
+fn constraint_languages(constraint: &Constraint, languages: &mut BTreeSet<Language>) {
+    let string_constraint = match constraint {
+        Constraint::Message(MessageConstraint::Content(c))
+        | Constraint::Message(MessageConstraint::Author(UserConstraint::Username(c)))
+        | Constraint::Message(MessageConstraint::Member(MemberConstraint::Nickname(c)))
+        | Constraint::Message(MessageConstraint::Member(MemberConstraint::RoleName(c)))
+        | Constraint::Message(MessageConstraint::Channel(ChannelConstraint::Name(c))) => c,
+        _ => return,
+    };
+
+    match string_constraint {
+        StringConstraint::IsLanguage(lang) | StringConstraint::IsNotLanguage(lang) => {
+            languages.insert(*lang);
+        }
+        StringConstraint::IsInLanguage(langs) | StringConstraint::IsNotInLanguage(langs) => {
+            languages.extend(langs.iter().map(|l| l.0));
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{constraint::LanguageWrapper, Trigger};
+
+    fn content_condition(constraint: StringConstraint) -> Condition {
+        Condition::Condition {
+            constraint: Constraint::Message(MessageConstraint::Content(constraint)),
+        }
+    }
+
+    #[test]
+    fn collects_nested_languages() {
+        let rule = Rule {
+            id: 0,
+            name: "rule".into(),
+            enabled: true,
+            trigger: Trigger::MessageCreate,
+            conditions: Condition::Or {
+                or: vec![
+                    content_condition(StringConstraint::IsLanguage(Language::French)),
+                    Condition::Not {
+                        not: Box::new(content_condition(StringConstraint::IsNotInLanguage(vec![
+                            LanguageWrapper(Language::German),
+                            LanguageWrapper(Language::French),
+                        ]))),
+                    },
+                ],
+            },
+            actions: vec![],
+            cooldown: None,
+            priority: 0,
+            stop_processing: false,
+        };
+
+        assert_eq!(
+            referenced_languages(&[rule]),
+            vec![Language::French, Language::German]
+        );
+    }
+
+    #[test]
+    fn single_language_compares_with_fallback() {
+        let detector = LinguaDetector::new();
+        detector.detector(&[Language::French]);
+
+        let mut expected = vec![Language::French, Language::English];
+        expected.sort();
+
+        assert!(detector.detectors.contains_key(&expected));
+    }
+}
@@ -0,0 +1,215 @@
+use anyhow::Result;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use twilight_http::client::Client;
+use twilight_model::id::{ChannelId, MessageId};
+
+use crate::model::template::truncate_str;
+
+/// Discord's limit on message content length, in characters
+pub const MESSAGE_MAX_LEN: usize = 2000;
+
+/// Most messages a single action can send when splitting long content
+pub const MAX_SPLIT_MESSAGES: usize = 3;
+
+/// Which mentions in a sent message are allowed to ping. Mentions in
+/// interpolated user content are always escaped, so this only applies to
+/// mentions written in the template or created by helpers.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
+pub struct MentionPolicy {
+    /// Mentioned users are pinged
+    #[serde(default)]
+    pub users: bool,
+    /// Mentioned roles are pinged
+    #[serde(default)]
+    pub roles: bool,
+    /// @everyone and @here are pinged
+    #[serde(default)]
+    pub everyone: bool,
+    /// Author of the replied to message is pinged, only used for replies
+    #[serde(default = "default_replied_user")]
+    pub replied_user: bool,
+}
+
+fn default_replied_user() -> bool {
+    true
+}
+
+impl Default for MentionPolicy {
+    fn default() -> Self {
+        Self {
+            users: false,
+            roles: false,
+            everyone: false,
+            replied_user: default_replied_user(),
+        }
+    }
+}
+
+/// What to do with content longer than Discord allows
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
+pub enum Overflow {
+    /// Cut off content at the limit, ending with an ellipsis
+    Truncate,
+    /// Send content in multiple messages, up to 3
+    Split,
+}
+
+impl Default for Overflow {
+    fn default() -> Self {
+        Self::Truncate
+    }
+}
+
+/// Finishes an allowed mentions builder. The builder changes type when users
+/// or roles are parsed, so each combination needs its own chain.
+macro_rules! build_mentions {
+    ($builder:expr, $policy:expr) => {{
+        let builder = $builder.replied_user($policy.replied_user);
+
+        if $policy.everyone {
+            builder.parse_everyone().build()
+        } else {
+            builder.build()
+        }
+    }};
+}
+
+/// Sends rendered content to a channel, only the first message is sent as a
+/// reply when the content is split
+pub async fn send_content(
+    http: &Client,
+    channel_id: ChannelId,
+    reply_to: Option<MessageId>,
+    content: &str,
+    mentions: &MentionPolicy,
+    overflow: Overflow,
+) -> Result<()> {
+    for (i, chunk) in fit_content(content, overflow).into_iter().enumerate() {
+        let mut req = http.create_message(channel_id).content(chunk)?;
+
+        if let Some(message_id) = reply_to.filter(|_| i == 0) {
+            req = req.reply(message_id);
+        }
+
+        let req = match (mentions.users, mentions.roles) {
+            (false, false) => build_mentions!(req.allowed_mentions(), mentions),
+            (true, false) => build_mentions!(req.allowed_mentions().parse_users(), mentions),
+            (false, true) => build_mentions!(req.allowed_mentions().parse_roles(), mentions),
+            (true, true) => {
+                build_mentions!(req.allowed_mentions().parse_users().parse_roles(), mentions)
+            }
+        };
+
+        req.await?;
+    }
+
+    Ok(())
+}
+
+/// Fits content into messages within Discord's length limit
+pub fn fit_content(content: &str, overflow: Overflow) -> Vec<String> {
+    if content.chars().count() <= MESSAGE_MAX_LEN {
+        return vec![content.to_string()];
+    }
+
+    match overflow {
+        Overflow::Truncate => vec![truncate_str(content, MESSAGE_MAX_LEN, "…")],
+        Overflow::Split => {
+            let mut chunks = split_content(content, MESSAGE_MAX_LEN);
+
+            if chunks.len() > MAX_SPLIT_MESSAGES {
+                chunks.truncate(MAX_SPLIT_MESSAGES);
+
+                if let Some(last) = chunks.last_mut() {
+                    *last = format!("{}…", truncate_str(last, MESSAGE_MAX_LEN - 1, ""));
+                }
+            }
+
+            chunks
+        }
+    }
+}
+
+/// Splits text into chunks of at most `max_len` characters, preferring to
+/// split on new lines and then whitespace
+pub fn split_content(text: &str, max_len: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut rest = text;
+
+    while rest.chars().count() > max_len {
+        // Byte index of the first character past the limit
+        let limit = rest
+            .char_indices()
+            .nth(max_len)
+            .map_or(rest.len(), |(i, _)| i);
+        let head = &rest[..limit];
+
+        let split_at = head
+            .rfind('\n')
+            .or_else(|| head.rfind(char::is_whitespace))
+            .filter(|&i| i > 0)
+            .unwrap_or(limit);
+
+        chunks.push(rest[..split_at].to_string());
+        rest = rest[split_at..].trim_start();
+    }
+
+    if !rest.is_empty() {
+        chunks.push(rest.to_string());
+    }
+
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_content_is_unchanged() {
+        assert_eq!(fit_content("hi", Overflow::Truncate), vec!["hi"]);
+        assert_eq!(fit_content("hi", Overflow::Split), vec!["hi"]);
+    }
+
+    #[test]
+    fn truncates_long_content() {
+        let content = "a".repeat(MESSAGE_MAX_LEN + 10);
+        let messages = fit_content(&content, Overflow::Truncate);
+
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].chars().count(), MESSAGE_MAX_LEN);
+        assert!(messages[0].ends_with('…'));
+    }
+
+    #[test]
+    fn splits_on_lines_then_whitespace() {
+        assert_eq!(
+            split_content("one two\nthree four", 12),
+            vec!["one two", "three four"]
+        );
+        assert_eq!(split_content("one two three", 9), vec!["one two", "three"]);
+        assert_eq!(split_content("abcdefgh", 3), vec!["abc", "def", "gh"]);
+    }
+
+    #[test]
+    fn split_is_capped() {
+        let content = "word ".repeat(MESSAGE_MAX_LEN);
+        let messages = fit_content(&content, Overflow::Split);
+
+        assert_eq!(messages.len(), MAX_SPLIT_MESSAGES);
+        assert!(messages
+            .iter()
+            .all(|m| m.chars().count() <= MESSAGE_MAX_LEN));
+        assert!(messages[MAX_SPLIT_MESSAGES - 1].ends_with('…'));
+    }
+
+    #[test]
+    fn default_policy_only_pings_replied_user() {
+        let policy: MentionPolicy = serde_json::from_str("{}").unwrap();
+
+        assert_eq!(policy, MentionPolicy::default());
+        assert!(policy.replied_user);
+        assert!(!policy.users && !policy.roles && !policy.everyone);
+    }
+}
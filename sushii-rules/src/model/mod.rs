@@ -7,22 +7,30 @@ pub mod constraint;
 pub mod engine;
 pub mod event;
 pub mod has_id;
+pub mod language;
+pub mod message;
+pub mod mod_log;
 pub mod rule;
 pub mod rule_context;
 pub mod rule_set;
+pub mod rule_test;
 pub mod status;
+pub mod template;
 pub mod trigger;
+pub mod validation;
+pub mod worker;
 
 pub use self::{
     action::Action,
     condition::Condition, // condition_result::ConditionResult,
     config::RuleConfig,
     constraint::Constraint,
-    engine::RulesEngine,
+    engine::{EngineOptions, RulesEngine},
     event::Event,
     rule::Rule,
-    rule_context::RuleContext,
+    rule_context::{EngineHandles, RuleContext},
     rule_set::RuleSet,
     status::Status,
     trigger::Trigger,
+    worker::WorkerPool,
 };
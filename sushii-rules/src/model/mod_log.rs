@@ -0,0 +1,182 @@
+use anyhow::Result;
+use twilight_model::channel::embed::{Embed, EmbedAuthor, EmbedField, EmbedFooter};
+use twilight_model::channel::message::Message;
+use twilight_model::gateway::event::DispatchEvent;
+use twilight_model::id::ChannelId;
+
+use sushii_model::model::sql::ModLogEntry;
+
+use crate::model::{
+    template::{escape_markdown_str, truncate_str},
+    Event, RuleContext,
+};
+
+/// Max length of an embed field value
+const FIELD_MAX_LEN: usize = 1024;
+
+/// Posts a case created by a rule to the guild's mod log channel, then saves
+/// it as no longer pending along with the ID of the log message. Cases are
+/// finalized without a message ID if the guild doesn't have a mod log channel.
+/// If the post fails the error is returned and the case is left pending, so it
+/// isn't recorded as posted.
+pub async fn post_case(
+    ctx: &RuleContext<'_>,
+    event: &Event,
+    mut entry: ModLogEntry,
+) -> Result<ModLogEntry> {
+    let message = trigger_message(event);
+
+    if let Some(message) = message {
+        entry.attachments = message.attachments.iter().map(|a| a.url.clone()).collect();
+    }
+
+    let log_channel = ctx
+        .guild_config
+        .log_mod
+        .filter(|_| ctx.guild_config.log_mod_enabled);
+
+    if let Some(channel_id) = log_channel {
+        let embed = case_embed(&entry, &ctx.rule_name, message);
+
+        let msg_id = post_embed(ctx, channel_id as u64, embed)
+            .await
+            .map_err(|e| {
+                metrics::increment_counter!("mod_log_post_failed");
+                tracing::warn!(
+                    guild_id = entry.guild_id,
+                    case_id = entry.case_id,
+                    "Failed to post mod log case: {}",
+                    e
+                );
+
+                e
+            })?;
+
+        entry.msg_id.replace(msg_id as i64);
+    }
+
+    entry.pending = false;
+
+    Ok(entry.save_exec(&ctx.pg_pool).await?)
+}
+
+/// Posts an embed to a channel, returning the new message ID
+async fn post_embed(ctx: &RuleContext<'_>, channel_id: u64, embed: Embed) -> Result<u64> {
+    let msg = ctx
+        .http
+        .create_message(ChannelId(channel_id))
+        .embed(embed)?
+        .await?;
+
+    Ok(msg.id.0)
+}
+
+/// Message that caused the rule to run, if there is one
+fn trigger_message(event: &Event) -> Option<&Message> {
+    match event {
+        Event::Twilight(DispatchEvent::MessageCreate(msg))
+        | Event::Counter {
+            original_event: DispatchEvent::MessageCreate(msg),
+            ..
+        } => Some(&msg.0),
+        Event::LevelUp { message, .. } => Some(message.as_ref()),
+        _ => None,
+    }
+}
+
+fn field(name: &str, value: String) -> EmbedField {
+    EmbedField {
+        inline: false,
+        name: name.to_string(),
+        value: truncate_str(&value, FIELD_MAX_LEN, "…"),
+    }
+}
+
+/// Case embed in the same format as cases from moderator commands, with the
+/// rule as the executor
+pub fn case_embed(entry: &ModLogEntry, rule_name: &str, message: Option<&Message>) -> Embed {
+    let mut fields = vec![
+        field("User", format!("<@{}> ({})", entry.user_id, entry.user_id)),
+        field("Executor", format!("Automod rule: {}", rule_name)),
+        field(
+            "Reason",
+            entry
+                .reason
+                .clone()
+                .unwrap_or_else(|| "No reason given".to_string()),
+        ),
+    ];
+
+    if let Some(message) = message {
+        let content = if message.content.is_empty() {
+            "*No content*".to_string()
+        } else {
+            escape_markdown_str(&message.content)
+        };
+
+        fields.push(field(
+            "Message",
+            format!("In <#{}>\n{}", message.channel_id.0, content),
+        ));
+
+        if !message.attachments.is_empty() {
+            let urls: Vec<&str> = message.attachments.iter().map(|a| a.url.as_str()).collect();
+
+            fields.push(field("Attachments", urls.join("\n")));
+        }
+    }
+
+    Embed {
+        author: Some(EmbedAuthor {
+            icon_url: None,
+            name: Some(format!("{} ({})", entry.user_tag, entry.user_id)),
+            proxy_icon_url: None,
+            url: None,
+        }),
+        color: Some(entry.color()),
+        description: None,
+        fields,
+        footer: Some(EmbedFooter {
+            icon_url: None,
+            proxy_icon_url: None,
+            text: format!("Case #{}", entry.case_id),
+        }),
+        image: None,
+        kind: "rich".to_string(),
+        provider: None,
+        thumbnail: None,
+        timestamp: Some(format!(
+            "{}Z",
+            entry.action_time.format("%Y-%m-%dT%H:%M:%S")
+        )),
+        title: Some(capitalize(&entry.action)),
+        url: None,
+        video: None,
+    }
+}
+
+fn capitalize(s: &str) -> String {
+    let mut chars = s.chars();
+
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn embed_uses_case_color_and_rule() {
+        let entry = ModLogEntry::new("mute", true, 1, 2, "user#0001").reason(&Some("spam".into()));
+        let embed = case_embed(&entry, "Anti spam", None);
+
+        assert_eq!(embed.color, Some(0xe67e22));
+        assert_eq!(embed.title.as_deref(), Some("Mute"));
+        assert_eq!(embed.fields[1].value, "Automod rule: Anti spam");
+        assert_eq!(embed.fields[2].value, "spam");
+        assert_eq!(embed.fields.len(), 3);
+    }
+}
@@ -1,17 +1,26 @@
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use sqlx::types::Json;
 use sqlx::types::Uuid;
 use std::error::Error;
 use std::result::Result as StdResult;
 use std::sync::Arc;
 
-use crate::error::Result;
-use crate::model::{Action, Condition, Event, RuleContext, Trigger};
+use sushii_model::model::sql::{RuleActionResult, RuleScope};
+
+use crate::error::{self, is_transient, Result};
+use crate::migration;
+use crate::model::has_id::*;
+use crate::model::{
+    config::{ConfigGet, DurationVar},
+    Action, Condition, Event, RuleContext, Trigger,
+};
+use crate::schema::SCHEMA_VERSION;
 
 #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct Rule {
-    #[schemars(skip)]
+    #[serde(default)]
     pub id: i64,
     /// Name of this rule
     pub name: String,
@@ -25,6 +34,64 @@ pub struct Rule {
     /// # Actions
     /// Actions are executed sequentially if condition passes
     pub actions: Vec<Action>,
+    /// # Cooldown
+    /// Suppresses repeat runs of this rule
+    #[serde(default)]
+    pub cooldown: Option<RuleCooldown>,
+    /// # Priority
+    /// Rules with a higher priority run first, rules with the same priority
+    /// run in the order they are listed
+    #[serde(default)]
+    pub priority: i32,
+    /// # Stop processing
+    /// Skips lower priority rules for the same event if this rule's
+    /// conditions pass
+    #[serde(default)]
+    pub stop_processing: bool,
+}
+
+/// Skips a rule if it already ran in the same guild, channel or for the same
+/// user within the duration
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct RuleCooldown {
+    /// What the cooldown applies to
+    pub scope: CooldownScope,
+    /// # Duration
+    /// How long to suppress the rule after it runs
+    pub duration: DurationVar,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub enum CooldownScope {
+    Guild,
+    Channel,
+    User,
+}
+
+impl From<CooldownScope> for RuleScope {
+    fn from(scope: CooldownScope) -> Self {
+        match scope {
+            CooldownScope::Guild => Self::Guild,
+            CooldownScope::Channel => Self::Channel,
+            CooldownScope::User => Self::User,
+        }
+    }
+}
+
+impl RuleCooldown {
+    /// Starts the cooldown for a rule, returns false if the rule is already on
+    /// cooldown from a different event
+    pub async fn start(&self, rule_id: i64, event: &Event, ctx: &RuleContext<'_>) -> Result<bool> {
+        let guild_id = event.guild_id()?;
+        let scope_id = event.scope_id(self.scope.into())?;
+        let key = format!("{}:{}:{:?}:{}", guild_id.0, rule_id, self.scope, scope_id);
+
+        let duration = self.duration.get(ctx)?.to_std().unwrap_or_default();
+
+        ctx.rate_limits
+            .start_cooldown(&key, &ctx.event_id, duration)
+            .await
+    }
 }
 
 impl Rule {
@@ -41,12 +108,23 @@ impl Rule {
             return Ok(false);
         }
 
-        let passes_conditions = self.conditions.check_event(event.clone(), ctx).await?;
+        let passes_conditions = ctx
+            .check_condition_once(&self.conditions, event.clone(), "conditions")
+            .await?;
 
         if !passes_conditions {
             return Ok(false);
         }
 
+        if let Some(cooldown) = &self.cooldown {
+            if !cooldown.start(self.id, &event, ctx).await? {
+                metrics::increment_counter!("rule_suppressed", "reason" => "cooldown");
+                tracing::debug!(rule_id = self.id, "Rule is on cooldown");
+
+                return Ok(false);
+            }
+        }
+
         metrics::increment_counter!("rule_triggered", "event_name" => event
             .kind()
             .ok()
@@ -57,27 +135,74 @@ impl Rule {
         tracing::debug!("Rule triggered on {:?}", event.kind().map(|k| k.name()));
 
         // Run all actions in order if passes conditions
-        for action in &self.actions {
-            action.execute(event.clone(), &mut ctx).await?;
+        for (i, action) in self.actions.iter().enumerate() {
+            ctx.action_path = vec![i.to_string()];
+            let res = action.execute(event.clone(), &mut ctx).await;
+
+            ctx.action_results.push(RuleActionResult {
+                action: action.name().to_string(),
+                error: res.as_ref().err().map(|e| e.to_string()),
+            });
+
+            if let Err(e) = res {
+                return Err(Box::new(error::Error::ActionFailed {
+                    action: action.name(),
+                    message: e.to_string(),
+                    transient: e.chain().any(is_transient),
+                }));
+            }
         }
 
         Ok(true)
     }
 
+    /// Disables a rule owned by a guild and saves the reason, rules in global
+    /// rule sets aren't changed
+    pub async fn disable(
+        pool: &sqlx::PgPool,
+        guild_id: u64,
+        rule_id: i64,
+        reason: &str,
+    ) -> Result<()> {
+        sqlx::query!(
+            r#"update app_public.guild_rules
+                  set enabled = false,
+                      disabled_reason = $3
+                where id = $1
+                  and set_id in (select id
+                                   from app_public.guild_rule_sets
+                                  where guild_id = $2)
+            "#,
+            rule_id,
+            guild_id as i64,
+            reason,
+        )
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
     pub async fn from_set_id(pool: &sqlx::PgPool, set_id: i64) -> Result<Vec<Rule>> {
         let db_rules = RuleDb::from_set_id(pool, set_id).await?;
 
         let mut rules = Vec::new();
 
-        for rule in db_rules {
-            rules.push(Rule {
-                id: rule.id,
-                name: rule.name,
-                enabled: rule.enabled,
-                trigger: rule.trigger.0,
-                conditions: rule.conditions.0,
-                actions: rule.actions.0,
-            });
+        for mut rule in db_rules {
+            let id = rule.id;
+
+            // Upgraded rules are saved so they're only migrated once
+            if rule.migrate() {
+                if let Err(e) = rule.save_migrated(pool).await {
+                    tracing::warn!(rule_id = id, "Failed to save migrated rule: {}", e);
+                }
+            }
+
+            match rule.into_rule() {
+                Ok(rule) => rules.push(rule),
+                // Skip the rule instead of the whole set
+                Err(e) => tracing::warn!(rule_id = id, "Invalid rule: {}", e),
+            }
         }
 
         Ok(rules)
@@ -93,13 +218,69 @@ struct RuleDb {
     pub enabled: bool,
     /// Event that triggers this rule
     pub trigger: Json<Trigger>,
+    /// Version of the conditions and actions JSON, older rules are migrated
+    /// before deserializing
+    pub schema_version: i32,
     /// Conditions that need to pass before running actions
-    pub conditions: Json<Condition>,
+    pub conditions: Json<Value>,
     /// Actions are executed sequentially if condition passes
-    pub actions: Json<Vec<Action>>,
+    pub actions: Json<Value>,
+    pub cooldown: Option<Json<RuleCooldown>>,
+    pub priority: i32,
+    pub stop_processing: bool,
 }
 
 impl RuleDb {
+    /// Upgrades the conditions and actions to the current schema version,
+    /// returns true if the rule was from an older version
+    fn migrate(&mut self) -> bool {
+        if self.schema_version as u32 >= SCHEMA_VERSION {
+            return false;
+        }
+
+        migration::migrate(&mut self.conditions.0, self.schema_version as u32);
+        migration::migrate(&mut self.actions.0, self.schema_version as u32);
+        self.schema_version = SCHEMA_VERSION as i32;
+
+        true
+    }
+
+    /// Saves migrated conditions and actions, rules already upgraded by
+    /// another instance are left as is
+    async fn save_migrated(&self, pool: &sqlx::PgPool) -> Result<()> {
+        sqlx::query!(
+            r#"update app_public.guild_rules
+                  set conditions = $2,
+                      actions = $3,
+                      schema_version = $4
+                where id = $1
+                  and schema_version < $4
+            "#,
+            self.id,
+            &self.conditions as _,
+            &self.actions as _,
+            self.schema_version,
+        )
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
+    fn into_rule(self) -> serde_json::Result<Rule> {
+        Ok(Rule {
+            id: self.id,
+            name: self.name,
+            enabled: self.enabled,
+            trigger: self.trigger.0,
+            conditions: serde_json::from_value(self.conditions.0)?,
+            actions: serde_json::from_value(self.actions.0)?,
+            cooldown: self.cooldown.map(|c| c.0),
+            priority: self.priority,
+            stop_processing: self.stop_processing,
+        })
+    }
+
     pub async fn from_set_id(pool: &sqlx::PgPool, set_id: i64) -> Result<Vec<RuleDb>> {
         sqlx::query_as!(
             RuleDb,
@@ -107,8 +288,12 @@ impl RuleDb {
                       name,
                       enabled,
                       trigger as "trigger!: Json<Trigger>",
-                      conditions as "conditions!: Json<Condition>",
-                      actions as "actions!: Json<Vec<Action>>"
+                      schema_version as "schema_version!: i32",
+                      conditions as "conditions!: Json<Value>",
+                      actions as "actions!: Json<Value>",
+                      cooldown as "cooldown: Json<RuleCooldown>",
+                      priority as "priority!: i32",
+                      stop_processing as "stop_processing!: bool"
                  from app_public.guild_rules
                 where set_id = $1
             "#,
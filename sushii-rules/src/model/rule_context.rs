@@ -1,19 +1,34 @@
 use aho_corasick::AhoCorasick;
 use anyhow::Result;
 use handlebars::Handlebars;
+use lingua::Language;
 use serde::Serialize;
 use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
 use std::hash::Hasher;
 use std::sync::Arc;
-use tokio::sync::mpsc::Sender;
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedSender;
 use tokio::sync::RwLock;
 use twilight_http::client::Client;
 use twilight_model::id::GuildId;
 
-use sushii_model::model::sql::GuildConfig;
+use sushii_model::model::sql::{GuildConfig, RuleActionResult};
 
-use crate::model::{Event, RuleConfig};
+use crate::model::{
+    cache::{DiscordCache, MessageHistory},
+    engine::{ActionBudget, BudgetMode},
+    has_id::HasGuildId,
+    language::LanguageDetector,
+    template::{render_bounded, RenderBudget},
+    Condition, Event, RuleConfig,
+};
+use crate::persistence::{counter::CounterStore, EventProgress, RateLimitStore};
+
+/// Longest a queued action waits in total for the action budget before it is
+/// dropped. Actions wait inside a worker and block its other events, so this
+/// should stay short.
+const MAX_BUDGET_WAIT: Duration = Duration::from_secs(5);
 
 #[derive(Debug, Default, Clone, Serialize)]
 pub struct RuleContextData {
@@ -28,6 +43,34 @@ pub struct RuleContextData {
 // Word list only in a single guild
 type GuildWordList = Arc<RwLock<HashMap<String, AhoCorasick>>>;
 
+/// Clients, stores and caches shared by every rule context, owned by the
+/// engine or a rule test run
+#[derive(Debug, Clone)]
+pub struct EngineHandles<'a> {
+    /// Twilight HTTP client
+    pub http: Client,
+    /// Postgres database pool
+    pub pg_pool: sqlx::PgPool,
+    /// Rule counters
+    pub counters: Arc<dyn CounterStore>,
+    /// Rule cooldowns and action budgets
+    pub rate_limits: Arc<dyn RateLimitStore>,
+    pub action_budget: Option<ActionBudget>,
+    pub reqwest: reqwest::Client,
+    /// Detects languages for language constraints
+    pub language_detector: Arc<dyn LanguageDetector>,
+    /// Shared handlebars template to prevent reparsing
+    /// This is a RwLock since registering templates requires mut self
+    pub handlebars_templates: Arc<RwLock<Handlebars<'a>>>,
+    /// Recent message hashes per user for duplicate message checks
+    pub message_history: MessageHistory,
+    /// Guild data from the gateway, updated with every gateway event in the
+    /// order they are received before they are queued on workers
+    pub discord_cache: DiscordCache,
+    /// Counter events sent by rules, these are queued on the worker pool
+    pub channel_tx: UnboundedSender<Event>,
+}
+
 /// This is shared to be accessed in the rules parsing.
 /// Created each time an event fires.
 #[derive(Debug, Clone)]
@@ -35,35 +78,150 @@ pub struct RuleContext<'a> {
     pub guild_config: Arc<GuildConfig>,
     pub http: Client,
     pub pg_pool: sqlx::PgPool,
+    pub counters: Arc<dyn CounterStore>,
+    pub rate_limits: Arc<dyn RateLimitStore>,
+    pub action_budget: Option<ActionBudget>,
+    /// Actions that already ran for the event and its condition results,
+    /// shared by every rule and attempt of the event
+    pub progress: Arc<EventProgress>,
     pub reqwest: reqwest::Client,
-    pub language_client: language_api_wrapper::LanguageApiClient,
+    pub language_detector: Arc<dyn LanguageDetector>,
+    /// Languages referenced by the guild's rules, language detection only
+    /// picks from these
+    pub languages: Arc<Vec<Language>>,
     pub handlebars_templates: Arc<RwLock<Handlebars<'a>>>,
     pub word_lists: Option<GuildWordList>,
+    pub message_history: MessageHistory,
+    /// Guilds, channels, roles and members from the gateway
+    pub discord_cache: DiscordCache,
     pub data: RuleContextData,
-    pub channel_tx: Sender<Event>,
+    pub channel_tx: UnboundedSender<Event>,
+    /// ID of the rule being run, passed along in counter events
+    pub rule_id: i64,
+    pub rule_name: String,
+    pub rule_set_id: i64,
+    /// ID of the event from its source, the same when the event is retried
+    pub event_id: String,
+    /// Path of the running action in the rule's actions, e.g. `2/actions/0`
+    /// for the first action in the third action's sub condition. Used for
+    /// action idempotency keys so they stay the same when the event is
+    /// retried.
+    pub action_path: Vec<String>,
+    /// Results of each action that ran, saved in the rule execution log
+    pub action_results: Vec<RuleActionResult>,
+    /// Names of every action that ran, including actions in sub conditions
+    pub actions_run: Vec<&'static str>,
+    /// Skip requests to Discord, e.g. when running rule tests. Templates are
+    /// still rendered.
+    pub dry_run: bool,
 }
 
 impl<'a> RuleContext<'a> {
+    /// Context for a rule in a guild, `languages` are the languages
+    /// referenced by the guild's rules
     pub fn new(
+        handles: &EngineHandles<'a>,
         guild_config: Arc<GuildConfig>,
-        http: Client,
-        pg_pool: sqlx::PgPool,
-        reqwest: reqwest::Client,
-        language_client: language_api_wrapper::LanguageApiClient,
-        handlebars_templates: Arc<RwLock<Handlebars<'a>>>,
+        progress: Arc<EventProgress>,
         word_lists: Option<GuildWordList>,
-        channel_tx: Sender<Event>,
+        languages: Arc<Vec<Language>>,
     ) -> Self {
+        let handles = handles.clone();
+
         Self {
             guild_config,
-            http,
-            pg_pool,
-            reqwest,
-            language_client,
-            handlebars_templates,
+            http: handles.http,
+            pg_pool: handles.pg_pool,
+            counters: handles.counters,
+            rate_limits: handles.rate_limits,
+            action_budget: handles.action_budget,
+            progress,
+            reqwest: handles.reqwest,
+            language_detector: handles.language_detector,
+            languages,
+            handlebars_templates: handles.handlebars_templates,
             word_lists,
+            message_history: handles.message_history,
+            discord_cache: handles.discord_cache,
             data: RuleContextData::default(),
-            channel_tx,
+            channel_tx: handles.channel_tx,
+            rule_id: 0,
+            rule_name: String::new(),
+            rule_set_id: 0,
+            event_id: String::new(),
+            action_path: Vec::new(),
+            action_results: Vec::new(),
+            actions_run: Vec::new(),
+            dry_run: false,
+        }
+    }
+
+    /// Key of the running action in the event's progress
+    pub fn action_key(&self) -> String {
+        format!("{}:{}", self.rule_id, self.action_path.join("/"))
+    }
+
+    /// Checks a condition once per event, retried events reuse the first
+    /// result. Earlier actions may have changed counters or roles the
+    /// condition depends on, which would otherwise skip or change the actions
+    /// left to run.
+    pub async fn check_condition_once(
+        &self,
+        condition: &Condition,
+        event: Arc<Event>,
+        name: &str,
+    ) -> crate::error::Result<bool> {
+        let key = format!("{}:{}", self.rule_id, name);
+
+        if let Some(passed) = self.progress.condition_result(&key) {
+            tracing::debug!(%key, passed, "Condition already checked for event");
+            return Ok(passed);
+        }
+
+        let passed = condition.check_event(event, self).await?;
+        self.progress.save_condition_result(&key, passed);
+
+        Ok(passed)
+    }
+
+    /// Detects the language of text out of the languages the guild's rules
+    /// reference
+    pub async fn detect_language(&self, text: &str) -> crate::error::Result<Option<Language>> {
+        self.language_detector.detect(text, &self.languages).await
+    }
+
+    /// Uses one action from the guild's action budget, queued actions wait for
+    /// the budget to reset. Returns false if the action should be dropped.
+    pub async fn take_action_budget(&self, event: &Event) -> Result<bool> {
+        let budget = match self.action_budget {
+            Some(b) => b,
+            None => return Ok(true),
+        };
+
+        let guild_id = event.guild_id()?;
+        let mut waited = Duration::from_secs(0);
+
+        loop {
+            let retry_after = match self
+                .rate_limits
+                .take_action(guild_id.0, budget.limit, budget.window)
+                .await?
+            {
+                Some(d) => d,
+                None => return Ok(true),
+            };
+
+            if budget.mode == BudgetMode::Drop || waited + retry_after > MAX_BUDGET_WAIT {
+                metrics::increment_counter!("rule_suppressed", "reason" => "action_budget");
+                tracing::debug!(guild_id = guild_id.0, "Action budget exceeded");
+
+                return Ok(false);
+            }
+
+            metrics::increment_counter!("rule_action_queued");
+            waited += retry_after;
+
+            tokio::time::sleep(retry_after).await;
         }
     }
 
@@ -91,10 +249,8 @@ impl<'a> RuleContext<'a> {
 
         tracing::debug!("Rendering template with context: {:?}", self.data);
 
-        self.handlebars_templates
-            .read()
-            .await
-            .render(&hash, &self.data)
-            .map_err(Into::into)
+        let templates = self.handlebars_templates.read().await;
+
+        render_bounded(&templates, &hash, &self.data, RenderBudget::default()).map_err(Into::into)
     }
 }
@@ -7,17 +7,27 @@ use sqlx::types::Uuid;
 use std::collections::HashMap;
 
 use crate::error::Result;
-use crate::model::Rule;
+use crate::migration::UNVERSIONED;
+use crate::model::{
+    config::{resolve_config, ConfigParam},
+    rule_test::RuleTest,
+    Rule,
+};
+use crate::schema::SCHEMA_VERSION;
 
 const RULE_SET_TIMEOUT_SECS: usize = 30;
 
 /// Rule set used in engine and front end schema
 #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct RuleSet {
-    #[schemars(skip)]
+    #[serde(default)]
     pub id: i64,
+    /// Version of the rule JSON format, older rule sets are migrated when
+    /// loaded
+    #[serde(default = "default_schema_version")]
+    pub schema_version: u32,
     /// Guild ID this rule set belongs to
-    #[schemars(skip)]
+    #[serde(default)]
     pub guild_id: Option<i64>,
     /// Name of this rule set, should be the feature name
     pub name: String,
@@ -33,11 +43,23 @@ pub struct RuleSet {
     /// Rule set category, e.g. moderation, fun, etc.
     pub category: Option<String>,
     /// Rule set configuration, map of json values
-    #[schemars(skip)]
+    #[serde(default)]
     pub config: HashMap<String, Value>,
+    /// Config parameters this rule set expects each guild to set, along with
+    /// their defaults
+    #[serde(default)]
+    pub config_schema: Vec<ConfigParam>,
     /// List of rules in this rule set
-    #[schemars(skip)]
+    #[serde(default)]
     pub rules: Vec<Rule>,
+    /// Test cases run with `RuleSet::run_tests`
+    #[serde(default)]
+    pub tests: Vec<RuleTest>,
+}
+
+/// Rule sets without a version are from before versions were added
+fn default_schema_version() -> u32 {
+    UNVERSIONED
 }
 
 impl RuleSet {
@@ -57,9 +79,13 @@ impl RuleSet {
 
         if let Some(cached_sets_str) = cached_sets_str {
             tracing::debug!("Found cached rule set: {}", redis_key);
-            let cached_sets = serde_json::from_str(&cached_sets_str)?;
 
-            return Ok(cached_sets);
+            // Sets cached by an older version may not deserialize, these are
+            // refetched instead
+            match serde_json::from_str(&cached_sets_str) {
+                Ok(cached_sets) => return Ok(cached_sets),
+                Err(e) => tracing::warn!("Invalid cached rule set {}: {}", redis_key, e),
+            }
         }
 
         tracing::debug!("Rule set not cached: {}", redis_key);
@@ -83,8 +109,29 @@ impl RuleSet {
         let mut rule_sets = Vec::new();
 
         for set in rule_sets_db {
+            let config_schema = set.config_schema.map_or_else(Vec::new, |s| s.0);
+            let config = set.config.map_or_else(|| HashMap::new(), |c| c.0);
+
+            // Guild configs that don't match the declared config would only
+            // fail when rules run, so skip the whole rule set instead
+            let config = match resolve_config(&config_schema, &config) {
+                Ok(config) => config,
+                Err(errors) => {
+                    let messages: Vec<String> = errors.iter().map(ToString::to_string).collect();
+                    tracing::warn!(
+                        set_id = set.id,
+                        guild_id = ?set.guild_id,
+                        "Invalid rule set config, skipping rule set: {}",
+                        messages.join(", ")
+                    );
+
+                    continue;
+                }
+            };
+
             let set = Self {
                 id: set.id,
+                schema_version: SCHEMA_VERSION,
                 guild_id: set.guild_id,
                 name: set.name,
                 description: set.description,
@@ -92,8 +139,10 @@ impl RuleSet {
                 editable: set.editable,
                 author: set.author,
                 category: set.category,
-                config: set.config.map_or_else(|| HashMap::new(), |c| c.0),
+                config,
+                config_schema,
                 rules: Rule::from_set_id(pool, set.id).await?,
+                tests: Vec::new(),
             };
 
             rule_sets.push(set);
@@ -124,6 +173,8 @@ struct RuleSetDb {
     pub category: Option<String>,
     /// Rule set configuration, map of json values
     pub config: Option<Json<HashMap<String, Value>>>,
+    /// Declared config parameters
+    pub config_schema: Option<Json<Vec<ConfigParam>>>,
 }
 
 impl RuleSetDb {
@@ -140,10 +191,12 @@ impl RuleSetDb {
                       editable as "editable!: bool",
                       author,
                       category,
-                      config as "config: Json<HashMap<String, Value>>"
+                      config as "config: Json<HashMap<String, Value>>",
+                      config_schema as "config_schema: Json<Vec<ConfigParam>>"
                from app_public.guild_rule_sets s
                     left join app_public.guild_rule_set_configs c
                            on s.id = c.set_id
+                          and c.guild_id = $1
               where (s.enabled = true and (c.enabled is null or c.enabled = true))
                 and (s.guild_id = $1 or s.guild_id is null)
             "#,
@@ -0,0 +1,423 @@
+use aho_corasick::AhoCorasick;
+use chrono::Utc;
+use schemars::JsonSchema;
+use serde::{de::DeserializeSeed, Deserialize, Serialize};
+use serde_json::json;
+use sqlx::types::Json;
+use std::cmp::Reverse;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use tokio::sync::{mpsc, RwLock};
+use twilight_http::client::Client;
+use twilight_model::gateway::event::{DispatchEvent, DispatchEventWithTypeDeserializer};
+
+use sushii_model::model::sql::{GuildConfig, RuleScope};
+
+use crate::error::Result;
+use crate::model::{
+    cache::{DiscordCache, MessageHistory},
+    config::resolve_config,
+    language::{referenced_languages, CachedDetector, LinguaDetector},
+    template, EngineHandles, Event, Rule, RuleContext, RuleSet,
+};
+use crate::persistence::counter::{CounterKey, MemoryCounterStore};
+use crate::persistence::{EventProgress, MemoryRateLimitStore};
+
+/// Guild used for test messages in rule sets without a guild
+const TEST_GUILD_ID: u64 = 1;
+const TEST_CHANNEL_ID: u64 = 2;
+
+/// Tests never connect to Postgres, conditions that need it fail the test
+const OFFLINE_DATABASE_URL: &str = "postgres://localhost/sushii_rules_test";
+
+/// Same as the engine default
+const MAX_COUNTER_DEPTH: usize = 5;
+
+/// First second of 2015, Discord IDs count milliseconds from this
+const DISCORD_EPOCH_MS: i64 = 1_420_070_400_000;
+
+/// Test case for a rule set, a message along with what the rules should do
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct RuleTest {
+    /// # Name
+    pub name: String,
+    /// # Message
+    /// Message sent in the test
+    pub message: TestMessage,
+    /// # Word lists
+    /// Word lists available to the rules, by name
+    #[serde(default)]
+    pub word_lists: HashMap<String, Vec<String>>,
+    /// # Expect
+    pub expect: TestExpectation,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct TestMessage {
+    /// # Content
+    pub content: String,
+    /// # Author roles
+    /// Role IDs of the author
+    #[serde(default)]
+    pub author_roles: Vec<u64>,
+    /// # Account age
+    /// Seconds since the author's account was created
+    #[serde(default = "default_account_age")]
+    pub account_age: u64,
+    /// # Author is bot
+    #[serde(default)]
+    pub author_bot: bool,
+    /// # Counters
+    /// Counter values before the message is sent
+    #[serde(default)]
+    pub counters: Vec<TestCounter>,
+}
+
+fn default_account_age() -> u64 {
+    60 * 60 * 24 * 365
+}
+
+/// Counter value for the test message, e.g. the author's `User` counter
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct TestCounter {
+    pub name: String,
+    pub scope: RuleScope,
+    /// Required for role scoped counters
+    #[serde(default)]
+    pub role_id: Option<u64>,
+    pub value: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct TestExpectation {
+    /// # Fires
+    /// If any rule should pass its conditions
+    pub fires: bool,
+    /// # Actions
+    /// Names of the actions that should run in order, including counter
+    /// events, e.g. `["AddCounter", "Reply"]`. Not checked if unset.
+    #[serde(default)]
+    pub actions: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RuleTestResult {
+    pub name: String,
+    pub passed: bool,
+    pub fired: bool,
+    /// Names of the actions that ran
+    pub actions: Vec<String>,
+    /// Rules that failed with an error, these fail the test
+    pub errors: Vec<String>,
+}
+
+impl TestMessage {
+    /// Message create event with IDs generated from the current time, the
+    /// author's ID is from their account age
+    fn event(&self, guild_id: u64) -> Result<Event> {
+        let now = Utc::now();
+        let created_ms = now.timestamp_millis() - self.account_age as i64 * 1000;
+
+        let payload = json!({
+            "id": snowflake(now.timestamp_millis()).to_string(),
+            "channel_id": TEST_CHANNEL_ID.to_string(),
+            "guild_id": guild_id.to_string(),
+            "author": {
+                "id": snowflake(created_ms).to_string(),
+                "username": "test",
+                "discriminator": "0001",
+                "avatar": null,
+                "bot": self.author_bot,
+            },
+            "member": {
+                "roles": self.author_roles.iter().map(ToString::to_string).collect::<Vec<_>>(),
+                "joined_at": now.to_rfc3339(),
+                "deaf": false,
+                "mute": false,
+            },
+            "content": self.content,
+            "timestamp": now.to_rfc3339(),
+            "edited_timestamp": null,
+            "tts": false,
+            "mention_everyone": false,
+            "mentions": [],
+            "mention_roles": [],
+            "attachments": [],
+            "embeds": [],
+            "pinned": false,
+            "type": 0,
+        });
+
+        let event =
+            DispatchEventWithTypeDeserializer::new("MESSAGE_CREATE").deserialize(payload)?;
+
+        Ok(Event::Twilight(event))
+    }
+}
+
+fn snowflake(ms: i64) -> u64 {
+    ((ms - DISCORD_EPOCH_MS).max(0) as u64) << 22
+}
+
+impl RuleTest {
+    /// Runs the test without connecting to anything. Counters, cooldowns and
+    /// word lists are in memory and Discord actions are only checked, not
+    /// sent.
+    pub async fn run(&self, rule_set: &RuleSet) -> Result<RuleTestResult> {
+        let guild_id = rule_set.guild_id.map_or(TEST_GUILD_ID, |id| id as u64);
+        let event = self.message.event(guild_id)?;
+
+        let counters = Arc::new(MemoryCounterStore::new());
+        for counter in &self.message.counters {
+            let key =
+                CounterKey::from_event(&event, counter.scope, counter.role_id, &counter.name)?;
+            counters.set(&key, counter.value);
+        }
+
+        let word_lists: HashMap<String, AhoCorasick> = self
+            .word_lists
+            .iter()
+            .map(|(name, words)| (name.clone(), AhoCorasick::new(words)))
+            .collect();
+        let word_lists = Arc::new(RwLock::new(word_lists));
+
+        let rule_config = resolve_config(&rule_set.config_schema, &rule_set.config)
+            .unwrap_or_else(|_| rule_set.config.clone());
+
+        let guild_config = Arc::new(GuildConfig::new(guild_id as i64));
+        let languages = Arc::new(referenced_languages(&rule_set.rules));
+        let (channel_tx, mut channel_rx) = mpsc::unbounded_channel();
+
+        let handles = EngineHandles {
+            http: Client::new(String::new()),
+            pg_pool: sqlx::PgPool::connect_lazy(OFFLINE_DATABASE_URL)?,
+            counters,
+            rate_limits: Arc::new(MemoryRateLimitStore::new()),
+            action_budget: None,
+            reqwest: reqwest::Client::new(),
+            language_detector: Arc::new(CachedDetector::new(LinguaDetector::new())),
+            handlebars_templates: Arc::new(RwLock::new(template::registry(false))),
+            message_history: MessageHistory::new(),
+            discord_cache: DiscordCache::new(),
+            channel_tx,
+        };
+
+        if let Event::Twilight(e) = &event {
+            handles.discord_cache.update(e);
+        }
+
+        if let Event::Twilight(DispatchEvent::MessageCreate(msg)) = &event {
+            handles.message_history.record(msg);
+        }
+
+        let mut queue = VecDeque::new();
+        queue.push_back(Arc::new(event));
+
+        let mut fired = false;
+        let mut actions = Vec::new();
+        let mut errors = Vec::new();
+
+        let mut event_index = 0;
+
+        while let Some(event) = queue.pop_front() {
+            // Counter events are separate events with their own IDs in the
+            // engine, so conditions and actions aren't shared between them
+            let event_id = format!("{}:{}", self.name, event_index);
+            let progress = Arc::new(EventProgress::new());
+            event_index += 1;
+
+            if let Event::Counter { cause, .. } = event.as_ref() {
+                if cause.is_cycle() || cause.depth() > MAX_COUNTER_DEPTH {
+                    errors.push(format!("Counter events loop: {}", cause.describe()));
+                    continue;
+                }
+            }
+
+            let kind = event.kind()?;
+            let mut rules: Vec<&Rule> = rule_set
+                .rules
+                .iter()
+                .filter(|rule| rule.enabled && rule.trigger == kind)
+                .collect();
+
+            rules.sort_by_key(|rule| Reverse(rule.priority));
+
+            for rule in rules {
+                let mut ctx = RuleContext::new(
+                    &handles,
+                    guild_config.clone(),
+                    progress.clone(),
+                    Some(word_lists.clone()),
+                    languages.clone(),
+                );
+                ctx.data.rule_config = rule_config.clone();
+                ctx.rule_id = rule.id;
+                ctx.rule_name = rule.name.clone();
+                ctx.rule_set_id = rule_set.id;
+                ctx.event_id = event_id.clone();
+                ctx.dry_run = true;
+
+                let res = rule
+                    .check_event(event.clone(), &mut ctx)
+                    .await
+                    .map_err(|e| e.to_string());
+
+                actions.extend(ctx.actions_run.iter().map(ToString::to_string));
+
+                match res {
+                    Ok(true) => {
+                        fired = true;
+
+                        if rule.stop_processing {
+                            break;
+                        }
+                    }
+                    Ok(false) => {}
+                    Err(e) => errors.push(format!("Rule {:?} failed, {}", rule.name, e)),
+                }
+            }
+
+            while let Ok(counter_event) = channel_rx.try_recv() {
+                queue.push_back(Arc::new(counter_event));
+            }
+        }
+
+        let passed = errors.is_empty()
+            && fired == self.expect.fires
+            && self.expect.actions.as_ref().map_or(true, |a| *a == actions);
+
+        Ok(RuleTestResult {
+            name: self.name.clone(),
+            passed,
+            fired,
+            actions,
+            errors,
+        })
+    }
+}
+
+impl RuleSet {
+    /// Loads the test cases of a rule set saved in the database. Rule sets
+    /// used by the engine are loaded without them.
+    pub async fn load_tests(&mut self, pool: &sqlx::PgPool) -> Result<()> {
+        let row = sqlx::query!(
+            r#"select tests as "tests: Json<Vec<RuleTest>>"
+                 from app_public.guild_rule_sets
+                where id = $1
+            "#,
+            self.id,
+        )
+        .fetch_optional(pool)
+        .await?;
+
+        self.tests = row.and_then(|r| r.tests).map_or_else(Vec::new, |t| t.0);
+
+        Ok(())
+    }
+
+    /// Runs every test case of this rule set offline, e.g. in CI or when a
+    /// rule set is saved
+    pub async fn run_tests(&self) -> Result<Vec<RuleTestResult>> {
+        let mut results = Vec::with_capacity(self.tests.len());
+
+        for test in &self.tests {
+            results.push(test.run(self).await?);
+        }
+
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule_set(tests: serde_json::Value) -> RuleSet {
+        serde_json::from_value(json!({
+            "name": "Spam",
+            "enabled": true,
+            "editable": true,
+            "description": null,
+            "author": null,
+            "category": null,
+            "rules": [{
+                "name": "No links",
+                "enabled": true,
+                "trigger": "MessageCreate",
+                "conditions": {
+                    "Condition": {
+                        "message": { "content": { "contains": { "value": "http" } } }
+                    }
+                },
+                "actions": [{ "Reply": { "content": "No links {{trigger.author.username}}" } }]
+            }],
+            "tests": tests,
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn runs_test_cases() {
+        let set = rule_set(json!([
+            {
+                "name": "link",
+                "message": { "content": "http://example.com" },
+                "expect": { "fires": true, "actions": ["Reply"] }
+            },
+            {
+                "name": "no link",
+                "message": { "content": "hello" },
+                "expect": { "fires": false, "actions": [] }
+            },
+            {
+                "name": "wrong expectation",
+                "message": { "content": "hello" },
+                "expect": { "fires": true }
+            }
+        ]));
+
+        let results = set.run_tests().await.unwrap();
+
+        assert!(results[0].passed, "{:?}", results[0]);
+        assert!(results[1].passed, "{:?}", results[1]);
+        assert!(!results[2].passed);
+        assert!(!results[2].fired);
+    }
+
+    #[tokio::test]
+    async fn uncached_constraints_dont_match() {
+        let set: RuleSet = serde_json::from_value(json!({
+            "name": "Channels",
+            "enabled": true,
+            "editable": true,
+            "description": null,
+            "author": null,
+            "category": null,
+            "rules": [{
+                "name": "Named channel",
+                "enabled": true,
+                "trigger": "MessageCreate",
+                "conditions": {
+                    "Or": {
+                        "or": [
+                            { "Condition": { "message": { "channel": { "name": { "contains": { "value": "spam" } } } } } },
+                            { "Condition": { "message": { "member": { "role_name": { "contains": { "value": "Mod" } } } } } }
+                        ]
+                    }
+                },
+                "actions": [{ "Reply": { "content": "hi" } }]
+            }],
+            "tests": [{
+                "name": "nothing cached",
+                "message": { "content": "hello" },
+                "expect": { "fires": false, "actions": [] }
+            }],
+        }))
+        .unwrap();
+
+        let results = set.run_tests().await.unwrap();
+
+        assert!(results[0].passed, "{:?}", results[0]);
+        assert!(results[0].errors.is_empty());
+    }
+}
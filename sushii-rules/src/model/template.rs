@@ -0,0 +1,731 @@
+use chrono::DateTime;
+use handlebars::{
+    to_json, BlockContext, BlockParams, Context, Handlebars, Helper, HelperDef, HelperResult,
+    Output, PathAndJson, RenderContext, RenderError, Renderable,
+};
+use serde::Serialize;
+use serde_json::Value;
+use std::cell::Cell;
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+use crate::error::Error;
+use crate::model::config::{value_as_duration, value_as_id};
+
+/// Discord timestamp styles, e.g. `t` for short time or `R` for relative
+const TIMESTAMP_STYLES: &[&str] = &["t", "T", "d", "D", "f", "F", "R"];
+
+/// Characters that are formatted by Discord markdown
+const MARKDOWN_CHARS: &[char] = &['\\', '*', '_', '~', '`', '|', '>'];
+
+/// Zero width space, breaks up mentions without visibly changing the text
+const ZWSP: char = '\u{200b}';
+
+/// Creates the handlebars registry used for rule templates with all Discord
+/// helpers. In strict mode, rendering fails on undefined variables instead of
+/// rendering an empty string.
+///
+/// Interpolated values, e.g. `{{trigger.content}}`, are escaped with
+/// [`escape_discord`] so user content can't format the message or ping
+/// anyone. Helper output and triple braces `{{{...}}}` are not escaped.
+pub fn registry(strict: bool) -> Handlebars<'static> {
+    let mut reg = Handlebars::new();
+    reg.set_strict_mode(strict);
+    reg.register_escape_fn(escape_discord);
+
+    reg.register_helper("mention_user", Box::new(mention_user));
+    reg.register_helper("mention_channel", Box::new(mention_channel));
+    reg.register_helper("mention_role", Box::new(mention_role));
+    reg.register_helper("user_tag", Box::new(user_tag));
+    reg.register_helper("timestamp", Box::new(timestamp));
+    reg.register_helper("relative_time", Box::new(relative_time));
+    reg.register_helper("truncate", Box::new(truncate));
+    reg.register_helper("escape_markdown", Box::new(escape_markdown));
+    reg.register_helper("pluralize", Box::new(pluralize));
+    reg.register_helper("duration", Box::new(duration));
+    reg.register_helper("each", Box::new(BoundedEach));
+
+    reg
+}
+
+/// Every helper reads its parameters through this, so it also stops renders
+/// that are past their time budget
+fn param<'a>(h: &'a Helper<'_, '_>, index: usize) -> Result<&'a Value, RenderError> {
+    check_deadline()?;
+
+    h.param(index)
+        .map(|p| p.value())
+        .filter(|v| !v.is_null())
+        .ok_or_else(|| {
+            RenderError::new(format!(
+                "Helper {} is missing parameter {}",
+                h.name(),
+                index + 1
+            ))
+        })
+}
+
+fn invalid_param(h: &Helper<'_, '_>, expected: &str) -> RenderError {
+    RenderError::new(format!("Helper {} requires {}", h.name(), expected))
+}
+
+fn id_param(h: &Helper<'_, '_>) -> Result<u64, RenderError> {
+    value_as_id(param(h, 0)?).ok_or_else(|| invalid_param(h, "an ID"))
+}
+
+fn str_param<'a>(h: &'a Helper<'_, '_>, index: usize) -> Result<&'a str, RenderError> {
+    param(h, index)?
+        .as_str()
+        .ok_or_else(|| invalid_param(h, "text"))
+}
+
+/// Unix timestamp from an RFC 3339 date, or a number of seconds
+fn timestamp_param(h: &Helper<'_, '_>) -> Result<i64, RenderError> {
+    match param(h, 0)? {
+        Value::Number(n) => n.as_i64(),
+        Value::String(s) => DateTime::parse_from_rfc3339(s)
+            .map(|d| d.timestamp())
+            .ok()
+            .or_else(|| s.parse().ok()),
+        _ => None,
+    }
+    .ok_or_else(|| invalid_param(h, "a date or unix timestamp"))
+}
+
+/// `{{mention_user id}}` -> `<@id>`
+fn mention_user(
+    h: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    out.write(&format!("<@{}>", id_param(h)?))?;
+    Ok(())
+}
+
+/// `{{mention_channel id}}` -> `<#id>`
+fn mention_channel(
+    h: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    out.write(&format!("<#{}>", id_param(h)?))?;
+    Ok(())
+}
+
+/// `{{mention_role id}}` -> `<@&id>`
+fn mention_role(
+    h: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    out.write(&format!("<@&{}>", id_param(h)?))?;
+    Ok(())
+}
+
+/// `{{user_tag user}}` -> `name#0001`
+fn user_tag(
+    h: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let user = param(h, 0)?;
+
+    let name = user
+        .get("username")
+        .or_else(|| user.get("name"))
+        .and_then(Value::as_str)
+        .ok_or_else(|| invalid_param(h, "a user"))?;
+
+    let discriminator = match user.get("discriminator") {
+        Some(Value::String(s)) => s.clone(),
+        Some(Value::Number(n)) => n.to_string(),
+        _ => return Err(invalid_param(h, "a user")),
+    };
+
+    out.write(&format!("{}#{:0>4}", name, discriminator))?;
+    Ok(())
+}
+
+/// `{{timestamp date "R"}}` -> `<t:1620000000:R>`, style defaults to `f`
+fn timestamp(
+    h: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let ts = timestamp_param(h)?;
+
+    let style = match h.param(1) {
+        Some(_) => str_param(h, 1)?,
+        None => "f",
+    };
+
+    if !TIMESTAMP_STYLES.contains(&style) {
+        return Err(invalid_param(h, "a style of t, T, d, D, f, F or R"));
+    }
+
+    out.write(&format!("<t:{}:{}>", ts, style))?;
+    Ok(())
+}
+
+/// `{{relative_time date}}` -> `<t:1620000000:R>`, e.g. "2 hours ago"
+fn relative_time(
+    h: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    out.write(&format!("<t:{}:R>", timestamp_param(h)?))?;
+    Ok(())
+}
+
+/// `{{truncate text 100}}` shortens text to at most 100 characters, ending
+/// with an ellipsis or the given suffix if it was truncated. The output is
+/// escaped like interpolated values as this is usually used on user content.
+fn truncate(
+    h: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let text = str_param(h, 0)?;
+    let max_len = param(h, 1)?
+        .as_u64()
+        .ok_or_else(|| invalid_param(h, "a length"))? as usize;
+
+    let suffix = match h.param(2) {
+        Some(_) => str_param(h, 2)?,
+        None => "…",
+    };
+
+    out.write(&escape_discord(&truncate_str(text, max_len, suffix)))?;
+    Ok(())
+}
+
+pub fn truncate_str(text: &str, max_len: usize, suffix: &str) -> String {
+    if text.chars().count() <= max_len {
+        return text.to_string();
+    }
+
+    let keep = max_len.saturating_sub(suffix.chars().count());
+    let mut truncated: String = text.chars().take(keep).collect();
+    truncated.push_str(suffix);
+
+    truncated
+}
+
+/// `{{escape_markdown text}}` escapes Discord markdown formatting and
+/// mentions, the same as interpolated values
+fn escape_markdown(
+    h: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    out.write(&escape_discord(str_param(h, 0)?))?;
+    Ok(())
+}
+
+pub fn escape_markdown_str(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        if MARKDOWN_CHARS.contains(&c) {
+            escaped.push('\\');
+        }
+
+        escaped.push(c);
+    }
+
+    escaped
+}
+
+/// Escapes markdown and breaks up user, role, @everyone and @here mentions
+pub fn escape_discord(text: &str) -> String {
+    escape_markdown_str(text)
+        .replace("<@", &format!("<{}@", ZWSP))
+        .replace("@everyone", &format!("@{}everyone", ZWSP))
+        .replace("@here", &format!("@{}here", ZWSP))
+}
+
+/// `{{pluralize count "message"}}` -> `message` or `messages`, with an
+/// optional plural form for irregular words
+fn pluralize(
+    h: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let count = param(h, 0)?
+        .as_f64()
+        .ok_or_else(|| invalid_param(h, "a count"))?;
+    let singular = str_param(h, 1)?;
+
+    if (count - 1.0).abs() < f64::EPSILON {
+        out.write(singular)?;
+    } else if h.param(2).is_some() {
+        out.write(str_param(h, 2)?)?;
+    } else {
+        out.write(&format!("{}s", singular))?;
+    }
+
+    Ok(())
+}
+
+/// `{{duration 5400}}` -> `1h 30m`
+fn duration(
+    h: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let secs = value_as_duration(param(h, 0)?)
+        .ok_or_else(|| invalid_param(h, "seconds or a duration"))?
+        .as_secs();
+
+    out.write(&humantime::format_duration(Duration::from_secs(secs)).to_string())?;
+    Ok(())
+}
+
+/// Limits on a single template render, so that a template looping over large
+/// event data can't build huge messages or hold up the event handler
+#[derive(Debug, Clone, Copy)]
+pub struct RenderBudget {
+    /// Max rendered output size in bytes
+    pub max_bytes: usize,
+    /// Max time spent rendering
+    pub max_time: Duration,
+}
+
+impl Default for RenderBudget {
+    fn default() -> Self {
+        Self {
+            max_bytes: 16 * 1024,
+            max_time: Duration::from_millis(50),
+        }
+    }
+}
+
+/// Time limit of the render running on this thread
+#[derive(Clone, Copy)]
+struct Deadline {
+    at: Instant,
+    exceeded: bool,
+}
+
+thread_local! {
+    // Rendering is synchronous, so helpers can find the current deadline here
+    // without access to the output writer
+    static RENDER_DEADLINE: Cell<Option<Deadline>> = Cell::new(None);
+}
+
+/// Fails once the current render is past its time budget. This is checked on
+/// writes, helper calls and every `{{#each}}` iteration, so templates that
+/// spend their time without writing anything are stopped too.
+fn check_deadline() -> Result<(), RenderError> {
+    RENDER_DEADLINE.with(|cell| match cell.get() {
+        Some(deadline) if deadline.exceeded || Instant::now() > deadline.at => {
+            cell.set(Some(Deadline {
+                exceeded: true,
+                ..deadline
+            }));
+
+            Err(RenderError::new("render time budget exceeded"))
+        }
+        _ => Ok(()),
+    })
+}
+
+/// `{{#each}}` from handlebars with a deadline check before each item, the
+/// built in helper can't be wrapped as it isn't public
+#[derive(Clone, Copy)]
+struct BoundedEach;
+
+impl BoundedEach {
+    /// Updates the block for the next item, the same as the built in helper
+    fn update_block<'reg: 'rc, 'rc>(
+        block: &mut BlockContext<'reg>,
+        h: &Helper<'reg, 'rc>,
+        base_path: Option<&Vec<String>>,
+        relative_path: String,
+        is_first: bool,
+        key: &Value,
+        value: &Value,
+    ) -> Result<(), RenderError> {
+        match base_path {
+            Some(path) if is_first => {
+                let mut path = path.clone();
+                path.push(relative_path);
+
+                *block.base_path_mut() = path;
+            }
+            Some(_) => {
+                if let Some(last) = block.base_path_mut().last_mut() {
+                    *last = relative_path;
+                }
+            }
+            None => block.set_base_value(value.clone()),
+        }
+
+        let mut params = BlockParams::new();
+
+        if let Some(value_name) = h.block_param() {
+            if base_path.is_some() {
+                params.add_path(value_name, Vec::new())?;
+            } else {
+                params.add_value(value_name, value.clone())?;
+            }
+
+            block.set_block_params(params);
+        } else if let Some((value_name, key_name)) = h.block_param_pair() {
+            if base_path.is_some() {
+                params.add_path(value_name, Vec::new())?;
+            } else {
+                params.add_value(value_name, value.clone())?;
+            }
+
+            params.add_value(key_name, key.clone())?;
+            block.set_block_params(params);
+        }
+
+        Ok(())
+    }
+
+    fn create_block<'reg: 'rc, 'rc>(param: &PathAndJson<'reg, 'rc>) -> BlockContext<'reg> {
+        let mut block = BlockContext::new();
+
+        match param.context_path() {
+            Some(path) => *block.base_path_mut() = path.clone(),
+            None => block.set_base_value(param.value().clone()),
+        }
+
+        block
+    }
+}
+
+impl HelperDef for BoundedEach {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        r: &'reg Handlebars<'reg>,
+        ctx: &'rc Context,
+        rc: &mut RenderContext<'reg, 'rc>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        let value = h
+            .param(0)
+            .ok_or_else(|| RenderError::new("Param not found for helper \"each\""))?;
+
+        let template = match h.template() {
+            Some(t) => t,
+            None => return Ok(()),
+        };
+
+        match value.value() {
+            Value::Array(list) if !list.is_empty() => {
+                rc.push_block(Self::create_block(value));
+
+                for (i, v) in list.iter().enumerate() {
+                    check_deadline()?;
+
+                    if let Some(block) = rc.block_mut() {
+                        let index = to_json(i);
+
+                        block.set_local_var("@first".to_string(), to_json(i == 0));
+                        block.set_local_var("@last".to_string(), to_json(i == list.len() - 1));
+                        block.set_local_var("@index".to_string(), index.clone());
+
+                        let path = value.context_path();
+                        Self::update_block(block, h, path, i.to_string(), i == 0, &index, v)?;
+                    }
+
+                    template.render(r, ctx, rc, out)?;
+                }
+
+                rc.pop_block();
+            }
+            Value::Object(obj) if !obj.is_empty() => {
+                rc.push_block(Self::create_block(value));
+
+                for (i, (k, v)) in obj.iter().enumerate() {
+                    check_deadline()?;
+
+                    if let Some(block) = rc.block_mut() {
+                        let key = to_json(k);
+
+                        block.set_local_var("@first".to_string(), to_json(i == 0));
+                        block.set_local_var("@key".to_string(), key.clone());
+
+                        let path = value.context_path();
+                        Self::update_block(block, h, path, k.to_string(), i == 0, &key, v)?;
+                    }
+
+                    template.render(r, ctx, rc, out)?;
+                }
+
+                rc.pop_block();
+            }
+            _ => {
+                if let Some(else_template) = h.inverse() {
+                    else_template.render(r, ctx, rc, out)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Output buffer that fails writes once the render budget is used up, which
+/// stops rendering early
+struct BudgetWriter {
+    buf: Vec<u8>,
+    max_bytes: usize,
+    size_exceeded: bool,
+}
+
+impl Write for BudgetWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.buf.len() + data.len() > self.max_bytes {
+            self.size_exceeded = true;
+
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "render size budget exceeded",
+            ));
+        }
+
+        check_deadline().map_err(|e| io::Error::new(io::ErrorKind::Other, e.desc))?;
+
+        self.buf.extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Renders a registered template within the given budget
+pub fn render_bounded<T: Serialize>(
+    reg: &Handlebars<'_>,
+    name: &str,
+    data: &T,
+    budget: RenderBudget,
+) -> Result<String, Error> {
+    let mut writer = BudgetWriter {
+        buf: Vec::new(),
+        max_bytes: budget.max_bytes,
+        size_exceeded: false,
+    };
+
+    let deadline = Deadline {
+        at: Instant::now() + budget.max_time,
+        exceeded: false,
+    };
+
+    // Restores the outer deadline after, templates aren't rendered from
+    // inside other renders but this keeps it correct if they are
+    let outer = RENDER_DEADLINE.with(|cell| cell.replace(Some(deadline)));
+    let res = reg.render_to_write(name, data, &mut writer);
+    let deadline = RENDER_DEADLINE.with(|cell| cell.replace(outer));
+
+    if writer.size_exceeded {
+        return Err(Error::RenderBudgetExceeded("size"));
+    }
+
+    if matches!(deadline, Some(d) if d.exceeded) {
+        return Err(Error::RenderBudgetExceeded("time"));
+    }
+
+    res?;
+
+    Ok(String::from_utf8_lossy(&writer.buf).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn render(template: &str, data: Value) -> String {
+        registry(false).render_template(template, &data).unwrap()
+    }
+
+    #[test]
+    fn mentions() {
+        let data =
+            json!({ "user_id": "145764790046818304", "channel_id": 1234, "role_id": "5678" });
+
+        assert_eq!(
+            render(
+                "{{mention_user user_id}} {{mention_channel channel_id}} {{mention_role role_id}}",
+                data
+            ),
+            "<@145764790046818304> <#1234> <@&5678>"
+        );
+    }
+
+    #[test]
+    fn user_tags() {
+        let data = json!({ "author": { "username": "tzuwy", "discriminator": "0001" } });
+
+        assert_eq!(render("{{user_tag author}}", data), "tzuwy#0001");
+    }
+
+    #[test]
+    fn timestamps() {
+        let data = json!({ "joined_at": "2021-05-03T00:00:00+00:00" });
+
+        assert_eq!(
+            render(
+                "{{timestamp joined_at}} {{timestamp joined_at \"D\"}}",
+                data.clone()
+            ),
+            "<t:1620000000:f> <t:1620000000:D>"
+        );
+        assert_eq!(
+            render("{{relative_time joined_at}}", data),
+            "<t:1620000000:R>"
+        );
+    }
+
+    #[test]
+    fn text_helpers() {
+        let data = json!({ "content": "**hello** world", "count": 2, "secs": 5400 });
+
+        assert_eq!(
+            render("{{truncate content 8}}", data.clone()),
+            "\\*\\*hello…"
+        );
+        assert_eq!(
+            render("{{escape_markdown content}}", data.clone()),
+            "\\*\\*hello\\*\\* world"
+        );
+        assert_eq!(
+            render("{{count}} {{pluralize count \"message\"}}", data.clone()),
+            "2 messages"
+        );
+        assert_eq!(render("{{duration secs}}", data), "1h 30m");
+    }
+
+    #[test]
+    fn escapes_interpolated_content() {
+        let data = json!({ "content": "**hi** @everyone <@1234> @here", "id": 1234 });
+
+        assert_eq!(
+            render("{{content}}", data.clone()),
+            "\\*\\*hi\\*\\* @\u{200b}everyone <\u{200b}@1234\\> @\u{200b}here"
+        );
+        // Helpers and triple braces are not escaped
+        assert_eq!(render("{{mention_user id}}", data.clone()), "<@1234>");
+        assert_eq!(
+            render("{{{content}}}", data),
+            "**hi** @everyone <@1234> @here"
+        );
+    }
+
+    #[test]
+    fn render_budget() {
+        let mut reg = registry(false);
+        reg.register_template_string("list", "{{#each items}}{{this}},{{/each}}")
+            .unwrap();
+
+        let data = json!({ "items": vec!["word"; 100] });
+        let budget = RenderBudget {
+            max_bytes: 64,
+            ..Default::default()
+        };
+
+        assert!(matches!(
+            render_bounded(&reg, "list", &data, budget),
+            Err(Error::RenderBudgetExceeded("size"))
+        ));
+        assert_eq!(
+            render_bounded(&reg, "list", &json!({ "items": ["a", "b"] }), budget).unwrap(),
+            "a,b,"
+        );
+    }
+
+    #[test]
+    fn render_time_budget_stops_loops_without_output() {
+        let mut reg = registry(false);
+        reg.register_template_string(
+            "silent",
+            "{{#each items}}{{#each ../items}}{{#if false}}{{this}}{{/if}}{{/each}}{{/each}}",
+        )
+        .unwrap();
+
+        let data = json!({ "items": vec![1; 2000] });
+        let budget = RenderBudget {
+            max_time: Duration::from_millis(1),
+            ..Default::default()
+        };
+
+        assert!(matches!(
+            render_bounded(&reg, "silent", &data, budget),
+            Err(Error::RenderBudgetExceeded("time"))
+        ));
+    }
+
+    #[test]
+    fn each_matches_builtin() {
+        let data = json!({
+            "list": ["a", "b", "c"],
+            "map": { "x": 1, "y": 2 },
+            "people": [{ "name": "x" }, { "name": "y" }],
+        });
+
+        assert_eq!(
+            render(
+                "{{#each list}}{{@index}}{{this}}{{#if @first}}^{{/if}}{{#if @last}}${{/if}} {{/each}}",
+                data.clone()
+            ),
+            "0a^ 1b 2c$ "
+        );
+        assert_eq!(
+            render("{{#each map}}{{@key}}={{this}},{{/each}}", data.clone()),
+            "x=1,y=2,"
+        );
+        assert_eq!(
+            render(
+                "{{#each people as |p i|}}{{i}}:{{p.name}} {{/each}}",
+                data.clone()
+            ),
+            "0:x 1:y "
+        );
+        assert_eq!(
+            render("{{#each missing}}x{{else}}empty{{/each}}", data),
+            "empty"
+        );
+    }
+
+    #[test]
+    fn strict_mode_reports_undefined() {
+        let data = json!({ "user": { "id": "1" } });
+
+        assert_eq!(
+            registry(false)
+                .render_template("hi {{user.name}}", &data)
+                .unwrap(),
+            "hi "
+        );
+        assert!(registry(true)
+            .render_template("hi {{user.name}}", &data)
+            .is_err());
+    }
+}
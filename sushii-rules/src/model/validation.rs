@@ -0,0 +1,845 @@
+use handlebars::Template;
+use serde_json::Value;
+use std::collections::HashSet;
+use std::fmt;
+use thiserror::Error as ThisError;
+
+use sushii_model::model::sql::GuildConfig;
+
+use crate::model::{
+    config::{
+        value_as_duration, value_as_id, BoolVar, ChannelIdVar, DurationVar, IntegerVar, RoleIdVar,
+        StringVar, StringVecVar, TimezoneVar,
+    },
+    constraint::*,
+    Action, Condition, Constraint, RuleConfig, RuleSet, Trigger,
+};
+
+/// Problems in a rule set that would otherwise only show up as warnings when
+/// the rule is triggered
+#[derive(ThisError, Debug, Clone, PartialEq)]
+pub enum ValidationErrorKind {
+    #[error("Rule config does not have {0:?} set")]
+    MissingConfigKey(String),
+    #[error("Rule config field {0:?} is not the correct type {1}")]
+    MismatchedConfigType(String, &'static str),
+    #[error("Rule config field {0:?} is out of bounds, min {1:?} max {2:?}")]
+    ConfigOutOfBounds(String, Option<u64>, Option<u64>),
+    #[error("Rule config field {0:?} is not declared in the rule set config schema")]
+    UndeclaredConfigKey(String),
+    #[error("Unknown word list {0:?}")]
+    UnknownWordList(String),
+    #[error("Guild config does not have {0} set")]
+    MissingGuildConfig(&'static str),
+    #[error("Invalid template, {0}")]
+    InvalidTemplate(String),
+    #[error("{0} constraint can never match on trigger {1:?}")]
+    InapplicableConstraint(&'static str, Trigger),
+    #[error("{0} action can never run on trigger {1:?}")]
+    InapplicableAction(&'static str, Trigger),
+    #[error("Unknown timezone {0:?}")]
+    InvalidTimezone(String),
+    #[error("Hour {0} is not between 0 and 24")]
+    InvalidHour(u64),
+    #[error("Conditions always {0}, actions are unreachable")]
+    UnreachableActions(bool),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValidationError {
+    /// JSON pointer to the invalid value in the rule set
+    pub path: String,
+    pub kind: ValidationErrorKind,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.kind)
+    }
+}
+
+/// Data outside of the rule set that rules reference
+#[derive(Debug, Clone, Default)]
+pub struct ValidationContext<'a> {
+    /// Names of word lists available to the rule set, word lists aren't
+    /// checked if this is None
+    pub word_lists: Option<&'a HashSet<String>>,
+    /// Guild config of the guild using the rule set, guild config values
+    /// aren't checked if this is None
+    pub guild_config: Option<&'a GuildConfig>,
+}
+
+struct Validator<'a> {
+    config: &'a RuleConfig,
+    params: &'a [ConfigParam],
+    ctx: &'a ValidationContext<'a>,
+    trigger: Trigger,
+    errors: Vec<ValidationError>,
+}
+
+impl<'a> Validator<'a> {
+    fn error(&mut self, path: String, kind: ValidationErrorKind) {
+        self.errors.push(ValidationError { path, kind });
+    }
+
+    fn config_key(&mut self, path: String, key: &str) -> Option<&'a Value> {
+        let value = self.config.get(key);
+
+        if value.is_none() {
+            self.error(path, ValidationErrorKind::MissingConfigKey(key.to_string()));
+        }
+
+        value
+    }
+
+    /// Checks a config key is declared, exists and has a value of the
+    /// expected type
+    fn typed_config_key(&mut self, path: String, key: &str, kind: ConfigParamType) {
+        let path = format!("{}/config_key", path);
+
+        if !self.params.is_empty() && !self.params.iter().any(|p| p.name == key) {
+            self.error(
+                path.clone(),
+                ValidationErrorKind::UndeclaredConfigKey(key.to_string()),
+            );
+        }
+
+        if let Some(value) = self.config_key(path.clone(), key) {
+            if !kind.is_type(value) {
+                self.error(
+                    path,
+                    ValidationErrorKind::MismatchedConfigType(key.to_string(), kind.name()),
+                );
+            }
+        }
+    }
+
+    fn string_var(&mut self, path: String, var: &StringVar) {
+        if let StringVar::ConfigKey(key) = var {
+            self.typed_config_key(path, key, ConfigParamType::String);
+        }
+    }
+
+    fn string_vec_var(&mut self, path: String, var: &StringVecVar) {
+        if let StringVecVar::ConfigKey(key) = var {
+            self.typed_config_key(path, key, ConfigParamType::StringVec);
+        }
+    }
+
+    fn integer_var(&mut self, path: String, var: &IntegerVar) {
+        if let IntegerVar::ConfigKey(key) = var {
+            self.typed_config_key(path, key, ConfigParamType::Integer);
+        }
+    }
+
+    fn bool_var(&mut self, path: String, var: &BoolVar) {
+        if let BoolVar::ConfigKey(key) = var {
+            self.typed_config_key(path, key, ConfigParamType::Bool);
+        }
+    }
+
+    fn duration_var(&mut self, path: String, var: &DurationVar) {
+        if let DurationVar::ConfigKey(key) = var {
+            self.typed_config_key(path, key, ConfigParamType::Duration);
+        }
+    }
+
+    fn channel_id_var(&mut self, path: String, var: &ChannelIdVar) {
+        if let ChannelIdVar::ConfigKey(key) = var {
+            self.typed_config_key(path, key, ConfigParamType::ChannelId);
+        }
+    }
+
+    fn role_id_var(&mut self, path: String, var: &RoleIdVar) {
+        if let RoleIdVar::ConfigKey(key) = var {
+            self.typed_config_key(path, key, ConfigParamType::RoleId);
+        }
+    }
+
+    fn timezone_var(&mut self, path: String, var: &TimezoneVar) {
+        match var {
+            TimezoneVar::ConfigKey(key) => {
+                self.typed_config_key(path, key, ConfigParamType::Timezone)
+            }
+            TimezoneVar::Value(name) => {
+                if name.parse::<chrono_tz::Tz>().is_err() {
+                    self.error(path, ValidationErrorKind::InvalidTimezone(name.clone()));
+                }
+            }
+        }
+    }
+
+    fn word_list(&mut self, path: String, name: &str) {
+        if let Some(word_lists) = self.ctx.word_lists {
+            if !word_lists.contains(name) {
+                self.error(path, ValidationErrorKind::UnknownWordList(name.to_string()));
+            }
+        }
+    }
+
+    fn template(&mut self, path: String, template: &str) {
+        if let Err(e) = Template::compile(template) {
+            self.error(path, ValidationErrorKind::InvalidTemplate(e.to_string()));
+        }
+    }
+
+    fn condition(&mut self, path: String, condition: &Condition) {
+        match condition {
+            Condition::Condition { constraint } => {
+                self.constraint(format!("{}/Condition", path), constraint)
+            }
+            Condition::And { and } => {
+                for (i, c) in and.iter().enumerate() {
+                    self.condition(format!("{}/And/and/{}", path, i), c);
+                }
+            }
+            Condition::Or { or } => {
+                for (i, c) in or.iter().enumerate() {
+                    self.condition(format!("{}/Or/or/{}", path, i), c);
+                }
+            }
+            Condition::Not { not } => self.condition(format!("{}/Not/not", path), not),
+            Condition::AtLeast { conditions, .. } => {
+                for (i, c) in conditions.iter().enumerate() {
+                    self.condition(format!("{}/AtLeast/conditions/{}", path, i), c);
+                }
+            }
+        }
+    }
+
+    fn constraint(&mut self, path: String, constraint: &Constraint) {
+        match constraint {
+            Constraint::Message(c) => {
+                let path = format!("{}/message", path);
+
+                // Counters can be from message events
+                if !matches!(self.trigger, Trigger::MessageCreate | Trigger::Counter) {
+                    self.error(
+                        path.clone(),
+                        ValidationErrorKind::InapplicableConstraint("Message", self.trigger),
+                    );
+                }
+
+                self.message_constraint(path, c);
+            }
+            Constraint::Counter(c) => self.counter_constraint(format!("{}/counter", path), c),
+            Constraint::Time(c) => self.time_constraint(format!("{}/time", path), c),
+        }
+    }
+
+    fn time_constraint(&mut self, path: String, constraint: &TimeConstraint) {
+        if let Some(timezone) = &constraint.timezone {
+            self.timezone_var(format!("{}/timezone", path), timezone);
+        }
+
+        if let TimeValueConstraint::Hours { start, end } = &constraint.value {
+            let path = format!("{}/value/hours", path);
+
+            for (name, var) in &[("start", start), ("end", end)] {
+                let path = format!("{}/{}", path, name);
+
+                match var {
+                    IntegerVar::Value(hour) if *hour > 24 => {
+                        self.error(path, ValidationErrorKind::InvalidHour(*hour))
+                    }
+                    _ => self.integer_var(path, var),
+                }
+            }
+        }
+    }
+
+    fn counter_constraint(&mut self, path: String, constraint: &CounterConstraint) {
+        if let Some(role_id) = &constraint.role_id {
+            self.role_id_var(format!("{}/role_id", path), role_id);
+        }
+
+        if let CounterValueConstraint::CountsInDuration {
+            increased_by,
+            duration,
+        } = &constraint.value
+        {
+            let path = format!("{}/value/counts_in_duration", path);
+
+            self.integer_var(format!("{}/increased_by", path), increased_by);
+            self.duration_var(format!("{}/duration", path), duration);
+        }
+    }
+
+    fn message_constraint(&mut self, path: String, constraint: &MessageConstraint) {
+        match constraint {
+            MessageConstraint::Id(c) => self.integer_constraint(format!("{}/id", path), c),
+            MessageConstraint::Content(c) => self.string_constraint(format!("{}/content", path), c),
+            MessageConstraint::Author(c) => self.user_constraint(format!("{}/author", path), c),
+            MessageConstraint::Member(c) => self.member_constraint(format!("{}/member", path), c),
+            MessageConstraint::CreatedAt(c) => {
+                self.date_constraint(format!("{}/created_at", path), c)
+            }
+            MessageConstraint::ChannelId(c) => {
+                self.integer_constraint(format!("{}/channel_id", path), c)
+            }
+            MessageConstraint::Duplicates(c) => {
+                let path = format!("{}/duplicates", path);
+
+                self.integer_var(format!("{}/count", path), &c.count);
+                self.duration_var(format!("{}/duration", path), &c.duration);
+            }
+            MessageConstraint::Channel(c) => {
+                self.channel_constraint(format!("{}/channel", path), c)
+            }
+        }
+    }
+
+    fn channel_constraint(&mut self, path: String, constraint: &ChannelConstraint) {
+        match constraint {
+            ChannelConstraint::Nsfw(c) => self.bool_constraint(format!("{}/nsfw", path), c),
+            ChannelConstraint::Name(c) => self.string_constraint(format!("{}/name", path), c),
+            ChannelConstraint::Id(_)
+            | ChannelConstraint::Category(_)
+            | ChannelConstraint::Type(_) => {}
+        }
+    }
+
+    fn user_constraint(&mut self, path: String, constraint: &UserConstraint) {
+        match constraint {
+            UserConstraint::Username(c) => self.string_constraint(format!("{}/username", path), c),
+            UserConstraint::Id(c) => self.integer_constraint(format!("{}/id", path), c),
+            UserConstraint::IsBot(c) => self.bool_constraint(format!("{}/is_bot", path), c),
+            UserConstraint::IsVerifiedBot(c) => {
+                self.bool_constraint(format!("{}/is_verified_bot", path), c)
+            }
+            UserConstraint::ServerLevel(c) => {
+                self.integer_constraint(format!("{}/server_level", path), c)
+            }
+            UserConstraint::ServerXp(c) => {
+                self.integer_constraint(format!("{}/server_xp", path), c)
+            }
+            UserConstraint::GlobalLevel(c) => {
+                self.integer_constraint(format!("{}/global_level", path), c)
+            }
+            UserConstraint::GlobalXp(c) => {
+                self.integer_constraint(format!("{}/global_xp", path), c)
+            }
+        }
+    }
+
+    fn member_constraint(&mut self, path: String, constraint: &MemberConstraint) {
+        match constraint {
+            MemberConstraint::Deaf(c) => self.bool_constraint(format!("{}/deaf", path), c),
+            MemberConstraint::Mute(c) => self.bool_constraint(format!("{}/mute", path), c),
+            MemberConstraint::JoinedAt(c) => self.date_constraint(format!("{}/joined_at", path), c),
+            MemberConstraint::Nickname(c) => {
+                self.string_constraint(format!("{}/nickname", path), c)
+            }
+            MemberConstraint::Pending(c) => self.bool_constraint(format!("{}/pending", path), c),
+            MemberConstraint::PremiumSince(c) => {
+                self.date_constraint(format!("{}/premium_since", path), c)
+            }
+            MemberConstraint::HighestRolePosition(c) => {
+                self.integer_constraint(format!("{}/highest_role_position", path), c)
+            }
+            MemberConstraint::RoleName(c) => {
+                self.string_constraint(format!("{}/role_name", path), c)
+            }
+            MemberConstraint::Roles(_) | MemberConstraint::Permissions(_) => {}
+        }
+    }
+
+    fn integer_constraint(&mut self, path: String, constraint: &IntegerConstraint) {
+        match constraint {
+            IntegerConstraint::Equals(v) => self.integer_var(format!("{}/equals", path), v),
+            IntegerConstraint::NotEquals(v) => self.integer_var(format!("{}/not_equals", path), v),
+            IntegerConstraint::GreaterThan(v) => {
+                self.integer_var(format!("{}/greater_than", path), v)
+            }
+            IntegerConstraint::LessThan(v) => self.integer_var(format!("{}/less_than", path), v),
+            IntegerConstraint::InclusiveBetween { lower, upper } => {
+                let path = format!("{}/inclusive_between", path);
+
+                self.integer_var(format!("{}/lower", path), lower);
+                self.integer_var(format!("{}/upper", path), upper);
+            }
+            IntegerConstraint::ExclusiveBetween { lower, upper } => {
+                let path = format!("{}/exclusive_between", path);
+
+                self.integer_var(format!("{}/lower", path), lower);
+                self.integer_var(format!("{}/upper", path), upper);
+            }
+        }
+    }
+
+    fn bool_constraint(&mut self, path: String, constraint: &BoolConstraint) {
+        match constraint {
+            BoolConstraint::Equals(v) => self.bool_var(format!("{}/equals", path), v),
+            BoolConstraint::NotEquals(v) => self.bool_var(format!("{}/not_equals", path), v),
+        }
+    }
+
+    fn date_constraint(&mut self, path: String, constraint: &DateConstraint) {
+        if let DateConstraint::OlderThan(v) = constraint {
+            self.duration_var(format!("{}/older_than", path), v);
+        }
+    }
+
+    fn string_constraint(&mut self, path: String, constraint: &StringConstraint) {
+        match constraint {
+            StringConstraint::Equals(s) => self.string_var(format!("{}/equals", path), s),
+            StringConstraint::NotEquals(s) => self.string_var(format!("{}/not_equals", path), s),
+            StringConstraint::Contains(s) => self.string_var(format!("{}/contains", path), s),
+            StringConstraint::ContainsAll(s) => {
+                self.string_vec_var(format!("{}/contains_all", path), s)
+            }
+            StringConstraint::InWordList(name) => {
+                self.word_list(format!("{}/in_word_list", path), name)
+            }
+            StringConstraint::NotInWordList(name) => {
+                self.word_list(format!("{}/not_in_word_list", path), name)
+            }
+            StringConstraint::Length(c) => self.integer_constraint(format!("{}/length", path), c),
+            _ => {}
+        }
+    }
+
+    fn actions(&mut self, path: String, actions: &[Action]) {
+        for (i, action) in actions.iter().enumerate() {
+            self.action(format!("{}/{}", path, i), action);
+        }
+    }
+
+    fn action(&mut self, path: String, action: &Action) {
+        match action {
+            Action::Reply { content, .. } => {
+                let path = format!("{}/Reply", path);
+
+                if !matches!(
+                    self.trigger,
+                    Trigger::MessageCreate | Trigger::Counter | Trigger::LevelUp
+                ) {
+                    self.error(
+                        path.clone(),
+                        ValidationErrorKind::InapplicableAction("Reply", self.trigger),
+                    );
+                }
+
+                self.template(format!("{}/content", path), content);
+            }
+            Action::SendMessage {
+                channel_id,
+                content,
+                ..
+            } => {
+                let path = format!("{}/SendMessage", path);
+
+                self.channel_id_var(format!("{}/channel_id", path), channel_id);
+                self.template(format!("{}/content", path), content);
+            }
+            Action::AddCounter { role_id, .. } => {
+                if let Some(role_id) = role_id {
+                    self.role_id_var(format!("{}/AddCounter/role_id", path), role_id);
+                }
+            }
+            Action::SubtractCounter { role_id, .. } => {
+                if let Some(role_id) = role_id {
+                    self.role_id_var(format!("{}/SubtractCounter/role_id", path), role_id);
+                }
+            }
+            Action::ResetCounter { role_id, .. } => {
+                if let Some(role_id) = role_id {
+                    self.role_id_var(format!("{}/ResetCounter/role_id", path), role_id);
+                }
+            }
+            Action::Ban { delete_days, .. } => {
+                self.integer_var(format!("{}/Ban/delete_days", path), delete_days);
+            }
+            Action::Mute { duration, .. } => {
+                let path = format!("{}/Mute", path);
+                let mute_role = self.ctx.guild_config.map(|c| c.mute_role);
+
+                if let Some(None) = mute_role {
+                    self.error(
+                        path.clone(),
+                        ValidationErrorKind::MissingGuildConfig("mute_role"),
+                    );
+                }
+
+                if let Some(duration) = duration {
+                    self.duration_var(format!("{}/duration", path), duration);
+                }
+            }
+            Action::SubCondition {
+                condition,
+                actions,
+                actions_else,
+            } => {
+                let path = format!("{}/SubCondition", path);
+
+                self.condition(format!("{}/condition", path), condition);
+
+                match static_result(condition) {
+                    Some(true) if !actions_else.is_empty() => self.error(
+                        format!("{}/actions_else", path),
+                        ValidationErrorKind::UnreachableActions(true),
+                    ),
+                    Some(false) if !actions.is_empty() => self.error(
+                        format!("{}/actions", path),
+                        ValidationErrorKind::UnreachableActions(false),
+                    ),
+                    _ => {}
+                }
+
+                self.actions(format!("{}/actions", path), actions);
+                self.actions(format!("{}/actions_else", path), actions_else);
+            }
+        }
+    }
+}
+
+/// Result of a condition if it doesn't depend on the event, e.g. an empty Or
+/// or AtLeast with a min_count larger than the number of conditions
+pub fn static_result(condition: &Condition) -> Option<bool> {
+    match condition {
+        Condition::Condition { .. } => None,
+        Condition::And { and } => {
+            let results: Vec<Option<bool>> = and.iter().map(static_result).collect();
+
+            if results.contains(&Some(false)) {
+                Some(false)
+            } else if results.iter().all(|r| *r == Some(true)) {
+                Some(true)
+            } else {
+                None
+            }
+        }
+        Condition::Or { or } => {
+            let results: Vec<Option<bool>> = or.iter().map(static_result).collect();
+
+            if results.contains(&Some(true)) {
+                Some(true)
+            } else if results.iter().all(|r| *r == Some(false)) {
+                Some(false)
+            } else {
+                None
+            }
+        }
+        Condition::Not { not } => static_result(not).map(|r| !r),
+        Condition::AtLeast {
+            min_count,
+            conditions,
+        } => {
+            let results: Vec<Option<bool>> = conditions.iter().map(static_result).collect();
+            let passed = results.iter().filter(|r| **r == Some(true)).count();
+            let possible = results.iter().filter(|r| **r != Some(false)).count();
+
+            if passed >= *min_count {
+                Some(true)
+            } else if possible < *min_count {
+                Some(false)
+            } else {
+                None
+            }
+        }
+    }
+}
+
+/// Checks a rule set with the given config, returning every problem found
+pub fn validate_rule_set(
+    rule_set: &RuleSet,
+    config: &RuleConfig,
+    ctx: &ValidationContext<'_>,
+) -> Vec<ValidationError> {
+    let mut errors = Vec::new();
+
+    for (i, rule) in rule_set.rules.iter().enumerate() {
+        let path = format!("/rules/{}", i);
+
+        let mut validator = Validator {
+            config,
+            params: &rule_set.config_schema,
+            ctx,
+            trigger: rule.trigger,
+            errors: Vec::new(),
+        };
+
+        validator.condition(format!("{}/conditions", path), &rule.conditions);
+
+        if static_result(&rule.conditions) == Some(false) && !rule.actions.is_empty() {
+            validator.error(
+                format!("{}/actions", path),
+                ValidationErrorKind::UnreachableActions(false),
+            );
+        }
+
+        validator.actions(format!("{}/actions", path), &rule.actions);
+
+        if let Some(cooldown) = &rule.cooldown {
+            validator.duration_var(format!("{}/cooldown/duration", path), &cooldown.duration);
+        }
+
+        errors.extend(validator.errors);
+    }
+
+    errors
+}
+
+impl RuleSet {
+    /// Checks this rule set with its own config, including defaults from the
+    /// declared config schema
+    pub fn validate(&self, ctx: &ValidationContext<'_>) -> Vec<ValidationError> {
+        let (config, mut errors) = match resolve_config(&self.config_schema, &self.config) {
+            Ok(config) => (config, Vec::new()),
+            Err(errors) => (self.config.clone(), errors),
+        };
+
+        errors.extend(validate_rule_set(self, &config, ctx));
+
+        errors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{
+        config::StringVar,
+        message::{MentionPolicy, Overflow},
+        Rule,
+    };
+    use crate::schema::SCHEMA_VERSION;
+    use std::collections::HashMap;
+
+    fn rule_set(rule: Rule) -> RuleSet {
+        RuleSet {
+            id: 0,
+            schema_version: SCHEMA_VERSION,
+            guild_id: None,
+            name: "test".into(),
+            description: None,
+            enabled: true,
+            editable: true,
+            author: None,
+            category: None,
+            config: HashMap::new(),
+            config_schema: Vec::new(),
+            rules: vec![rule],
+            tests: Vec::new(),
+        }
+    }
+
+    fn content_condition(constraint: StringConstraint) -> Condition {
+        Condition::Condition {
+            constraint: Constraint::Message(MessageConstraint::Content(constraint)),
+        }
+    }
+
+    #[test]
+    fn missing_config_key() {
+        let set = rule_set(Rule {
+            id: 0,
+            name: "rule".into(),
+            enabled: true,
+            trigger: Trigger::MessageCreate,
+            conditions: content_condition(StringConstraint::Equals(StringVar::ConfigKey(
+                "word".into(),
+            ))),
+            actions: vec![],
+            cooldown: None,
+            priority: 0,
+            stop_processing: false,
+        });
+
+        let errors = set.validate(&ValidationContext::default());
+
+        assert_eq!(
+            errors,
+            vec![ValidationError {
+                path: "/rules/0/conditions/Condition/message/content/equals/config_key".into(),
+                kind: ValidationErrorKind::MissingConfigKey("word".into()),
+            }]
+        );
+    }
+
+    #[test]
+    fn mismatched_config_type() {
+        let mut set = rule_set(Rule {
+            id: 0,
+            name: "rule".into(),
+            enabled: true,
+            trigger: Trigger::MessageCreate,
+            conditions: content_condition(StringConstraint::Length(
+                IntegerConstraint::GreaterThan(IntegerVar::ConfigKey("max_length".into())),
+            )),
+            actions: vec![Action::Mute {
+                duration: Some(DurationVar::ConfigKey("mute_duration".into())),
+                reason: None,
+            }],
+            cooldown: None,
+            priority: 0,
+            stop_processing: false,
+        });
+
+        set.config.insert("max_length".into(), "100".into());
+        set.config.insert("mute_duration".into(), "10m".into());
+
+        let errors = set.validate(&ValidationContext::default());
+
+        assert_eq!(
+            errors,
+            vec![ValidationError {
+                path:
+                    "/rules/0/conditions/Condition/message/content/length/greater_than/config_key"
+                        .into(),
+                kind: ValidationErrorKind::MismatchedConfigType("max_length".into(), "Integer"),
+            }]
+        );
+    }
+
+    #[test]
+    fn declared_config_defaults() {
+        let mut set = rule_set(Rule {
+            id: 0,
+            name: "rule".into(),
+            enabled: true,
+            trigger: Trigger::MessageCreate,
+            conditions: content_condition(StringConstraint::Equals(StringVar::ConfigKey(
+                "word".into(),
+            ))),
+            actions: vec![Action::Mute {
+                duration: Some(DurationVar::ConfigKey("mute_duration".into())),
+                reason: None,
+            }],
+            cooldown: None,
+            priority: 0,
+            stop_processing: false,
+        });
+
+        set.config_schema.push(ConfigParam {
+            name: "word".into(),
+            kind: ConfigParamType::String,
+            description: None,
+            default: Some("hello".into()),
+            min: None,
+            max: None,
+        });
+
+        let errors = set.validate(&ValidationContext::default());
+
+        assert_eq!(
+            errors,
+            vec![
+                ValidationError {
+                    path: "/rules/0/actions/0/Mute/duration/config_key".into(),
+                    kind: ValidationErrorKind::UndeclaredConfigKey("mute_duration".into()),
+                },
+                ValidationError {
+                    path: "/rules/0/actions/0/Mute/duration/config_key".into(),
+                    kind: ValidationErrorKind::MissingConfigKey("mute_duration".into()),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_time_constraint() {
+        let set = rule_set(Rule {
+            id: 0,
+            name: "rule".into(),
+            enabled: true,
+            trigger: Trigger::MessageCreate,
+            conditions: Condition::Condition {
+                constraint: Constraint::Time(TimeConstraint {
+                    timezone: Some(TimezoneVar::Value("Mars/Olympus_Mons".into())),
+                    value: TimeValueConstraint::Hours {
+                        start: IntegerVar::Value(22),
+                        end: IntegerVar::Value(30),
+                    },
+                }),
+            },
+            actions: vec![],
+            cooldown: None,
+            priority: 0,
+            stop_processing: false,
+        });
+
+        let errors = set.validate(&ValidationContext::default());
+
+        assert_eq!(
+            errors,
+            vec![
+                ValidationError {
+                    path: "/rules/0/conditions/Condition/time/timezone".into(),
+                    kind: ValidationErrorKind::InvalidTimezone("Mars/Olympus_Mons".into()),
+                },
+                ValidationError {
+                    path: "/rules/0/conditions/Condition/time/value/hours/end".into(),
+                    kind: ValidationErrorKind::InvalidHour(30),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_template_and_trigger() {
+        let set = rule_set(Rule {
+            id: 0,
+            name: "rule".into(),
+            enabled: true,
+            trigger: Trigger::MemberAdd,
+            conditions: content_condition(StringConstraint::IsUppercase()),
+            actions: vec![Action::SendMessage {
+                channel_id: ChannelIdVar::Value(1),
+                content: "{{#if}".into(),
+                allowed_mentions: MentionPolicy::default(),
+                overflow: Overflow::Truncate,
+            }],
+            cooldown: None,
+            priority: 0,
+            stop_processing: false,
+        });
+
+        let paths: Vec<String> = set
+            .validate(&ValidationContext::default())
+            .into_iter()
+            .map(|e| e.path)
+            .collect();
+
+        assert_eq!(
+            paths,
+            vec![
+                "/rules/0/conditions/Condition/message",
+                "/rules/0/actions/0/SendMessage/content",
+            ]
+        );
+    }
+
+    #[test]
+    fn unreachable_branches() {
+        let set = rule_set(Rule {
+            id: 0,
+            name: "rule".into(),
+            enabled: true,
+            trigger: Trigger::MessageCreate,
+            conditions: Condition::And { and: vec![] },
+            actions: vec![Action::SubCondition {
+                condition: Condition::Or { or: vec![] },
+                actions: vec![Action::Reply {
+                    content: "hi".into(),
+                    allowed_mentions: MentionPolicy::default(),
+                    overflow: Overflow::Truncate,
+                }],
+                actions_else: vec![],
+            }],
+            cooldown: None,
+            priority: 0,
+            stop_processing: false,
+        });
+
+        let errors = set.validate(&ValidationContext::default());
+
+        assert_eq!(
+            errors,
+            vec![ValidationError {
+                path: "/rules/0/actions/0/SubCondition/actions".into(),
+                kind: ValidationErrorKind::UnreachableActions(false),
+            }]
+        );
+    }
+}
@@ -0,0 +1,287 @@
+use async_trait::async_trait;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::{watch, Notify, OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinHandle;
+
+use crate::model::has_id::*;
+use crate::model::{Event, RulesEngine};
+use crate::source::SourceEvent;
+
+/// Processes events taken from worker queues
+#[async_trait]
+pub trait EventProcessor: Send + Sync {
+    async fn process(&self, event: SourceEvent);
+}
+
+#[async_trait]
+impl EventProcessor for RulesEngine {
+    async fn process(&self, event: SourceEvent) {
+        self.process_source_event(event).await
+    }
+}
+
+/// Event waiting in a worker queue, holds a pending slot until it is processed
+struct Job {
+    event: SourceEvent,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+/// Number of events queued or being processed, including counter events
+#[derive(Default)]
+struct ActiveJobs {
+    count: AtomicUsize,
+    idle: Notify,
+}
+
+impl ActiveJobs {
+    fn start(&self) {
+        self.count.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn finish(&self) {
+        if self.count.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.idle.notify_one();
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        self.count.load(Ordering::SeqCst) == 0
+    }
+}
+
+/// Fixed number of workers that each process events one at a time. Events
+/// are sharded by guild and user ID, so events from the same user are
+/// processed in order and counter changes are applied in event order.
+pub struct WorkerPool {
+    queues: Vec<UnboundedSender<Job>>,
+    workers: Vec<JoinHandle<()>>,
+    router: JoinHandle<()>,
+    stop: watch::Sender<bool>,
+    /// Limits gateway events waiting in queues or being processed
+    pending: Arc<Semaphore>,
+    active: Arc<ActiveJobs>,
+}
+
+impl WorkerPool {
+    /// Starts the workers. Counter events sent by rules are received from
+    /// `counter_events` and queued directly, without waiting for a pending
+    /// slot.
+    pub fn new(
+        processor: Arc<dyn EventProcessor>,
+        workers: usize,
+        max_pending: usize,
+        counter_events: UnboundedReceiver<Event>,
+    ) -> Self {
+        let workers = workers.max(1);
+        let active = Arc::new(ActiveJobs::default());
+        let mut queues = Vec::with_capacity(workers);
+        let mut handles = Vec::with_capacity(workers);
+
+        for _ in 0..workers {
+            let (tx, mut rx) = mpsc::unbounded_channel::<Job>();
+            let processor = processor.clone();
+            let active = active.clone();
+
+            handles.push(tokio::spawn(async move {
+                while let Some(job) = rx.recv().await {
+                    let Job { event, _permit } = job;
+
+                    processor.process(event).await;
+                    drop(_permit);
+                    active.finish();
+                }
+            }));
+
+            queues.push(tx);
+        }
+
+        let (stop, stop_rx) = watch::channel(false);
+        let router = tokio::spawn(route_counter_events(
+            counter_events,
+            queues.clone(),
+            active.clone(),
+            stop_rx,
+        ));
+
+        Self {
+            queues,
+            workers: handles,
+            router,
+            stop,
+            pending: Arc::new(Semaphore::new(max_pending.max(1))),
+            active,
+        }
+    }
+
+    /// Queues an event on its shard, waiting if there are too many pending
+    /// events
+    pub async fn submit(&self, event: SourceEvent) {
+        // Semaphore is never closed
+        let permit = self.pending.clone().acquire_owned().await.ok();
+
+        enqueue(&self.queues, &self.active, event, permit);
+    }
+
+    /// Stops accepting events and waits for queued events to finish, along
+    /// with any counter events they send
+    pub async fn shutdown(self) {
+        // Counter events are still queued until every worker is idle
+        let _ = self.stop.send(true);
+
+        if let Err(e) = self.router.await {
+            tracing::error!("Counter event router panicked: {}", e);
+        }
+
+        drop(self.queues);
+
+        for worker in self.workers {
+            if let Err(e) = worker.await {
+                tracing::error!("Worker panicked: {}", e);
+            }
+        }
+    }
+}
+
+fn enqueue(
+    queues: &[UnboundedSender<Job>],
+    active: &ActiveJobs,
+    event: SourceEvent,
+    permit: Option<OwnedSemaphorePermit>,
+) {
+    let shard = shard(&event.event, queues.len());
+    active.start();
+
+    if queues[shard]
+        .send(Job {
+            event,
+            _permit: permit,
+        })
+        .is_err()
+    {
+        active.finish();
+        tracing::error!(shard, "Worker stopped, dropping event");
+    }
+}
+
+/// Queues counter events sent by workers. These aren't sent through the
+/// event stream since a worker waiting for the main loop, while the main loop
+/// waits for a pending slot held by the worker, would never finish.
+async fn route_counter_events(
+    mut rx: UnboundedReceiver<Event>,
+    queues: Vec<UnboundedSender<Job>>,
+    active: Arc<ActiveJobs>,
+    mut stop: watch::Receiver<bool>,
+) {
+    loop {
+        tokio::select! {
+            event = rx.recv() => match event {
+                Some(event) => enqueue(&queues, &active, SourceEvent::new(event), None),
+                None => return,
+            },
+            res = stop.changed() => {
+                if res.is_err() {
+                    return;
+                }
+            }
+            _ = active.idle.notified() => {}
+        }
+
+        // Counter events are only sent by workers, so once every worker is
+        // idle and no counter events are left, no more can be sent
+        if *stop.borrow() && active.is_idle() {
+            match rx.try_recv() {
+                Ok(event) => enqueue(&queues, &active, SourceEvent::new(event), None),
+                Err(_) => return,
+            }
+        }
+    }
+}
+
+/// Worker index for an event, events without a guild or user share a shard
+fn shard(event: &Event, shards: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    event.guild_id().ok().map(|id| id.0).hash(&mut hasher);
+    event.user_id().ok().map(|id| id.0).hash(&mut hasher);
+
+    (hasher.finish() % shards as u64) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use twilight_model::gateway::event::DispatchEvent;
+    use twilight_model::gateway::payload::RoleDelete;
+    use twilight_model::id::{GuildId, RoleId};
+
+    fn role_delete(role_id: u64) -> Event {
+        Event::Twilight(DispatchEvent::RoleDelete(RoleDelete {
+            guild_id: GuildId(1),
+            role_id: RoleId(role_id),
+        }))
+    }
+
+    /// Sends counter events for each gateway event, like rules with counter
+    /// actions
+    struct CounterProcessor {
+        counter_tx: UnboundedSender<Event>,
+        processed: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl EventProcessor for CounterProcessor {
+        async fn process(&self, event: SourceEvent) {
+            if let Event::Twilight(DispatchEvent::RoleDelete(e)) = &event.event {
+                if e.role_id.0 == 0 {
+                    for _ in 0..3 {
+                        self.counter_tx.send(role_delete(1)).unwrap();
+                    }
+                }
+            }
+
+            tokio::task::yield_now().await;
+            self.processed.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn shards_are_stable() {
+        let event = role_delete(2);
+        let first = shard(&event, 16);
+
+        assert!(first < 16);
+        assert_eq!(shard(&event, 16), first);
+        assert_eq!(shard(&event, 1), 0);
+    }
+
+    #[tokio::test]
+    async fn counter_events_dont_wait_for_pending_slots() {
+        let (counter_tx, counter_rx) = mpsc::unbounded_channel();
+        let processor = Arc::new(CounterProcessor {
+            counter_tx,
+            processed: AtomicUsize::new(0),
+        });
+
+        let pool = WorkerPool::new(processor.clone(), 2, 1, counter_rx);
+
+        let submit_all = async {
+            for _ in 0..20 {
+                pool.submit(SourceEvent::new(role_delete(0))).await;
+            }
+        };
+
+        tokio::time::timeout(Duration::from_secs(5), submit_all)
+            .await
+            .expect("submitting events with max pending events used should not block");
+
+        tokio::time::timeout(Duration::from_secs(5), pool.shutdown())
+            .await
+            .expect("shutdown should finish pending counter events");
+
+        assert_eq!(processor.processed.load(Ordering::SeqCst), 20 + 20 * 3);
+    }
+}
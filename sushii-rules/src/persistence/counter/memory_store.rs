@@ -0,0 +1,199 @@
+use async_trait::async_trait;
+use chrono::{Duration, Utc};
+use dashmap::{DashMap, DashSet};
+use std::sync::Arc;
+
+use sushii_model::model::sql::RuleGauge;
+
+use super::{apply_decay, CounterKey, CounterOptions, CounterStore};
+use crate::error::Result;
+
+#[derive(Debug, Clone, Default)]
+struct MemoryCounter {
+    value: i64,
+    options: CounterOptions,
+    /// Last time decay was applied, in milliseconds
+    decay_at_ms: i64,
+    /// Last time the counter was modified, in milliseconds
+    updated_ms: i64,
+    /// Times of increments since the last reset, in milliseconds
+    increments: Vec<i64>,
+}
+
+/// Counters kept in memory for rule tests and local runs, these aren't shared
+/// between instances
+#[derive(Debug, Clone, Default)]
+pub struct MemoryCounterStore {
+    counters: Arc<DashMap<CounterKey, MemoryCounter>>,
+    modified: Arc<DashSet<CounterKey>>,
+}
+
+impl MemoryCounterStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets a counter to a value, e.g. for test fixtures
+    pub fn set(&self, key: &CounterKey, value: i64) {
+        let now_ms = Utc::now().timestamp_millis();
+        let mut counter = self.counters.entry(key.clone()).or_default();
+
+        counter.value = value;
+        counter.decay_at_ms = now_ms;
+        counter.updated_ms = now_ms;
+    }
+
+    /// Current value of a counter with expiry and decay applied
+    fn current(&self, key: &CounterKey, now_ms: i64) -> Option<i64> {
+        {
+            let mut counter = self.counters.get_mut(key)?;
+
+            let expired = counter.options.expire_after.map_or(false, |secs| {
+                now_ms - counter.updated_ms > secs as i64 * 1000
+            });
+
+            if !expired {
+                let (value, decay_at_ms) = apply_decay(
+                    counter.value,
+                    counter.decay_at_ms,
+                    counter.options.decay,
+                    now_ms,
+                );
+
+                counter.value = value;
+                counter.decay_at_ms = decay_at_ms;
+
+                return Some(value);
+            }
+        }
+
+        self.counters.remove(key);
+
+        None
+    }
+
+    fn update(&self, key: &CounterKey, options: &CounterOptions, delta: i64) -> RuleGauge {
+        let now_ms = Utc::now().timestamp_millis();
+        let value = self.current(key, now_ms).unwrap_or(0) + delta;
+
+        let mut counter = self
+            .counters
+            .entry(key.clone())
+            .or_insert_with(|| MemoryCounter {
+                decay_at_ms: now_ms,
+                ..Default::default()
+            });
+
+        if options.expire_after.is_some() {
+            counter.options.expire_after = options.expire_after;
+        }
+
+        if options.decay.is_some() {
+            counter.options.decay = options.decay;
+        }
+
+        if delta > 0 {
+            counter.increments.push(now_ms);
+        }
+
+        counter.value = value;
+        counter.updated_ms = now_ms;
+        self.modified.insert(key.clone());
+
+        key.gauge(value)
+    }
+}
+
+#[async_trait]
+impl CounterStore for MemoryCounterStore {
+    async fn get(&self, key: &CounterKey) -> Result<Option<RuleGauge>> {
+        let now_ms = Utc::now().timestamp_millis();
+
+        Ok(self.current(key, now_ms).map(|value| key.gauge(value)))
+    }
+
+    async fn inc(&self, key: &CounterKey, options: &CounterOptions) -> Result<RuleGauge> {
+        Ok(self.update(key, options, 1))
+    }
+
+    async fn dec(&self, key: &CounterKey, options: &CounterOptions) -> Result<RuleGauge> {
+        Ok(self.update(key, options, -1))
+    }
+
+    async fn reset(&self, key: &CounterKey) -> Result<RuleGauge> {
+        let now_ms = Utc::now().timestamp_millis();
+        let mut counter = self.counters.entry(key.clone()).or_default();
+
+        counter.value = 0;
+        counter.decay_at_ms = now_ms;
+        counter.updated_ms = now_ms;
+        counter.increments.clear();
+        self.modified.insert(key.clone());
+
+        Ok(key.gauge(0))
+    }
+
+    async fn get_interval_count(&self, key: &CounterKey, duration: Duration) -> Result<i64> {
+        let since_ms = (Utc::now() - duration).timestamp_millis();
+
+        Ok(self.counters.get(key).map_or(0, |counter| {
+            counter
+                .increments
+                .iter()
+                .filter(|t| **t >= since_ms)
+                .count() as i64
+        }))
+    }
+
+    async fn take_modified(&self) -> Result<Vec<RuleGauge>> {
+        let now_ms = Utc::now().timestamp_millis();
+        let keys: Vec<CounterKey> = self.modified.iter().map(|k| k.clone()).collect();
+        self.modified.clear();
+
+        Ok(keys
+            .into_iter()
+            .filter_map(|key| self.current(&key, now_ms).map(|value| key.gauge(value)))
+            .collect())
+    }
+
+    async fn mark_modified(&self, keys: &[CounterKey]) -> Result<()> {
+        for key in keys {
+            self.modified.insert(key.clone());
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sushii_model::model::sql::RuleScope;
+
+    #[tokio::test]
+    async fn counts_and_resets() {
+        let store = MemoryCounterStore::new();
+        let key = CounterKey::new(1, RuleScope::User, 2, "spam");
+        let options = CounterOptions::default();
+
+        store.set(&key, 4);
+        assert_eq!(store.inc(&key, &options).await.unwrap().value, 5);
+        assert_eq!(
+            store
+                .get_interval_count(&key, Duration::minutes(1))
+                .await
+                .unwrap(),
+            1
+        );
+
+        store.reset(&key).await.unwrap();
+        assert_eq!(store.get(&key).await.unwrap().unwrap().value, 0);
+        assert_eq!(store.take_modified().await.unwrap().len(), 1);
+        assert!(store.take_modified().await.unwrap().is_empty());
+
+        store.mark_modified(&[key.clone()]).await.unwrap();
+        let gauges = store.take_modified().await.unwrap();
+        assert_eq!(gauges.len(), 1);
+        assert_eq!(CounterKey::from_gauge(&gauges[0]), key);
+    }
+}
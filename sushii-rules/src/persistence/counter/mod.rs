@@ -0,0 +1,234 @@
+use async_trait::async_trait;
+use chrono::{Duration, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+use sushii_model::model::sql::{RuleGauge, RuleScope};
+
+use crate::error::{Error, Result};
+use crate::model::has_id::*;
+use crate::model::Event;
+
+pub mod memory_store;
+pub mod redis_store;
+
+pub use memory_store::MemoryCounterStore;
+pub use redis_store::RedisCounterStore;
+
+/// Identifies a single counter
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct CounterKey {
+    pub guild_id: u64,
+    pub scope: RuleScope,
+    pub scope_id: u64,
+    /// Secondary ID for composite scopes, e.g. the channel ID for UserChannel
+    pub sub_scope_id: Option<u64>,
+    pub name: String,
+}
+
+impl CounterKey {
+    pub fn new(guild_id: u64, scope: RuleScope, scope_id: u64, name: &str) -> Self {
+        Self {
+            guild_id,
+            scope,
+            scope_id,
+            sub_scope_id: None,
+            name: name.to_string(),
+        }
+    }
+
+    pub fn sub_scope_id(mut self, sub_scope_id: u64) -> Self {
+        self.sub_scope_id.replace(sub_scope_id);
+        self
+    }
+
+    /// Counter key for the given scope of an event. Role scoped counters use
+    /// the given role ID instead of anything from the event.
+    pub fn from_event(
+        event: &Event,
+        scope: RuleScope,
+        role_id: Option<u64>,
+        name: &str,
+    ) -> Result<Self> {
+        let guild_id = event.guild_id()?;
+
+        match scope {
+            RuleScope::Role => {
+                let role_id = role_id.ok_or(Error::MissingRoleId)?;
+
+                Ok(Self::new(guild_id.0, scope, role_id, name))
+            }
+            RuleScope::UserChannel => {
+                let user_id = event.user_id()?;
+                let channel_id = event.channel_id()?;
+
+                Ok(Self::new(guild_id.0, scope, user_id.0, name).sub_scope_id(channel_id.0))
+            }
+            _ => {
+                let scope_id = event.scope_id(scope)?;
+
+                Ok(Self::new(guild_id.0, scope, scope_id, name))
+            }
+        }
+    }
+
+    /// Key of the counter a gauge was created from
+    pub fn from_gauge(gauge: &RuleGauge) -> Self {
+        Self {
+            guild_id: gauge.guild_id as u64,
+            scope: gauge.scope,
+            scope_id: gauge.scope_id as u64,
+            sub_scope_id: gauge.sub_scope_id.map(|id| id as u64),
+            name: gauge.name.clone(),
+        }
+    }
+
+    /// Creates a gauge with the current time for this counter
+    pub fn gauge(&self, value: i64) -> RuleGauge {
+        RuleGauge {
+            time: Utc::now(),
+            guild_id: self.guild_id as i64,
+            scope: self.scope,
+            scope_id: self.scope_id as i64,
+            sub_scope_id: self.sub_scope_id.map(|id| id as i64),
+            name: self.name.clone(),
+            value,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct CounterOptions {
+    /// # Expire after
+    /// Deletes the counter if it isn't modified for this many seconds
+    #[serde(default)]
+    pub expire_after: Option<u64>,
+    /// # Decay
+    /// Automatically decreases the counter over time
+    #[serde(default)]
+    pub decay: Option<CounterDecay>,
+}
+
+/// Decreases a counter by `amount` every `interval` seconds until it reaches
+/// 0, e.g. -1 every 10 minutes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct CounterDecay {
+    /// # Amount
+    /// How much to decrease the counter by each interval
+    pub amount: u64,
+    /// # Interval
+    /// Interval in seconds
+    pub interval: u64,
+}
+
+/// Applies decay to a counter value that was last decayed at `decay_at`.
+/// Returns the decayed value along with the new decay time, which only
+/// advances by whole intervals so partial intervals aren't lost.
+pub fn apply_decay(
+    value: i64,
+    decay_at_ms: i64,
+    decay: Option<CounterDecay>,
+    now_ms: i64,
+) -> (i64, i64) {
+    let decay = match decay {
+        Some(d) if d.amount > 0 && d.interval > 0 => d,
+        _ => return (value, now_ms),
+    };
+
+    let interval_ms = decay.interval as i64 * 1000;
+    let ticks = (now_ms - decay_at_ms).max(0) / interval_ms;
+
+    if ticks == 0 {
+        return (value, decay_at_ms);
+    }
+
+    // Only decays towards 0, negative counters are left as is
+    let value = if value > 0 {
+        (value - ticks * decay.amount as i64).max(0)
+    } else {
+        value
+    };
+
+    (value, decay_at_ms + ticks * interval_ms)
+}
+
+/// Storage for rule counters. Counters are updated on every matching event so
+/// these should be fast, only periodic snapshots are persisted to Postgres.
+#[async_trait]
+pub trait CounterStore: fmt::Debug + Send + Sync {
+    /// Gets the current value of a counter with decay applied, None if it was
+    /// never set or has expired
+    async fn get(&self, key: &CounterKey) -> Result<Option<RuleGauge>>;
+
+    /// Increments a counter by 1, starting at 1 if it doesn't exist. Options
+    /// that are set replace the counter's existing options.
+    async fn inc(&self, key: &CounterKey, options: &CounterOptions) -> Result<RuleGauge>;
+
+    /// Decrements a counter by 1, starting at 0 if it doesn't exist. Options
+    /// that are set replace the counter's existing options.
+    async fn dec(&self, key: &CounterKey, options: &CounterOptions) -> Result<RuleGauge>;
+
+    /// Sets a counter to 0 and clears its increment history, keeping any
+    /// existing options
+    async fn reset(&self, key: &CounterKey) -> Result<RuleGauge>;
+
+    /// Gets the number of increments in the given duration since the last
+    /// reset
+    async fn get_interval_count(&self, key: &CounterKey, duration: Duration) -> Result<i64>;
+
+    /// Returns the current values of counters modified since the last call
+    async fn take_modified(&self) -> Result<Vec<RuleGauge>>;
+
+    /// Marks counters as modified again so they're included in the next
+    /// `take_modified`, used when their snapshots fail to save
+    async fn mark_modified(&self, keys: &[CounterKey]) -> Result<()>;
+}
+
+/// Saves the values of modified counters to Postgres, returns the number of
+/// counters saved
+pub async fn save_snapshots(store: &dyn CounterStore, pool: &sqlx::PgPool) -> Result<usize> {
+    let gauges = store.take_modified().await?;
+
+    for (i, gauge) in gauges.iter().enumerate() {
+        if let Err(e) = gauge.save_snapshot(pool).await {
+            // Counters that weren't saved are retried on the next snapshot
+            let unsaved: Vec<CounterKey> = gauges[i..].iter().map(CounterKey::from_gauge).collect();
+            store.mark_modified(&unsaved).await?;
+
+            return Err(e.into());
+        }
+    }
+
+    Ok(gauges.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DECAY: Option<CounterDecay> = Some(CounterDecay {
+        amount: 1,
+        interval: 600,
+    });
+
+    #[test]
+    fn decay_partial_interval() {
+        assert_eq!(apply_decay(5, 0, DECAY, 599_000), (5, 0));
+    }
+
+    #[test]
+    fn decay_keeps_remainder() {
+        assert_eq!(apply_decay(5, 0, DECAY, 1_300_000), (3, 1_200_000));
+    }
+
+    #[test]
+    fn decay_stops_at_zero() {
+        assert_eq!(apply_decay(2, 0, DECAY, 6_000_000), (0, 6_000_000));
+    }
+
+    #[test]
+    fn no_decay() {
+        assert_eq!(apply_decay(5, 0, None, 6_000_000), (5, 6_000_000));
+    }
+}
@@ -0,0 +1,301 @@
+use async_trait::async_trait;
+use chrono::{Duration, Utc};
+use redis::AsyncCommands;
+use sqlx::types::Uuid;
+use std::collections::HashMap;
+
+use sushii_model::model::sql::RuleGauge;
+
+use super::{apply_decay, CounterDecay, CounterKey, CounterOptions, CounterStore};
+use crate::error::Result;
+
+/// How long increments are kept for `CountsInDuration` constraints
+const INCREMENT_HISTORY_SECS: i64 = 7 * 24 * 60 * 60;
+
+/// Set of counter keys modified since the last snapshot
+const MODIFIED_KEY: &str = "rule_counters_modified";
+
+/// Max number of modified counters to pop at a time
+const MODIFIED_BATCH_SIZE: usize = 500;
+
+// Counters are stored as a hash with the value along with the options, so
+// that decay and expiry still apply when a later action doesn't set them.
+// This needs to be a script since decay has to be applied before modifying.
+//
+// KEYS: counter hash, increments sorted set, modified set
+// ARGV: operation, now in ms, counter key json, increment member,
+//       decay amount, decay interval in ms, expire after in secs,
+//       increment history in secs
+// Empty option args keep the counter's existing options.
+const UPDATE_SCRIPT: &str = r#"
+local now = tonumber(ARGV[2])
+local counter = redis.call('HMGET', KEYS[1], 'value', 'decay_at', 'decay_amount', 'decay_interval', 'expire_after')
+
+local exists = counter[1] ~= false
+local value = tonumber(counter[1]) or 0
+local decay_at = tonumber(counter[2]) or now
+local decay_amount = tonumber(ARGV[5]) or tonumber(counter[3]) or 0
+local decay_interval = tonumber(ARGV[6]) or tonumber(counter[4]) or 0
+local expire_after = tonumber(ARGV[7]) or tonumber(counter[5]) or 0
+local history = tonumber(ARGV[8])
+
+if decay_amount > 0 and decay_interval > 0 then
+    local ticks = math.floor(math.max(now - decay_at, 0) / decay_interval)
+
+    if ticks > 0 then
+        if value > 0 then
+            value = math.max(value - ticks * decay_amount, 0)
+        end
+
+        decay_at = decay_at + ticks * decay_interval
+    end
+else
+    decay_at = now
+end
+
+if ARGV[1] == 'inc' then
+    value = value + 1
+    redis.call('ZADD', KEYS[2], now, ARGV[4])
+    redis.call('ZREMRANGEBYSCORE', KEYS[2], '-inf', now - history * 1000)
+elseif ARGV[1] == 'dec' then
+    -- Decrementing a missing counter should result in 0 instead of -1
+    if exists then
+        value = value - 1
+    end
+elseif ARGV[1] == 'reset' then
+    value = 0
+    decay_at = now
+    redis.call('DEL', KEYS[2])
+end
+
+redis.call('HSET', KEYS[1],
+    'value', value,
+    'decay_at', decay_at,
+    'decay_amount', decay_amount,
+    'decay_interval', decay_interval,
+    'expire_after', expire_after)
+
+if expire_after > 0 then
+    redis.call('EXPIRE', KEYS[1], expire_after)
+    redis.call('EXPIRE', KEYS[2], math.min(expire_after, history))
+else
+    redis.call('PERSIST', KEYS[1])
+    redis.call('EXPIRE', KEYS[2], history)
+end
+
+redis.call('SADD', KEYS[3], ARGV[3])
+
+return value
+"#;
+
+/// Counters stored in Redis. Each counter is a hash of its value and options,
+/// along with a sorted set of increment timestamps which are trimmed and
+/// expired after `INCREMENT_HISTORY_SECS`.
+#[derive(Clone)]
+pub struct RedisCounterStore {
+    pool: deadpool_redis::Pool,
+}
+
+impl std::fmt::Debug for RedisCounterStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RedisCounterStore").finish()
+    }
+}
+
+enum Operation {
+    Inc,
+    Dec,
+    Reset,
+}
+
+impl Operation {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Inc => "inc",
+            Self::Dec => "dec",
+            Self::Reset => "reset",
+        }
+    }
+}
+
+impl RedisCounterStore {
+    pub fn new(pool: deadpool_redis::Pool) -> Self {
+        Self { pool }
+    }
+
+    fn scope_key(key: &CounterKey) -> String {
+        match key.sub_scope_id {
+            Some(sub_scope_id) => format!(
+                "{}:{:?}:{}:{}:{}",
+                key.guild_id, key.scope, key.scope_id, sub_scope_id, key.name
+            ),
+            None => format!(
+                "{}:{:?}:{}:{}",
+                key.guild_id, key.scope, key.scope_id, key.name
+            ),
+        }
+    }
+
+    fn value_key(key: &CounterKey) -> String {
+        format!("rule_counter:{}", Self::scope_key(key))
+    }
+
+    fn increments_key(key: &CounterKey) -> String {
+        format!("rule_counter_incs:{}", Self::scope_key(key))
+    }
+
+    async fn update(
+        &self,
+        key: &CounterKey,
+        op: Operation,
+        options: Option<&CounterOptions>,
+    ) -> Result<RuleGauge> {
+        let mut conn = self.pool.get().await?;
+
+        let decay = options.and_then(|o| o.decay);
+        let expire_after = options.and_then(|o| o.expire_after);
+
+        let value: i64 = redis::Script::new(UPDATE_SCRIPT)
+            .key(Self::value_key(key))
+            .key(Self::increments_key(key))
+            .key(MODIFIED_KEY)
+            .arg(op.as_str())
+            .arg(Utc::now().timestamp_millis())
+            .arg(serde_json::to_string(key)?)
+            .arg(Uuid::new_v4().to_string())
+            .arg(decay.map_or_else(String::new, |d| d.amount.to_string()))
+            .arg(decay.map_or_else(String::new, |d| (d.interval * 1000).to_string()))
+            .arg(expire_after.map_or_else(String::new, |s| s.to_string()))
+            .arg(INCREMENT_HISTORY_SECS)
+            .invoke_async(&mut conn)
+            .await?;
+
+        Ok(key.gauge(value))
+    }
+
+    async fn get_value(
+        conn: &mut deadpool_redis::Connection,
+        key: &CounterKey,
+    ) -> Result<Option<i64>> {
+        let fields: HashMap<String, i64> = conn.hgetall(Self::value_key(key)).await?;
+
+        let value = match fields.get("value") {
+            Some(v) => *v,
+            None => return Ok(None),
+        };
+
+        let decay = CounterDecay {
+            amount: fields.get("decay_amount").copied().unwrap_or(0) as u64,
+            // Stored in ms
+            interval: fields.get("decay_interval").copied().unwrap_or(0) as u64 / 1000,
+        };
+
+        let now_ms = Utc::now().timestamp_millis();
+        let decay_at = fields.get("decay_at").copied().unwrap_or(now_ms);
+        let (value, _) = apply_decay(value, decay_at, Some(decay), now_ms);
+
+        Ok(Some(value))
+    }
+
+    /// Current values of counters from their serialized keys, expired
+    /// counters and invalid keys are skipped
+    async fn get_gauges(
+        conn: &mut deadpool_redis::Connection,
+        keys: &[String],
+    ) -> Result<Vec<RuleGauge>> {
+        let mut gauges = Vec::new();
+
+        for key_str in keys {
+            let key: CounterKey = match serde_json::from_str(key_str) {
+                Ok(k) => k,
+                Err(e) => {
+                    tracing::warn!(?key_str, "Invalid modified counter key: {}", e);
+                    continue;
+                }
+            };
+
+            if let Some(value) = Self::get_value(conn, &key).await? {
+                gauges.push(key.gauge(value));
+            }
+        }
+
+        Ok(gauges)
+    }
+}
+
+#[async_trait]
+impl CounterStore for RedisCounterStore {
+    async fn get(&self, key: &CounterKey) -> Result<Option<RuleGauge>> {
+        let mut conn = self.pool.get().await?;
+        let value = Self::get_value(&mut conn, key).await?;
+
+        Ok(value.map(|v| key.gauge(v)))
+    }
+
+    async fn inc(&self, key: &CounterKey, options: &CounterOptions) -> Result<RuleGauge> {
+        self.update(key, Operation::Inc, Some(options)).await
+    }
+
+    async fn dec(&self, key: &CounterKey, options: &CounterOptions) -> Result<RuleGauge> {
+        self.update(key, Operation::Dec, Some(options)).await
+    }
+
+    async fn reset(&self, key: &CounterKey) -> Result<RuleGauge> {
+        self.update(key, Operation::Reset, None).await
+    }
+
+    async fn get_interval_count(&self, key: &CounterKey, duration: Duration) -> Result<i64> {
+        let mut conn = self.pool.get().await?;
+        let since_ms = (Utc::now() - duration).timestamp_millis();
+
+        conn.zcount(Self::increments_key(key), since_ms, "+inf")
+            .await
+            .map_err(Into::into)
+    }
+
+    async fn take_modified(&self) -> Result<Vec<RuleGauge>> {
+        let mut conn = self.pool.get().await?;
+        let mut popped = Vec::new();
+
+        loop {
+            let keys: Vec<String> = redis::cmd("SPOP")
+                .arg(MODIFIED_KEY)
+                .arg(MODIFIED_BATCH_SIZE)
+                .query_async(&mut conn)
+                .await?;
+
+            if keys.is_empty() {
+                break;
+            }
+
+            popped.extend(keys);
+        }
+
+        match Self::get_gauges(&mut conn, &popped).await {
+            Ok(gauges) => Ok(gauges),
+            Err(e) => {
+                // Add popped keys back so they aren't lost until the next
+                // modification
+                if !popped.is_empty() {
+                    let _: () = conn.sadd(MODIFIED_KEY, &popped).await?;
+                }
+
+                Err(e)
+            }
+        }
+    }
+
+    async fn mark_modified(&self, keys: &[CounterKey]) -> Result<()> {
+        if keys.is_empty() {
+            return Ok(());
+        }
+
+        let mut conn = self.pool.get().await?;
+        let members = keys
+            .iter()
+            .map(serde_json::to_string)
+            .collect::<serde_json::Result<Vec<_>>>()?;
+
+        conn.sadd(MODIFIED_KEY, members).await.map_err(Into::into)
+    }
+}
@@ -0,0 +1,221 @@
+use async_trait::async_trait;
+use jsonschema::JSONSchema;
+use serde_json::Value;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::RwLock;
+
+use super::RuleStore;
+use crate::error::{Error, Result};
+use crate::migration;
+use crate::model::{config::resolve_config, validation::ValidationContext, RuleSet};
+
+/// Modified times of each rule set file, used to check for changes
+type FileTimes = HashMap<PathBuf, SystemTime>;
+
+/// Loads rule sets from a directory of JSON or YAML files, one rule set per
+/// file. Rule sets without a guild_id apply to all guilds.
+#[derive(Debug, Clone)]
+pub struct FileStore {
+    dir: PathBuf,
+    rule_sets: Arc<RwLock<Vec<RuleSet>>>,
+    file_times: Arc<RwLock<FileTimes>>,
+}
+
+impl FileStore {
+    /// Loads all rule sets in a directory, fails if any of them are invalid
+    pub async fn new(dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = dir.into();
+        let file_times = read_file_times(&dir).await?;
+        let rule_sets = load_dir(&dir, &file_times).await?;
+
+        tracing::info!("Loaded {} rule sets from {:?}", rule_sets.len(), dir);
+
+        Ok(Self {
+            dir,
+            rule_sets: Arc::new(RwLock::new(rule_sets)),
+            file_times: Arc::new(RwLock::new(file_times)),
+        })
+    }
+
+    /// Reloads all rule sets if any files were added, removed or modified.
+    /// Previously loaded rule sets are kept if any of the new files are
+    /// invalid. Returns true if rule sets were reloaded.
+    pub async fn reload(&self) -> Result<bool> {
+        let file_times = read_file_times(&self.dir).await?;
+
+        if *self.file_times.read().await == file_times {
+            return Ok(false);
+        }
+
+        // Update times first so that invalid files aren't retried until they
+        // are modified again
+        *self.file_times.write().await = file_times.clone();
+
+        let rule_sets = load_dir(&self.dir, &file_times).await?;
+        tracing::info!("Reloaded {} rule sets from {:?}", rule_sets.len(), self.dir);
+
+        *self.rule_sets.write().await = rule_sets;
+
+        Ok(true)
+    }
+
+    /// Spawns a task to check for file changes on an interval
+    pub fn watch(&self, interval: Duration) {
+        let store = self.clone();
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+
+            loop {
+                interval.tick().await;
+
+                if let Err(e) = store.reload().await {
+                    tracing::error!("Failed to reload rule sets: {}", e);
+                }
+            }
+        });
+    }
+}
+
+#[async_trait]
+impl RuleStore for FileStore {
+    async fn get_guild_rule_sets(&self, guild_id: u64) -> Result<Vec<RuleSet>> {
+        let rule_sets = self.rule_sets.read().await;
+
+        Ok(rule_sets
+            .iter()
+            .filter(|set| set.guild_id.map_or(true, |id| id as u64 == guild_id))
+            .cloned()
+            .collect())
+    }
+}
+
+async fn read_file_times(dir: &Path) -> Result<FileTimes> {
+    let mut file_times = HashMap::new();
+    let mut entries = tokio::fs::read_dir(dir).await?;
+
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+
+        if !is_rule_set_file(&path) {
+            continue;
+        }
+
+        let modified = entry.metadata().await?.modified()?;
+        file_times.insert(path, modified);
+    }
+
+    Ok(file_times)
+}
+
+fn is_rule_set_file(path: &Path) -> bool {
+    matches!(
+        path.extension().and_then(|e| e.to_str()),
+        Some("json") | Some("yaml") | Some("yml")
+    )
+}
+
+async fn load_dir(dir: &Path, file_times: &FileTimes) -> Result<Vec<RuleSet>> {
+    let rule_set_schema = serde_json::to_value(schemars::schema_for!(RuleSet))?;
+    let rule_set_schema = JSONSchema::compile(&rule_set_schema)
+        .map_err(|e| Error::InvalidRuleSetFile(dir.into(), e.to_string()))?;
+
+    // Sort so that ids and order are consistent between loads
+    let mut paths: Vec<&PathBuf> = file_times.keys().collect();
+    paths.sort();
+
+    let mut rule_sets = Vec::new();
+
+    for path in paths {
+        let mut rule_set = load_file(path, &rule_set_schema).await?;
+        // Only the test runner uses tests, it loads files on its own
+        rule_set.tests = Vec::new();
+
+        rule_sets.push(rule_set);
+    }
+
+    Ok(rule_sets)
+}
+
+/// Loads and validates a single rule set file, e.g. to run its tests
+pub async fn load_rule_set_file(path: &Path) -> Result<RuleSet> {
+    let rule_set_schema = serde_json::to_value(schemars::schema_for!(RuleSet))?;
+    let rule_set_schema = JSONSchema::compile(&rule_set_schema)
+        .map_err(|e| Error::InvalidRuleSetFile(path.into(), e.to_string()))?;
+
+    load_file(path, &rule_set_schema).await
+}
+
+async fn load_file(path: &Path, rule_set_schema: &JSONSchema) -> Result<RuleSet> {
+    let contents = tokio::fs::read_to_string(path).await?;
+    let mut value = parse_file(path, &contents)?;
+
+    migration::migrate_rule_set(&mut value);
+    validate(path, rule_set_schema, &value)?;
+
+    let mut rule_set: RuleSet = serde_json::from_value(value)
+        .map_err(|e| Error::InvalidRuleSetFile(path.into(), e.to_string()))?;
+
+    let errors = rule_set.validate(&ValidationContext::default());
+    if !errors.is_empty() {
+        let messages: Vec<String> = errors.iter().map(ToString::to_string).collect();
+
+        return Err(Error::InvalidRuleSetFile(path.into(), messages.join(", ")));
+    }
+
+    // Already validated, this only fills in defaults
+    if let Ok(config) = resolve_config(&rule_set.config_schema, &rule_set.config) {
+        rule_set.config = config;
+    }
+
+    assign_ids(path, &mut rule_set);
+
+    Ok(rule_set)
+}
+
+fn parse_file(path: &Path, contents: &str) -> Result<Value> {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("json") => serde_json::from_str(contents).map_err(Into::into),
+        _ => serde_yaml::from_str(contents).map_err(Into::into),
+    }
+}
+
+fn validate(path: &Path, schema: &JSONSchema, value: &Value) -> Result<()> {
+    if let Err(errors) = schema.validate(value) {
+        let messages: Vec<String> = errors
+            .map(|e| format!("{}: {}", e.instance_path, e))
+            .collect();
+
+        return Err(Error::InvalidRuleSetFile(path.into(), messages.join(", ")));
+    }
+
+    Ok(())
+}
+
+/// Files don't have database IDs, so these are generated from the file name
+/// and rule names so they stay the same between reloads
+fn assign_ids(path: &Path, rule_set: &mut RuleSet) {
+    let file_name = path.file_name().map(|n| n.to_string_lossy().to_string());
+
+    if rule_set.id == 0 {
+        rule_set.id = positive_hash(&file_name);
+    }
+
+    for rule in rule_set.rules.iter_mut() {
+        if rule.id == 0 {
+            rule.id = positive_hash(&(&file_name, &rule.name));
+        }
+    }
+}
+
+fn positive_hash<T: Hash>(t: &T) -> i64 {
+    let mut hasher = DefaultHasher::new();
+    t.hash(&mut hasher);
+
+    (hasher.finish() >> 1) as i64
+}
@@ -0,0 +1,230 @@
+use async_trait::async_trait;
+use dashmap::{DashMap, DashSet};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::error::Result;
+
+/// How long the progress of an event is kept, events are retried or dead
+/// lettered well before this
+const DONE_TTL: Duration = Duration::from_secs(60 * 60 * 24);
+
+/// Actions that already ran for an event and the results of its conditions,
+/// so retried events don't run actions again or take different branches.
+/// Shared by every rule and attempt of the event, changes are kept in memory
+/// until they are saved.
+#[derive(Debug, Default)]
+pub struct EventProgress {
+    /// Saved and unsaved entries, `action:{key}` or `condition:{key}`
+    entries: DashMap<String, bool>,
+    /// Entries added since the progress was loaded or last saved
+    unsaved: DashSet<String>,
+}
+
+impl EventProgress {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Progress loaded from a store
+    fn from_entries(entries: HashMap<String, bool>) -> Self {
+        Self {
+            entries: entries.into_iter().collect(),
+            unsaved: DashSet::new(),
+        }
+    }
+
+    fn insert(&self, field: String, value: bool) {
+        self.unsaved.insert(field.clone());
+        self.entries.insert(field, value);
+    }
+
+    /// If an action with this key already ran
+    pub fn is_done(&self, key: &str) -> bool {
+        self.entries.contains_key(&format!("action:{}", key))
+    }
+
+    /// Marks an action as done after it ran successfully
+    pub fn mark_done(&self, key: &str) {
+        self.insert(format!("action:{}", key), true);
+    }
+
+    /// Result of a condition checked earlier in the event
+    pub fn condition_result(&self, key: &str) -> Option<bool> {
+        self.entries
+            .get(&format!("condition:{}", key))
+            .map(|passed| *passed)
+    }
+
+    /// Saves the result of a condition so retries of the event take the same
+    /// branch
+    pub fn save_condition_result(&self, key: &str, passed: bool) {
+        self.insert(format!("condition:{}", key), passed);
+    }
+
+    /// Entries that need to be saved. Condition results only matter once an
+    /// action changed something they may depend on, so nothing is returned
+    /// until an action ran.
+    pub fn unsaved(&self) -> Vec<(String, bool)> {
+        let ran_action = self.entries.iter().any(|e| e.key().starts_with("action:"));
+
+        if !ran_action {
+            return Vec::new();
+        }
+
+        self.unsaved
+            .iter()
+            .filter_map(|field| {
+                self.entries
+                    .get(field.key())
+                    .map(|value| (field.key().clone(), *value))
+            })
+            .collect()
+    }
+
+    /// Marks entries as saved after they are written to a store
+    pub fn mark_saved(&self, entries: &[(String, bool)]) {
+        for (field, _) in entries {
+            self.unsaved.remove(field);
+        }
+    }
+}
+
+/// Stores the progress of events, so events that are redelivered after the
+/// engine stops or on another instance don't run actions again
+#[async_trait]
+pub trait IdempotencyStore: fmt::Debug + Send + Sync {
+    /// Progress saved by earlier attempts of the event
+    async fn load(&self, event_id: &str) -> Result<EventProgress>;
+
+    /// Saves the unsaved progress of the event in a single write
+    async fn save(&self, event_id: &str, progress: &EventProgress) -> Result<()>;
+}
+
+#[derive(Clone)]
+pub struct RedisIdempotencyStore {
+    pool: deadpool_redis::Pool,
+}
+
+impl fmt::Debug for RedisIdempotencyStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RedisIdempotencyStore").finish()
+    }
+}
+
+impl RedisIdempotencyStore {
+    pub fn new(pool: deadpool_redis::Pool) -> Self {
+        Self { pool }
+    }
+}
+
+/// Progress is a hash per event, so it is read with a single HGETALL
+#[async_trait]
+impl IdempotencyStore for RedisIdempotencyStore {
+    async fn load(&self, event_id: &str) -> Result<EventProgress> {
+        let mut conn = self.pool.get().await?;
+
+        let entries: HashMap<String, bool> = redis::cmd("HGETALL")
+            .arg(format!("rule_event:{}", event_id))
+            .query_async(&mut conn)
+            .await?;
+
+        Ok(EventProgress::from_entries(entries))
+    }
+
+    async fn save(&self, event_id: &str, progress: &EventProgress) -> Result<()> {
+        let entries = progress.unsaved();
+
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        let key = format!("rule_event:{}", event_id);
+        let mut conn = self.pool.get().await?;
+
+        redis::pipe()
+            .atomic()
+            .cmd("HSET")
+            .arg(&key)
+            .arg(&entries)
+            .ignore()
+            .cmd("EXPIRE")
+            .arg(&key)
+            .arg(DONE_TTL.as_secs())
+            .ignore()
+            .query_async::<_, ()>(&mut conn)
+            .await?;
+
+        progress.mark_saved(&entries);
+
+        Ok(())
+    }
+}
+
+/// Event progress kept in memory for local runs, events are never expired
+#[derive(Debug, Clone, Default)]
+pub struct MemoryIdempotencyStore {
+    events: Arc<DashMap<String, HashMap<String, bool>>>,
+}
+
+impl MemoryIdempotencyStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl IdempotencyStore for MemoryIdempotencyStore {
+    async fn load(&self, event_id: &str) -> Result<EventProgress> {
+        let entries = self
+            .events
+            .get(event_id)
+            .map(|e| e.value().clone())
+            .unwrap_or_default();
+
+        Ok(EventProgress::from_entries(entries))
+    }
+
+    async fn save(&self, event_id: &str, progress: &EventProgress) -> Result<()> {
+        let entries = progress.unsaved();
+
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        self.events
+            .entry(event_id.to_string())
+            .or_default()
+            .extend(entries.iter().cloned());
+
+        progress.mark_saved(&entries);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn saves_conditions_once_an_action_ran() {
+        let store = MemoryIdempotencyStore::new();
+        let progress = store.load("event").await.unwrap();
+
+        progress.save_condition_result("1:conditions", true);
+        store.save("event", &progress).await.unwrap();
+        assert!(store.load("event").await.unwrap().entries.is_empty());
+
+        progress.mark_done("1:0");
+        store.save("event", &progress).await.unwrap();
+        assert!(progress.unsaved().is_empty());
+
+        let redelivered = store.load("event").await.unwrap();
+        assert!(redelivered.is_done("1:0"));
+        assert!(!redelivered.is_done("1:1"));
+        assert_eq!(redelivered.condition_result("1:conditions"), Some(true));
+    }
+}
@@ -1,15 +1,34 @@
+use async_trait::async_trait;
+use std::fmt;
+
 use crate::error::Result;
 use crate::model::RuleSet;
-use std::fmt;
 
+pub mod counter;
+pub mod file;
+pub mod idempotency;
 // pub mod hard_coded;
-// pub mod postgres;
+pub mod postgres;
+pub mod rate_limit;
 
 // pub use hard_coded::HardCodedStore;
+pub use file::FileStore;
+pub use idempotency::{
+    EventProgress, IdempotencyStore, MemoryIdempotencyStore, RedisIdempotencyStore,
+};
+pub use postgres::PostgresStore;
+pub use rate_limit::{MemoryRateLimitStore, RateLimitStore, RedisRateLimitStore};
 
-pub trait RuleStore: RuleStoreClone + fmt::Debug {
-    /// Fetches all rules in a guild
-    fn get_guild_rule_sets(&self, guild_id: u64) -> Result<Vec<RuleSet>>;
+#[async_trait]
+pub trait RuleStore: RuleStoreClone + fmt::Debug + Send + Sync {
+    /// Fetches all rule sets in a guild, including global rule sets
+    async fn get_guild_rule_sets(&self, guild_id: u64) -> Result<Vec<RuleSet>>;
+
+    /// Disables a rule in a guild and records why, e.g. when it loops counter
+    /// events. Stores that can't be modified keep the rule as is.
+    async fn disable_rule(&self, _guild_id: u64, _rule_id: i64, _reason: &str) -> Result<()> {
+        Ok(())
+    }
 }
 
 pub trait RuleStoreClone {
@@ -1,28 +1,46 @@
-use crate::model::{constraint::*, Action, Condition, Rule, RuleSet, Trigger};
+use async_trait::async_trait;
+use redis::AsyncCommands;
+
+use super::RuleStore;
 use crate::error::Result;
+use crate::model::{Rule, RuleSet};
+
+/// Rule sets stored in Postgres, managed by the web dashboard. These are
+/// cached in Redis for a short time.
+#[derive(Clone)]
+pub struct PostgresStore {
+    pg_pool: sqlx::PgPool,
+    redis_pool: deadpool_redis::Pool,
+}
+
+impl std::fmt::Debug for PostgresStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PostgresStore").finish()
+    }
+}
+
+impl PostgresStore {
+    pub fn new(pg_pool: sqlx::PgPool, redis_pool: deadpool_redis::Pool) -> Self {
+        Self {
+            pg_pool,
+            redis_pool,
+        }
+    }
+}
+
+#[async_trait]
+impl RuleStore for PostgresStore {
+    async fn get_guild_rule_sets(&self, guild_id: u64) -> Result<Vec<RuleSet>> {
+        RuleSet::sets_from_guild_id(self.redis_pool.clone(), &self.pg_pool, guild_id).await
+    }
+
+    async fn disable_rule(&self, guild_id: u64, rule_id: i64, reason: &str) -> Result<()> {
+        Rule::disable(&self.pg_pool, guild_id, rule_id, reason).await?;
+
+        // Drop cached rule sets so the change applies right away
+        let mut conn = self.redis_pool.get().await?;
+        conn.del::<_, ()>(RuleSet::key(guild_id)).await?;
 
-pub async fn get_guild_rule_sets(pool: &sqlx::PgPool, guild_id: u64) -> Result<Vec<RuleSet>> {
-    sqlx::query_as!(
-        RuleSet,
-        "select id,
-               guild_id,
-               name,
-               description,
-               enabled,
-               editable,
-               author,
-               category,
-               config,
-               array(select *
-                  from app_public.guild_rules
-                 where set_id = s.id
-                   and guild_id = $1) as rules
-          from app_public.guild_rule_sets s
-         where guild_id = $1
-        ",
-        guild_id as i64,
-    )
-    .fetch_all(pool)
-    .await
-    .map_err(Into::into)
+        Ok(())
+    }
 }
@@ -0,0 +1,180 @@
+use async_trait::async_trait;
+use dashmap::DashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::error::Result;
+
+// Fixed window counter, the window starts on the first action in it.
+//
+// KEYS: budget counter
+// ARGV: window in secs, limit
+// Returns -1 if the action is allowed, otherwise the secs until the window
+// resets.
+const BUDGET_SCRIPT: &str = r#"
+local count = redis.call('INCR', KEYS[1])
+
+if count == 1 then
+    redis.call('EXPIRE', KEYS[1], ARGV[1])
+end
+
+if count > tonumber(ARGV[2]) then
+    return math.max(redis.call('TTL', KEYS[1]), 1)
+end
+
+return -1
+"#;
+
+// Starts a cooldown unless another owner already holds it.
+//
+// KEYS: cooldown
+// ARGV: owner, duration in secs
+// Returns 1 if the cooldown was started or is held by the owner, otherwise 0.
+const COOLDOWN_SCRIPT: &str = r#"
+local owner = redis.call('GET', KEYS[1])
+
+if owner == false then
+    redis.call('SET', KEYS[1], ARGV[1], 'EX', ARGV[2])
+    return 1
+end
+
+if owner == ARGV[1] then
+    return 1
+end
+
+return 0
+"#;
+
+/// Storage for rule cooldowns and per-guild action budgets, shared between
+/// instances so limits apply to the whole bot
+#[async_trait]
+pub trait RateLimitStore: fmt::Debug + Send + Sync {
+    /// Starts a cooldown for the key, returns false if the key is already on
+    /// cooldown. Cooldowns started by the same owner, e.g. an event that is
+    /// being retried, don't block it.
+    async fn start_cooldown(&self, key: &str, owner: &str, duration: Duration) -> Result<bool>;
+
+    /// Uses one action from a guild's budget of `limit` actions per `window`.
+    /// Returns None if the action is allowed, otherwise the time until the
+    /// budget resets.
+    async fn take_action(
+        &self,
+        guild_id: u64,
+        limit: u64,
+        window: Duration,
+    ) -> Result<Option<Duration>>;
+}
+
+#[derive(Clone)]
+pub struct RedisRateLimitStore {
+    pool: deadpool_redis::Pool,
+}
+
+impl fmt::Debug for RedisRateLimitStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RedisRateLimitStore").finish()
+    }
+}
+
+impl RedisRateLimitStore {
+    pub fn new(pool: deadpool_redis::Pool) -> Self {
+        Self { pool }
+    }
+}
+
+#[async_trait]
+impl RateLimitStore for RedisRateLimitStore {
+    async fn start_cooldown(&self, key: &str, owner: &str, duration: Duration) -> Result<bool> {
+        let mut conn = self.pool.get().await?;
+
+        let started: bool = redis::Script::new(COOLDOWN_SCRIPT)
+            .key(format!("rule_cooldown:{}", key))
+            .arg(owner)
+            .arg(duration.as_secs().max(1))
+            .invoke_async(&mut conn)
+            .await?;
+
+        Ok(started)
+    }
+
+    async fn take_action(
+        &self,
+        guild_id: u64,
+        limit: u64,
+        window: Duration,
+    ) -> Result<Option<Duration>> {
+        let mut conn = self.pool.get().await?;
+
+        let retry_after: i64 = redis::Script::new(BUDGET_SCRIPT)
+            .key(format!("rule_action_budget:{}", guild_id))
+            .arg(window.as_secs().max(1))
+            .arg(limit)
+            .invoke_async(&mut conn)
+            .await?;
+
+        if retry_after < 0 {
+            return Ok(None);
+        }
+
+        Ok(Some(Duration::from_secs(retry_after as u64)))
+    }
+}
+
+/// Rate limits kept in memory for rule tests and local runs, these aren't
+/// shared between instances
+#[derive(Debug, Clone, Default)]
+pub struct MemoryRateLimitStore {
+    /// Owner of each cooldown and when it ends
+    cooldowns: Arc<DashMap<String, (String, Instant)>>,
+    /// Actions used in each guild's window and when the window ends
+    budgets: Arc<DashMap<u64, (u64, Instant)>>,
+}
+
+impl MemoryRateLimitStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl RateLimitStore for MemoryRateLimitStore {
+    async fn start_cooldown(&self, key: &str, owner: &str, duration: Duration) -> Result<bool> {
+        let now = Instant::now();
+        let mut cooldown = self
+            .cooldowns
+            .entry(key.to_string())
+            .or_insert_with(|| (owner.to_string(), now + duration));
+
+        if cooldown.1 <= now {
+            *cooldown = (owner.to_string(), now + duration);
+        }
+
+        Ok(cooldown.0 == owner)
+    }
+
+    async fn take_action(
+        &self,
+        guild_id: u64,
+        limit: u64,
+        window: Duration,
+    ) -> Result<Option<Duration>> {
+        let now = Instant::now();
+        let mut budget = self
+            .budgets
+            .entry(guild_id)
+            .or_insert_with(|| (0, now + window));
+
+        if budget.1 <= now {
+            *budget = (0, now + window);
+        }
+
+        budget.0 += 1;
+
+        if budget.0 > limit {
+            return Ok(Some(budget.1 - now));
+        }
+
+        Ok(None)
+    }
+}
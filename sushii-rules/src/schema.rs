@@ -0,0 +1,130 @@
+use schemars::gen::{SchemaGenerator, SchemaSettings};
+use schemars::schema::Schema;
+use schemars::{JsonSchema, Map};
+use serde::Serialize;
+
+use sushii_model::model::sql::RuleScope;
+
+use crate::model::{
+    config::{
+        BoolVar, ChannelIdVar, ConfigParam, ConfigParamType, DurationVar, IntegerVar, RoleIdVar,
+        StringVar, StringVecVar,
+    },
+    Action, Condition, Constraint, Rule, RuleSet, Trigger,
+};
+use crate::persistence::counter::CounterOptions;
+
+/// Version of the rule JSON format. This should be incremented along with a
+/// new migration in `migration.rs` whenever a change would make previously
+/// stored rules fail to deserialize.
+pub const SCHEMA_VERSION: u32 = 2;
+
+/// JSON schemas of every public rule type, sharing a single set of
+/// definitions. Each schema is a `$ref` into `definitions`.
+#[derive(Debug, Clone, Serialize)]
+pub struct SchemaBundle {
+    #[serde(rename = "$schema")]
+    pub meta_schema: Option<String>,
+    pub version: u32,
+    pub schemas: Map<String, Schema>,
+    pub definitions: Map<String, Schema>,
+}
+
+struct BundleBuilder {
+    gen: SchemaGenerator,
+    schemas: Map<String, Schema>,
+}
+
+impl BundleBuilder {
+    fn add<T: JsonSchema>(mut self) -> Self {
+        let schema = self.gen.subschema_for::<T>();
+        self.schemas.insert(T::schema_name(), schema);
+
+        self
+    }
+
+    fn build(mut self) -> SchemaBundle {
+        SchemaBundle {
+            meta_schema: self.gen.settings().meta_schema.clone(),
+            version: SCHEMA_VERSION,
+            schemas: self.schemas,
+            definitions: self.gen.take_definitions(),
+        }
+    }
+}
+
+/// Schemas for the dashboard and rule files, definitions are referenced from
+/// the bundle root
+pub fn schema_bundle() -> SchemaBundle {
+    BundleBuilder {
+        gen: SchemaSettings::draft07().into_generator(),
+        schemas: Map::new(),
+    }
+    .add::<RuleSet>()
+    .add::<Rule>()
+    .add::<Trigger>()
+    .add::<Condition>()
+    .add::<Constraint>()
+    .add::<Action>()
+    .add::<RuleScope>()
+    .add::<CounterOptions>()
+    .add::<ConfigParam>()
+    .add::<ConfigParamType>()
+    .add::<StringVar>()
+    .add::<StringVecVar>()
+    .add::<IntegerVar>()
+    .add::<BoolVar>()
+    .add::<DurationVar>()
+    .add::<ChannelIdVar>()
+    .add::<RoleIdVar>()
+    .build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bundle_references_resolve() {
+        let bundle = serde_json::to_value(schema_bundle()).unwrap();
+
+        assert_eq!(bundle["version"], SCHEMA_VERSION);
+
+        let refs = bundle["schemas"].as_object().unwrap();
+        assert!(refs.contains_key("RuleSet"));
+        assert!(refs.contains_key("Action"));
+
+        for schema in refs.values() {
+            let name = schema["$ref"]
+                .as_str()
+                .and_then(|r| r.strip_prefix("#/definitions/"))
+                .unwrap();
+
+            assert!(
+                bundle["definitions"].get(name).is_some(),
+                "missing {}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn schema_file_is_up_to_date() {
+        let file: serde_json::Value = serde_json::from_str(include_str!("../schema.json")).unwrap();
+        let bundle = serde_json::to_value(schema_bundle()).unwrap();
+
+        assert!(
+            file == bundle,
+            "schema.json is outdated, regenerate it with \
+             `cargo run --bin sushii-rules-exporter > schema.json`"
+        );
+    }
+
+    #[test]
+    fn language_uses_own_name() {
+        let bundle = serde_json::to_value(schema_bundle()).unwrap();
+
+        assert!(bundle["definitions"].get("Language").is_some());
+        assert!(bundle["definitions"].get("LanguageType").is_none());
+    }
+}
@@ -0,0 +1,182 @@
+use async_trait::async_trait;
+use lapin::{
+    options::{
+        BasicAckOptions, BasicConsumeOptions, BasicPublishOptions, BasicQosOptions,
+        ExchangeDeclareOptions, QueueDeclareOptions,
+    },
+    types::FieldTable,
+    BasicProperties, Channel, ExchangeKind,
+};
+use serde::Serialize;
+use serde_json::Value;
+use tokio_stream::StreamExt;
+
+use super::{parse_payload, payload_id, Acker, EventSource, EventStream, SourceEvent};
+use crate::error::Result;
+
+/// Queue that events are moved to after they fail too many times
+pub const DEAD_LETTER_QUEUE: &str = "gateway.recv.dead";
+
+/// Consumes gateway events from the `gateway.recv` RabbitMQ queue. Deliveries
+/// are acked only after their event is processed, so events are redelivered
+/// if the process stops before then.
+#[derive(Debug, Clone)]
+pub struct AmqpSource {
+    uri: String,
+    /// Max number of unacked deliveries
+    prefetch: u16,
+}
+
+impl AmqpSource {
+    pub fn new(uri: impl Into<String>) -> Self {
+        Self {
+            uri: uri.into(),
+            prefetch: 64,
+        }
+    }
+
+    pub fn prefetch(mut self, prefetch: u16) -> Self {
+        self.prefetch = prefetch;
+        self
+    }
+}
+
+#[async_trait]
+impl EventSource for AmqpSource {
+    async fn events(self: Box<Self>) -> Result<EventStream> {
+        let amqp =
+            lapin::Connection::connect(&self.uri, lapin::ConnectionProperties::default()).await?;
+
+        let channel = amqp.create_channel().await?;
+
+        channel
+            .exchange_declare(
+                "gateway",
+                ExchangeKind::Topic,
+                ExchangeDeclareOptions {
+                    passive: false,
+                    durable: true,
+                    auto_delete: false,
+                    internal: false,
+                    nowait: false,
+                },
+                FieldTable::default(),
+            )
+            .await?;
+
+        channel
+            .queue_declare(
+                DEAD_LETTER_QUEUE,
+                QueueDeclareOptions {
+                    durable: true,
+                    ..Default::default()
+                },
+                FieldTable::default(),
+            )
+            .await?;
+
+        channel
+            .basic_qos(self.prefetch, BasicQosOptions::default())
+            .await?;
+
+        let mut consumer = channel
+            .basic_consume(
+                "gateway.recv",
+                "",
+                BasicConsumeOptions::default(),
+                FieldTable::default(),
+            )
+            .await?;
+
+        Ok(Box::pin(async_stream::stream! {
+            // Keep the connection open as long as the stream
+            let _amqp = amqp;
+
+            while let Some(message) = consumer.next().await {
+                let (channel, delivery) = match message {
+                    Ok(m) => m,
+                    Err(e) => {
+                        tracing::error!("Failed to consume delivery: {}", e);
+                        continue;
+                    }
+                };
+
+                let acker = AmqpAcker {
+                    channel,
+                    delivery_tag: delivery.delivery_tag,
+                    data: delivery.data,
+                };
+
+                match parse_payload(&acker.data) {
+                    Ok(Some(event)) => yield Ok(SourceEvent {
+                        id: payload_id(&acker.data),
+                        event,
+                        acker: Box::new(acker),
+                    }),
+                    // Not a dispatch event, nothing to process
+                    Ok(None) => {
+                        if let Err(e) = acker.ack().await {
+                            yield Err(e);
+                        }
+                    }
+                    // Invalid payloads fail the same way every time
+                    Err(e) => {
+                        if let Err(e) = acker.dead_letter(&e.to_string(), 1).await {
+                            yield Err(e);
+                        }
+                    }
+                }
+            }
+        }))
+    }
+}
+
+/// Failed event published to the dead letter queue
+#[derive(Debug, Serialize)]
+struct DeadLetter<'a> {
+    error: &'a str,
+    attempts: u32,
+    /// Original gateway payload, as a string if it isn't valid JSON
+    payload: Value,
+}
+
+struct AmqpAcker {
+    channel: Channel,
+    delivery_tag: u64,
+    data: Vec<u8>,
+}
+
+#[async_trait]
+impl Acker for AmqpAcker {
+    async fn ack(&self) -> Result<()> {
+        self.channel
+            .basic_ack(self.delivery_tag, BasicAckOptions::default())
+            .await?;
+
+        Ok(())
+    }
+
+    async fn dead_letter(&self, error: &str, attempts: u32) -> Result<()> {
+        let payload = serde_json::from_slice(&self.data)
+            .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(&self.data).into_owned()));
+
+        let body = serde_json::to_vec(&DeadLetter {
+            error,
+            attempts,
+            payload,
+        })?;
+
+        self.channel
+            .basic_publish(
+                "",
+                DEAD_LETTER_QUEUE,
+                BasicPublishOptions::default(),
+                body,
+                BasicProperties::default(),
+            )
+            .await?;
+
+        // Only removed from the queue once it's in the dead letter queue
+        self.ack().await
+    }
+}
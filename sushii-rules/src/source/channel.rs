@@ -0,0 +1,38 @@
+use async_trait::async_trait;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+
+use super::{EventSource, EventStream, SourceEvent};
+use crate::error::Result;
+use crate::model::Event;
+
+/// Events sent from the same process. This is only for tests and for
+/// embedding the engine in another program, so it can't be chosen with
+/// `EVENT_SOURCE`. The stream ends when every sender is dropped. Events can't
+/// be redelivered, so failed events are retried in process but not kept after
+/// that.
+#[derive(Debug)]
+pub struct ChannelSource {
+    rx: Receiver<Event>,
+}
+
+impl ChannelSource {
+    /// Creates a source along with the sender to send events to it
+    pub fn new(buffer: usize) -> (Sender<Event>, Self) {
+        let (tx, rx) = mpsc::channel(buffer);
+
+        (tx, Self { rx })
+    }
+}
+
+#[async_trait]
+impl EventSource for ChannelSource {
+    async fn events(self: Box<Self>) -> Result<EventStream> {
+        let mut rx = self.rx;
+
+        Ok(Box::pin(async_stream::stream! {
+            while let Some(event) = rx.recv().await {
+                yield Ok(SourceEvent::new(event));
+            }
+        }))
+    }
+}
@@ -0,0 +1,85 @@
+use async_trait::async_trait;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+use super::{parse_payload, EventSource, EventStream, SourceEvent};
+use crate::error::Result;
+
+/// Replays gateway payloads from a file with one JSON payload per line, e.g.
+/// events recorded from the gateway queue. Blank lines are skipped.
+#[derive(Debug, Clone)]
+pub struct JsonlSource {
+    path: PathBuf,
+    /// Delay between events, none to replay as fast as possible
+    delay: Option<Duration>,
+}
+
+impl JsonlSource {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            delay: None,
+        }
+    }
+
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.delay.replace(delay);
+        self
+    }
+}
+
+#[async_trait]
+impl EventSource for JsonlSource {
+    async fn events(self: Box<Self>) -> Result<EventStream> {
+        let file = tokio::fs::File::open(&self.path).await?;
+        let mut lines = BufReader::new(file).lines();
+        let delay = self.delay;
+
+        Ok(Box::pin(async_stream::try_stream! {
+            while let Some(line) = lines.next_line().await? {
+                if line.trim().is_empty() {
+                    continue;
+                }
+
+                if let Some(event) = parse_payload(line.as_bytes())? {
+                    yield SourceEvent::new(event);
+                }
+
+                if let Some(delay) = delay {
+                    tokio::time::sleep(delay).await;
+                }
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::Event;
+    use tokio_stream::StreamExt;
+
+    #[tokio::test]
+    async fn replays_file() {
+        let path = std::env::temp_dir().join("sushii_rules_jsonl_source.jsonl");
+        let payload = r#"{"op":0,"t":"GUILD_ROLE_DELETE","d":{"guild_id":"1","role_id":"2"}}"#;
+        tokio::fs::write(&path, format!("{}\n\n{}\n", payload, payload))
+            .await
+            .unwrap();
+
+        let source = Box::new(JsonlSource::new(&path));
+        let events: Vec<Event> = source
+            .events()
+            .await
+            .unwrap()
+            .map(|e| e.unwrap().event)
+            .collect()
+            .await;
+
+        assert_eq!(events.len(), 2);
+        assert!(matches!(events[0], Event::Twilight(_)));
+
+        tokio::fs::remove_file(&path).await.unwrap();
+    }
+}
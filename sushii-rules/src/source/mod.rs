@@ -0,0 +1,122 @@
+use async_trait::async_trait;
+use serde::{de::DeserializeSeed, Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::pin::Pin;
+use tokio_stream::Stream;
+use twilight_model::gateway::event::DispatchEventWithTypeDeserializer;
+use twilight_model::gateway::OpCode;
+
+use crate::error::Result;
+use crate::model::Event;
+
+pub mod amqp;
+pub mod channel;
+pub mod jsonl;
+
+pub use amqp::AmqpSource;
+pub use channel::ChannelSource;
+pub use jsonl::JsonlSource;
+
+pub type EventStream = Pin<Box<dyn Stream<Item = Result<SourceEvent>> + Send>>;
+
+/// Event received from a source, acknowledged after every rule for it ran
+pub struct SourceEvent {
+    /// ID that stays the same when the source redelivers the event, used to
+    /// skip actions that already ran
+    pub id: String,
+    pub event: Event,
+    pub acker: Box<dyn Acker>,
+}
+
+impl SourceEvent {
+    /// Event from a source that can't redeliver it, with a random ID
+    pub fn new(event: Event) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            event,
+            acker: Box::new(NoAck),
+        }
+    }
+}
+
+impl fmt::Debug for SourceEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SourceEvent")
+            .field("id", &self.id)
+            .field("event", &self.event)
+            .finish()
+    }
+}
+
+/// Acknowledges an event to its source
+#[async_trait]
+pub trait Acker: Send + Sync {
+    /// Marks the event as processed
+    async fn ack(&self) -> Result<()>;
+
+    /// Gives up on an event that failed too many times, keeping it along with
+    /// the error so it can be inspected or replayed
+    async fn dead_letter(&self, error: &str, attempts: u32) -> Result<()>;
+}
+
+/// Acker for sources that can't redeliver events, dead lettered events are
+/// only logged
+#[derive(Debug, Clone, Copy)]
+pub struct NoAck;
+
+#[async_trait]
+impl Acker for NoAck {
+    async fn ack(&self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn dead_letter(&self, error: &str, attempts: u32) -> Result<()> {
+        tracing::error!(attempts, "Dropping failed event: {}", error);
+
+        Ok(())
+    }
+}
+
+/// Where gateway events are received from
+#[async_trait]
+pub trait EventSource: Send {
+    /// Connects to the source and returns a stream of its events
+    async fn events(self: Box<Self>) -> Result<EventStream>;
+}
+
+/// Raw gateway payload, as forwarded by the gateway service
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PayloadInfo {
+    pub op: OpCode,
+    pub t: Option<String>,
+    pub d: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub old: Option<Value>,
+}
+
+/// Parses a gateway payload, None if it isn't a dispatch event
+pub fn parse_payload(data: &[u8]) -> Result<Option<Event>> {
+    let payload: PayloadInfo = serde_json::from_slice(data)?;
+
+    let event_type = match payload.t {
+        Some(t) => t,
+        None => {
+            tracing::warn!("Payload missing t: {:?}", payload);
+            return Ok(None);
+        }
+    };
+
+    let de = DispatchEventWithTypeDeserializer::new(&event_type);
+    let gateway_event = de.deserialize(payload.d)?;
+
+    Ok(Some(Event::Twilight(gateway_event)))
+}
+
+/// ID of a raw payload, redelivered payloads are identical so they get the
+/// same ID. This needs to be stable across restarts and instances since
+/// redelivered events can be consumed by a different process.
+pub fn payload_id(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
@@ -0,0 +1,69 @@
+use std::env;
+use std::path::Path;
+use std::process;
+
+use sushii_rules::persistence::file::load_rule_set_file;
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = env::args().collect();
+
+    if args.len() < 2 {
+        eprintln!("Usage: {} <rule set file>...", args[0]);
+        process::exit(1);
+    }
+
+    let mut failed = 0;
+    let mut total = 0;
+    // Files that couldn't be loaded or run, these don't have a number of tests
+    let mut invalid = 0;
+
+    for path in &args[1..] {
+        let rule_set = match load_rule_set_file(Path::new(path)).await {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("invalid: {}", e);
+                invalid += 1;
+                continue;
+            }
+        };
+
+        let results = match rule_set.run_tests().await {
+            Ok(r) => r,
+            Err(e) => {
+                eprintln!("{}: failed to run tests, {}", path, e);
+                invalid += 1;
+                continue;
+            }
+        };
+
+        for res in results {
+            total += 1;
+
+            if res.passed {
+                println!("ok   {} / {}", path, res.name);
+                continue;
+            }
+
+            failed += 1;
+            println!(
+                "FAIL {} / {} (fired: {}, actions: {:?})",
+                path, res.name, res.fired, res.actions
+            );
+
+            for error in &res.errors {
+                println!("     {}", error);
+            }
+        }
+    }
+
+    println!("{} tests, {} failed", total, failed);
+
+    if invalid > 0 {
+        println!("{} files could not be tested", invalid);
+    }
+
+    if failed > 0 || invalid > 0 {
+        process::exit(1);
+    }
+}
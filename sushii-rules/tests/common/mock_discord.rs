@@ -0,0 +1,325 @@
+//! Stand-in for the Discord REST API, used in place of the proxy the rules
+//! engine normally sends requests to
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use serde_json::{json, Value};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::oneshot;
+use twilight_http::Client;
+
+/// Error response to return instead of handling a request
+#[derive(Debug, Clone, Copy)]
+pub enum Fault {
+    /// 403, user has DMs disabled or blocked the bot
+    CannotDm,
+    /// 403, bot is missing permissions or the role is above the bot's
+    MissingPermissions,
+    /// 429, not global
+    RateLimited,
+    /// 502 from Discord
+    ServerError,
+}
+
+impl Fault {
+    fn response(self) -> (StatusCode, Value) {
+        match self {
+            Self::CannotDm => (
+                StatusCode::FORBIDDEN,
+                json!({ "code": 50007, "message": "Cannot send messages to this user" }),
+            ),
+            Self::MissingPermissions => (
+                StatusCode::FORBIDDEN,
+                json!({ "code": 50013, "message": "Missing Permissions" }),
+            ),
+            Self::RateLimited => (
+                StatusCode::TOO_MANY_REQUESTS,
+                json!({
+                    "global": false,
+                    "message": "You are being rate limited.",
+                    "retry_after": 1.0,
+                }),
+            ),
+            Self::ServerError => (
+                StatusCode::BAD_GATEWAY,
+                json!({ "code": 0, "message": "502: Bad Gateway" }),
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RecordedRequest {
+    pub method: Method,
+    /// Path without the API version prefix, e.g. `/channels/1/messages`
+    pub path: String,
+    /// Audit log reason header
+    pub reason: Option<String>,
+    pub body: Value,
+}
+
+#[derive(Debug)]
+struct Injected {
+    method: Method,
+    path: String,
+    fault: Fault,
+    /// Number of requests left to fail, None to fail all of them
+    remaining: Option<usize>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    requests: Mutex<Vec<RecordedRequest>>,
+    faults: Mutex<Vec<Injected>>,
+    next_id: AtomicU64,
+}
+
+impl State {
+    fn fault(&self, method: &Method, path: &str) -> Option<Fault> {
+        let mut faults = self.faults.lock().unwrap();
+        let i = faults
+            .iter()
+            .position(|f| f.method == method && path.contains(&f.path))?;
+
+        let fault = faults[i].fault;
+
+        if let Some(remaining) = faults[i].remaining.as_mut() {
+            *remaining -= 1;
+
+            if *remaining == 0 {
+                faults.remove(i);
+            }
+        }
+
+        Some(fault)
+    }
+
+    fn snowflake(&self) -> String {
+        // Far enough from 0 to be a valid timestamp
+        (self.next_id.fetch_add(1, Ordering::SeqCst) + (1 << 40)).to_string()
+    }
+}
+
+/// Local HTTP server implementing the REST endpoints used by actions. Every
+/// request is recorded and requests can be made to fail with [`Fault`]s.
+#[derive(Debug)]
+pub struct MockDiscord {
+    addr: SocketAddr,
+    state: Arc<State>,
+    shutdown: Option<oneshot::Sender<()>>,
+}
+
+impl MockDiscord {
+    /// Starts the server on a random local port, stopped when dropped
+    pub async fn start() -> Self {
+        let state = Arc::new(State::default());
+        let service_state = state.clone();
+
+        let make_svc = make_service_fn(move |_| {
+            let state = service_state.clone();
+
+            async move { Ok::<_, Infallible>(service_fn(move |req| handle(state.clone(), req))) }
+        });
+
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+        let addr = server.local_addr();
+        let (shutdown, rx) = oneshot::channel();
+
+        tokio::spawn(server.with_graceful_shutdown(async {
+            rx.await.ok();
+        }));
+
+        Self {
+            addr,
+            state,
+            shutdown: Some(shutdown),
+        }
+    }
+
+    /// Client that sends requests to this server, without ratelimiting so
+    /// 429s are returned as errors
+    pub fn client(&self) -> Client {
+        Client::builder()
+            .proxy(self.addr.to_string(), true)
+            .ratelimiter(None)
+            .build()
+    }
+
+    /// Fails every request with the method whose path contains `path`
+    pub fn fail(&self, method: Method, path: &str, fault: Fault) {
+        self.inject(method, path, fault, None);
+    }
+
+    /// Fails only the next request with the method whose path contains `path`
+    pub fn fail_once(&self, method: Method, path: &str, fault: Fault) {
+        self.inject(method, path, fault, Some(1));
+    }
+
+    fn inject(&self, method: Method, path: &str, fault: Fault, remaining: Option<usize>) {
+        self.state.faults.lock().unwrap().push(Injected {
+            method,
+            path: path.to_string(),
+            fault,
+            remaining,
+        });
+    }
+
+    /// Requests received so far, including failed ones
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.state.requests.lock().unwrap().clone()
+    }
+
+    /// Requests with the method whose path contains `path`
+    pub fn requests_to(&self, method: Method, path: &str) -> Vec<RecordedRequest> {
+        self.requests()
+            .into_iter()
+            .filter(|r| r.method == method && r.path.contains(path))
+            .collect()
+    }
+}
+
+impl Drop for MockDiscord {
+    fn drop(&mut self) {
+        if let Some(tx) = self.shutdown.take() {
+            let _ = tx.send(());
+        }
+    }
+}
+
+async fn handle(state: Arc<State>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let method = req.method().clone();
+    let path = strip_api_version(req.uri().path()).to_string();
+    let reason = req
+        .headers()
+        .get("x-audit-log-reason")
+        .and_then(|v| v.to_str().ok())
+        .map(|v| percent_decode(v));
+
+    let bytes = hyper::body::to_bytes(req.into_body())
+        .await
+        .unwrap_or_default();
+    let body = serde_json::from_slice(&bytes).unwrap_or(Value::Null);
+
+    state.requests.lock().unwrap().push(RecordedRequest {
+        method: method.clone(),
+        path: path.clone(),
+        reason,
+        body: body.clone(),
+    });
+
+    if let Some(fault) = state.fault(&method, &path) {
+        let (status, body) = fault.response();
+
+        return Ok(json_response(status, &body));
+    }
+
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+    let res = match (&method, segments.as_slice()) {
+        (&Method::GET, ["users", "@me"]) => json_response(StatusCode::OK, &user("1", "sushii")),
+        (&Method::POST, ["users", "@me", "channels"]) => {
+            let recipient = body["recipient_id"].as_str().unwrap_or("0");
+
+            json_response(
+                StatusCode::OK,
+                &json!({
+                    "id": state.snowflake(),
+                    "type": 1,
+                    "last_message_id": null,
+                    "recipients": [user(recipient, "recipient")],
+                }),
+            )
+        }
+        (&Method::POST, ["channels", channel_id, "messages"]) => json_response(
+            StatusCode::OK,
+            &message(&state.snowflake(), channel_id, &body),
+        ),
+        (&Method::DELETE, ["channels", _, "messages", _])
+        | (&Method::PUT, ["guilds", _, "bans", _])
+        | (&Method::DELETE, ["guilds", _, "bans", _])
+        | (&Method::PUT, ["guilds", _, "members", _, "roles", _])
+        | (&Method::DELETE, ["guilds", _, "members", _, "roles", _]) => Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(Body::empty())
+            .unwrap(),
+        _ => json_response(
+            StatusCode::NOT_FOUND,
+            &json!({ "code": 0, "message": "404: Not Found" }),
+        ),
+    };
+
+    Ok(res)
+}
+
+/// Removes the `/api/v8` prefix twilight adds to every path
+fn strip_api_version(path: &str) -> &str {
+    path.strip_prefix("/api/")
+        .and_then(|p| p.find('/').map(|i| &p[i..]))
+        .unwrap_or(path)
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+
+        match (bytes[i], hex) {
+            (b'%', Some(b)) => {
+                out.push(b);
+                i += 3;
+            }
+            (b, _) => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn json_response(status: StatusCode, body: &Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+fn user(id: &str, name: &str) -> Value {
+    json!({
+        "id": id,
+        "username": name,
+        "discriminator": "0001",
+        "avatar": null,
+        "bot": false,
+    })
+}
+
+fn message(id: &str, channel_id: &str, body: &Value) -> Value {
+    json!({
+        "id": id,
+        "channel_id": channel_id,
+        "author": user("1", "sushii"),
+        "content": body["content"].as_str().unwrap_or_default(),
+        "timestamp": "2021-01-01T00:00:00+00:00",
+        "edited_timestamp": null,
+        "tts": false,
+        "mention_everyone": false,
+        "mentions": [],
+        "mention_roles": [],
+        "attachments": [],
+        "embeds": [],
+        "pinned": false,
+        "type": 0,
+    })
+}
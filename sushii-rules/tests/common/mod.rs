@@ -0,0 +1,119 @@
+#![allow(dead_code)]
+
+pub mod mock_discord;
+
+use serde::de::DeserializeSeed;
+use serde_json::json;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::{mpsc, RwLock};
+use twilight_http::Client;
+use twilight_model::gateway::event::DispatchEventWithTypeDeserializer;
+
+use sushii_model::model::sql::GuildConfig;
+use sushii_rules::model::{
+    cache::{DiscordCache, MessageHistory},
+    language::{CachedDetector, LinguaDetector},
+    template, EngineHandles, Event, RuleContext,
+};
+use sushii_rules::persistence::{counter::MemoryCounterStore, EventProgress, MemoryRateLimitStore};
+
+pub const GUILD_ID: u64 = 1;
+pub const CHANNEL_ID: u64 = 2;
+
+/// Connects to the database in `DATABASE_URL`. Tests that need it are
+/// ignored by default and run with `--ignored`, so a missing database fails
+/// them instead of silently passing.
+pub async fn pg_pool() -> sqlx::PgPool {
+    dotenv::dotenv().ok();
+
+    let url = std::env::var("DATABASE_URL").expect("DATABASE_URL is required for database tests");
+
+    sqlx::PgPool::connect(&url)
+        .await
+        .expect("Failed to connect to database")
+}
+
+/// User ID that isn't used by other tests, so database rows don't collide
+pub fn unique_user_id() -> u64 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+
+    (nanos as u64) >> 8
+}
+
+/// Message create event sent by the user in the test guild
+pub fn message_event(user_id: u64, content: &str) -> Arc<Event> {
+    let payload = json!({
+        "id": "900000000000000000",
+        "channel_id": CHANNEL_ID.to_string(),
+        "guild_id": GUILD_ID.to_string(),
+        "author": {
+            "id": user_id.to_string(),
+            "username": "spammer",
+            "discriminator": "0001",
+            "avatar": null,
+            "bot": false,
+        },
+        "member": {
+            "roles": [],
+            "joined_at": "2021-01-01T00:00:00+00:00",
+            "deaf": false,
+            "mute": false,
+        },
+        "content": content,
+        "timestamp": "2021-01-01T00:00:00+00:00",
+        "edited_timestamp": null,
+        "tts": false,
+        "mention_everyone": false,
+        "mentions": [],
+        "mention_roles": [],
+        "attachments": [],
+        "embeds": [],
+        "pinned": false,
+        "type": 0,
+    });
+
+    let event = DispatchEventWithTypeDeserializer::new("MESSAGE_CREATE")
+        .deserialize(payload)
+        .unwrap();
+
+    Arc::new(Event::Twilight(event))
+}
+
+/// Context with in memory stores, Discord requests go to `http`
+pub fn rule_context(
+    http: Client,
+    pg_pool: sqlx::PgPool,
+    guild_config: GuildConfig,
+) -> (RuleContext<'static>, mpsc::UnboundedReceiver<Event>) {
+    let (channel_tx, channel_rx) = mpsc::unbounded_channel();
+
+    let handles = EngineHandles {
+        http,
+        pg_pool,
+        counters: Arc::new(MemoryCounterStore::new()),
+        rate_limits: Arc::new(MemoryRateLimitStore::new()),
+        action_budget: None,
+        reqwest: reqwest::Client::new(),
+        language_detector: Arc::new(CachedDetector::new(LinguaDetector::new())),
+        handlebars_templates: Arc::new(RwLock::new(template::registry(false))),
+        message_history: MessageHistory::new(),
+        discord_cache: DiscordCache::new(),
+        channel_tx,
+    };
+
+    let mut ctx = RuleContext::new(
+        &handles,
+        Arc::new(guild_config),
+        Arc::new(EventProgress::new()),
+        None,
+        Arc::new(Vec::new()),
+    );
+    ctx.rule_name = "Test rule".into();
+    ctx.event_id = unique_user_id().to_string();
+
+    (ctx, channel_rx)
+}
@@ -0,0 +1,204 @@
+mod common;
+
+use hyper::Method;
+
+use sushii_model::model::sql::GuildConfig;
+use sushii_rules::error::is_transient;
+use sushii_rules::model::{config::DurationVar, Action};
+
+use common::mock_discord::{Fault, MockDiscord};
+use common::{message_event, pg_pool, rule_context, unique_user_id, GUILD_ID};
+
+const MUTE_ROLE_ID: u64 = 10;
+const LOG_CHANNEL_ID: u64 = 20;
+
+fn guild_config() -> GuildConfig {
+    GuildConfig {
+        mute_role: Some(MUTE_ROLE_ID as i64),
+        log_mod: Some(LOG_CHANNEL_ID as i64),
+        ..GuildConfig::new(GUILD_ID as i64)
+    }
+}
+
+fn mute() -> Action {
+    Action::Mute {
+        duration: Some(DurationVar::Value(600)),
+        reason: Some("Spam".into()),
+    }
+}
+
+/// Number of mod log cases and mute entries for the user
+async fn rows(pool: &sqlx::PgPool, user_id: u64) -> (i64, i64) {
+    let cases: i64 = sqlx::query_scalar(
+        "select count(*) from app_public.mod_logs where guild_id = $1 and user_id = $2",
+    )
+    .bind(GUILD_ID as i64)
+    .bind(user_id as i64)
+    .fetch_one(pool)
+    .await
+    .unwrap();
+
+    let mutes: i64 = sqlx::query_scalar(
+        "select count(*) from app_public.mutes where guild_id = $1 and user_id = $2",
+    )
+    .bind(GUILD_ID as i64)
+    .bind(user_id as i64)
+    .fetch_one(pool)
+    .await
+    .unwrap();
+
+    (cases, mutes)
+}
+
+#[tokio::test]
+#[ignore = "needs DATABASE_URL"]
+async fn mute_adds_role_and_logs_case() {
+    let pool = pg_pool().await;
+
+    let discord = MockDiscord::start().await;
+    let user_id = unique_user_id();
+    let (mut ctx, _rx) = rule_context(discord.client(), pool.clone(), guild_config());
+
+    mute()
+        .execute(message_event(user_id, "spam"), &mut ctx)
+        .await
+        .unwrap();
+
+    let role_path = format!(
+        "/guilds/{}/members/{}/roles/{}",
+        GUILD_ID, user_id, MUTE_ROLE_ID
+    );
+    let role_requests = discord.requests_to(Method::PUT, &role_path);
+    assert_eq!(role_requests.len(), 1);
+    assert_eq!(role_requests[0].reason.as_deref(), Some("Spam"));
+
+    let log_path = format!("/channels/{}/messages", LOG_CHANNEL_ID);
+    assert_eq!(discord.requests_to(Method::POST, &log_path).len(), 1);
+
+    let pending: bool = sqlx::query_scalar(
+        "select pending from app_public.mod_logs where guild_id = $1 and user_id = $2",
+    )
+    .bind(GUILD_ID as i64)
+    .bind(user_id as i64)
+    .fetch_one(&pool)
+    .await
+    .unwrap();
+
+    assert!(
+        !pending,
+        "case should no longer be pending after it is logged"
+    );
+    assert_eq!(rows(&pool, user_id).await, (1, 1));
+    assert_eq!(ctx.actions_run, vec!["Mute"]);
+}
+
+#[tokio::test]
+#[ignore = "needs DATABASE_URL"]
+async fn mute_rolls_back_when_role_add_is_forbidden() {
+    let pool = pg_pool().await;
+
+    let discord = MockDiscord::start().await;
+    discord.fail(Method::PUT, "/roles/", Fault::MissingPermissions);
+
+    let user_id = unique_user_id();
+    let (mut ctx, _rx) = rule_context(discord.client(), pool.clone(), guild_config());
+
+    let err = mute()
+        .execute(message_event(user_id, "spam"), &mut ctx)
+        .await
+        .unwrap_err();
+
+    assert!(!err.chain().any(is_transient), "403 should not be retried");
+    assert_eq!(rows(&pool, user_id).await, (0, 0));
+    assert!(discord.requests_to(Method::POST, "/messages").is_empty());
+    assert!(ctx.actions_run.is_empty());
+}
+
+#[tokio::test]
+#[ignore = "needs DATABASE_URL"]
+async fn mute_rolls_back_and_retries_when_rate_limited() {
+    let pool = pg_pool().await;
+
+    let discord = MockDiscord::start().await;
+    discord.fail_once(Method::PUT, "/roles/", Fault::RateLimited);
+
+    let user_id = unique_user_id();
+    let (mut ctx, _rx) = rule_context(discord.client(), pool.clone(), guild_config());
+    let event = message_event(user_id, "spam");
+
+    let err = mute().execute(event.clone(), &mut ctx).await.unwrap_err();
+
+    assert!(err.chain().any(is_transient), "429 should be retried");
+    assert_eq!(rows(&pool, user_id).await, (0, 0));
+
+    // Retried events run their actions with the same keys
+    mute().execute(event, &mut ctx).await.unwrap();
+
+    assert_eq!(discord.requests_to(Method::PUT, "/roles/").len(), 2);
+    assert_eq!(rows(&pool, user_id).await, (1, 1));
+}
+
+#[tokio::test]
+#[ignore = "needs DATABASE_URL"]
+async fn mute_leaves_case_pending_when_mod_log_fails() {
+    let pool = pg_pool().await;
+
+    let discord = MockDiscord::start().await;
+    discord.fail(Method::POST, "/messages", Fault::MissingPermissions);
+
+    let user_id = unique_user_id();
+    let (mut ctx, _rx) = rule_context(discord.client(), pool.clone(), guild_config());
+
+    // Role is added but the case can't be posted
+    assert!(mute()
+        .execute(message_event(user_id, "spam"), &mut ctx)
+        .await
+        .is_err());
+
+    assert_eq!(discord.requests_to(Method::PUT, "/roles/").len(), 1);
+    assert_eq!(rows(&pool, user_id).await, (1, 1));
+
+    let (pending, msg_id): (bool, Option<i64>) = sqlx::query_as(
+        "select pending, msg_id from app_public.mod_logs where guild_id = $1 and user_id = $2",
+    )
+    .bind(GUILD_ID as i64)
+    .bind(user_id as i64)
+    .fetch_one(&pool)
+    .await
+    .unwrap();
+
+    assert!(pending, "case that wasn't posted should stay pending");
+    assert_eq!(msg_id, None);
+    assert!(ctx.actions_run.is_empty());
+
+    // A retry of the event doesn't mute again or create another case
+    mute()
+        .execute(message_event(user_id, "spam"), &mut ctx)
+        .await
+        .unwrap();
+
+    assert_eq!(discord.requests_to(Method::PUT, "/roles/").len(), 1);
+    assert_eq!(rows(&pool, user_id).await, (1, 1));
+}
+
+#[tokio::test]
+#[ignore = "needs DATABASE_URL"]
+async fn mute_requires_mute_role() {
+    let pool = pg_pool().await;
+
+    let discord = MockDiscord::start().await;
+    let user_id = unique_user_id();
+    let (mut ctx, _rx) = rule_context(
+        discord.client(),
+        pool.clone(),
+        GuildConfig::new(GUILD_ID as i64),
+    );
+
+    assert!(mute()
+        .execute(message_event(user_id, "spam"), &mut ctx)
+        .await
+        .is_err());
+
+    assert!(discord.requests().is_empty());
+    assert_eq!(rows(&pool, user_id).await, (0, 0));
+}